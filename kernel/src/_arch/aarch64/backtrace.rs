@@ -0,0 +1,19 @@
+//! A frame record is the pair x29, x30 stored at the address in x29.
+use crate::memory::{address::VirtualAddress, probe};
+use core::arch::asm;
+
+pub const CALL_SIZE: usize = 4;
+
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+    }
+    fp
+}
+
+// ask the MMU instead of taking a fault while printing a panic
+pub fn is_readable(va: usize) -> bool {
+    probe(VirtualAddress::from(va), false).is_some()
+}
@@ -3,6 +3,7 @@ use core::arch::asm;
 
 extern "C" {
     pub fn __cpu_switch_to(prev: *mut Task, next: *mut Task);
+    pub fn __ret_from_fork();
 }
 
 #[derive(Default, Copy, Clone)]
@@ -11,4 +12,5 @@ pub struct Context {
     pub gpr: [u64; 11], // x19 - x29
     pub sp: u64,
     pub lr: u64,
+    pub sp_el0: u64,
 }
@@ -76,6 +76,8 @@ ttbr1_el1   0xffffff8000000000   ----++---------------+                   |
 
 .global	_start
 _start:
+    //the firmware passes the device tree blob in x0, x19 keeps it for the boot info
+    mov         x19, x0
     mrs         x0, CurrentEL
     ldr         x1, =.L_CONST_EL2
     cmp         x0, x1
@@ -250,6 +252,72 @@ _start:
 
 
 
+// Secondary cores are released here by start_secondary_cores, at EL2 with the MMU off
+.global __secondary_start
+__secondary_start:
+    ldr                 x0, =.L_CNTHCTL_EL2_val
+    msr                 CNTHCTL_EL2, x0
+
+    ldr                 x0, =.L_CNTVOFF_EL2_val
+    msr                 CNTVOFF_EL2, x0
+
+    ldr                 x0, =.L_HCR_EL2_val
+    msr                 HCR_EL2, x0
+
+    ldr                 x0, =.L_SPSR_EL2_val //all interrupts are masked
+    msr                 SPSR_EL2, x0
+
+    adr_load            x0, .L_secondary_in_el1
+    msr                 ELR_EL2, x0
+
+    ldr                 x1, =.L_KERNEL_BASE
+    adr_load            x0, __exception_vector_start
+    add                 x0, x0, x1
+    msr                 VBAR_EL1, x0
+
+    eret
+
+.L_secondary_in_el1:
+    //the lower half is unmapped by now, but higher L1[0] still maps the kernel
+    //at its physical address, so borrow it as the identity mapping
+    adr_load           x0, l1_higher_page_table
+    msr                ttbr0_el1, x0
+    msr                ttbr1_el1, x0
+
+    ldr                x0, =.L_TCR_EL1_val
+    msr                TCR_EL1, x0
+
+    ldr                x0, =.L_MAIR_EL1_val
+    msr                MAIR_EL1, x0
+
+    ldr                x0, =.L_SCTLR_EL1_val
+    msr                SCTLR_EL1, x0
+
+    isb                sy
+
+    adr_link           x0, .L_secondary_higher_half
+    br                 x0
+
+.L_secondary_higher_half:
+    adr_load           x0, l1_lower_page_table
+    ldr                x1, =.L_KERNEL_BASE
+    sub                x0, x0, x1
+    msr                ttbr0_el1, x0
+
+    DSB SY
+    TLBI VMALLE1
+    DSB SY
+    ISB sy
+
+    mrs                x0, MPIDR_EL1
+    ldr                x1, =.L_CONST_CORE_ID_MASK
+    and                x0, x0, x1
+    adr_load           x1, SECONDARY_STACK_TOP
+    ldr                x2, [x1, x0, LSL #3]
+    mov                sp, x2
+    b                  secondary_main
+
+
 .L_map_higher_half:
     mov                 x7, lr      // save the link register
 
@@ -392,7 +460,7 @@ _start:
 // this effects how you use the PC-relative address
 .L_prepare_boot_info:
     ldr     x0, =.L_KERNEL_BASE
-    sub     sp, sp, #176
+    sub     sp, sp, #192
 
     //code_and_ro
     adr_load    x1, __code_start
@@ -461,6 +529,9 @@ _start:
 
     stp     x1, x2, [sp, #16 * 10]
 
+    //device tree
+    str     x19, [sp, #16 * 11]
+
 
     mov         x0, sp
 
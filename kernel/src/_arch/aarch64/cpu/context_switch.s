@@ -12,7 +12,8 @@ __cpu_switch_to:
 	stp	x25, x26, [x8], #16
 	stp	x27, x28, [x8], #16
 	stp	x29, x9,  [x8], #16
-	str	lr, [x8]
+	mrs	x10, sp_el0
+	stp	lr, x10, [x8]
 	mov	x8, x1
 	ldp	x19, x20, [x8], #16
 	ldp	x21, x22, [x8], #16
@@ -20,7 +21,19 @@ __cpu_switch_to:
 	ldp	x25, x26, [x8], #16
 	ldp	x27, x28, [x8], #16
 	ldp	x29, x9,  [x8], #16
-	ldr     lr, [x8]
+	ldp	lr, x10, [x8]
+	msr	sp_el0, x10
 	mov	sp, x9
 	ret
 
+
+// The first __cpu_switch_to into a new task returns here.
+// x19: entry point
+// x20: argument
+.global __ret_from_fork
+__ret_from_fork:
+	mov	x0, x19
+	mov	x1, x20
+	bl	__kthread_start
+1:	wfe
+	b	1b
@@ -6,6 +6,8 @@
 .equ .L_QEMU_CONSOLE , 0x3F201000
 .equ .L_BOOT_CORE_ID,  0
 .equ .L_INITIAL_STACK_SIZE , 10
+.equ .L_NUM_OF_CORES , 4
+.equ .L_EMERGENCY_STACK_SIZE , 0x2000
 .equ .L_RWNORMAL , 0b0000000001100000000000000000000000000000000000000000011101000100
 .equ .L_RONORMAL , 0b0000000001100000000000000000000000000000000000000000011111000100
 .equ .L_XNORMAL , 0b0000000000000000000000000000000000000000000000000000011111000100
@@ -17,7 +19,8 @@
 .equ .L_L1_SHIFT , 9 + 9 + 12
 .equ .L_L2_SHIFT , 9 + 12
 .equ .L_L3_SHIFT , 12
-.equ .L_TCR_EL1_val , 0b0000000000000000000000000000000010110101000110010011010100011001
+// IPS = 0b001, 36 bit physical addresses, enough for the 8 GB of PHYSICAL_MEMORY_LIMIT
+.equ .L_TCR_EL1_val , 0b0000000000000000000000000000000110110101000110010011010100011001
 .equ .L_MAIR_EL1_val , 0b0000000000000000000000000000000000000000000000001111111100000100
 .equ .L_SCTLR_EL1_val , 0b0000000000000000000000000000000000000000110001010001100000111101
 .equ .L_HCR_EL2_val , 0b0000000000000000000000000000000010000000000000000000000000000000
@@ -38,6 +38,19 @@ __vector_\handler:
 .type __vector_\handler, function
 .endm
 
+// a kernel stack overflow faults on the guard page below the stack, and would fault again on
+// saving the context. probe the frame with AT first and move to the emergency stack of this core
+// if it is not mapped. TPIDR_EL1 is only used as scratch here
+.macro CHECK_STACK
+    msr     TPIDR_EL1, x0
+    sub     x0, sp, #16 * 17
+    at      s1e1w, x0
+    isb
+    mrs     x0, PAR_EL1
+    tbnz    x0, #0, __stack_overflow
+    mrs     x0, TPIDR_EL1
+.endm
+
 .macro FIQ_SUSPEND
 1:  wfe
     b   1b
@@ -63,6 +76,7 @@ __exception_vector_start:
 
 // Current exception level with SP_ELx, x > 0
 .org 0x200
+    CHECK_STACK
     CALL_WITH_CONTEXT current_elx_synchronous
 .org 0x280
     CALL_WITH_CONTEXT current_elx_irq
@@ -122,3 +136,26 @@ __exception_restore_context:
 
 .size	__exception_restore_context, . - __exception_restore_context
 .type	__exception_restore_context, function
+
+// stack_overflow(sp, far, elr) never returns
+__stack_overflow:
+    mov     x0, sp
+    mrs     x1, MPIDR_EL1
+    and     x1, x1, #.L_CONST_CORE_ID_MASK
+    add     x1, x1, #1
+    adrp    x2, __emergency_stacks
+    add     x2, x2, #:lo12:__emergency_stacks
+    mov     x3, #.L_EMERGENCY_STACK_SIZE
+    madd    x2, x1, x3, x2
+    mov     sp, x2
+    mrs     x1, FAR_EL1
+    mrs     x2, ELR_EL1
+    bl      stack_overflow
+
+.size	__stack_overflow, . - __stack_overflow
+.type	__stack_overflow, function
+
+.section .bss.emergency_stack, "aw", @nobits
+.p2align 4
+__emergency_stacks:
+    .space .L_EMERGENCY_STACK_SIZE * .L_NUM_OF_CORES, 0
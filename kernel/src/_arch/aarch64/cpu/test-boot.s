@@ -1,6 +1,7 @@
 
 .include "defines.s"
 .include "macro.s"
+.include "context_switch.s"
 
 
 .section .text._start
@@ -8,6 +9,8 @@
 
 .global	_start
 _start:
+    //the firmware passes the device tree blob in x0, x19 keeps it for the boot info
+    mov         x19, x0
     mrs         x0, CurrentEL
     cmp         x0, .L_CONST_EL2
     b.ne        .L_parking_loop
@@ -70,6 +73,54 @@ _start:
     eret
 
 
+// Secondary cores are released here by start_secondary_cores, at EL2 with the MMU off
+.global __secondary_start
+__secondary_start:
+    ldr                 x0, =.L_CNTHCTL_EL2_val
+    msr                 CNTHCTL_EL2, x0
+
+    ldr                 x0, =.L_CNTVOFF_EL2_val
+    msr                 CNTVOFF_EL2, x0
+
+    ldr                 x0, =.L_HCR_EL2_val
+    msr                 HCR_EL2, x0
+
+    ldr                 x0, =.L_SPSR_EL2_val //all interrupts are masked
+    msr                 SPSR_EL2, x0
+
+    adr_load            x0, .L_secondary_in_el1
+    msr                 ELR_EL2, x0
+
+    adr_load            x0, __exception_vector_start
+    msr                 VBAR_EL1, x0
+
+    eret
+
+.L_secondary_in_el1:
+    adr_load           x0, l1_lower_page_table
+    msr                ttbr0_el1, x0
+
+    adr_load           x0, l1_higher_page_table
+    msr                ttbr1_el1, x0
+
+    ldr                x0, =.L_TCR_EL1_val
+    msr                TCR_EL1, x0
+
+    ldr                x0, =.L_MAIR_EL1_val
+    msr                MAIR_EL1, x0
+
+    ldr                x0, =.L_SCTLR_EL1_val
+    msr                SCTLR_EL1, x0
+
+    isb                sy
+
+    mrs                x0, MPIDR_EL1
+    and                x0, x0, .L_CONST_CORE_ID_MASK
+    adr_load           x1, SECONDARY_STACK_TOP
+    ldr                x2, [x1, x0, LSL #3]
+    mov                sp, x2
+    b                  secondary_main
+
 .L_map_lower_half:
 
     mov                 x6, lr      // save the link register
@@ -206,7 +257,7 @@ _start:
     ret
 
 .L_prepare_boot_info:
-    sub     sp, sp, #176
+    sub     sp, sp, #192
     //code_and_ro
     adr_load    x1, __code_start
     adr_load    x2, __data_end_exclusive
@@ -243,6 +294,9 @@ _start:
     //higher free page
     stp     	x1, x2, [sp, #16 * 10]
 
+    //device tree
+    str     	x19, [sp, #16 * 11]
+
     mov        x0, sp
 
     ret
@@ -1,13 +1,33 @@
-use crate::{errno::ErrorCode, exception::PrivilegeLevel, interrupt::IRQ_CONTROLLER, println};
+extern crate alloc;
+use crate::{
+    backtrace::Backtrace,
+    errno::{ErrorCode, EFAULT},
+    exception::PrivilegeLevel,
+    interrupt::IRQ_CONTROLLER,
+    memory::{
+        address_space::{Access, PageFault},
+        translation_table::wait_for_split,
+    },
+    println,
+    scheduler::SCHEDULER,
+    syscall,
+};
 use aarch64_cpu::{asm::barrier, registers::*};
+use alloc::boxed::Box;
 use core::{arch::asm, fmt};
 use tock_registers::{
     interfaces::{ReadWriteable, Readable, Writeable},
     registers::InMemoryRegister,
 };
 
+#[path = "exception/fault.rs"]
+mod fault;
+pub use fault::*;
+
 extern "C" {
     static __exception_vector_start: u8;
+    static __ex_table_start: u8;
+    static __ex_table_end: u8;
 }
 
 pub fn current_privilege_level() -> (PrivilegeLevel, &'static str) {
@@ -115,6 +135,13 @@ impl fmt::Display for SpsrEL1 {
     }
 }
 
+impl SpsrEL1 {
+    #[inline(always)]
+    fn from_user(&self) -> bool {
+        self.0.read_as_enum(SPSR_EL1::M) == Some(SPSR_EL1::M::Value::EL0t)
+    }
+}
+
 impl EsrEL1 {
     #[inline(always)]
     fn exception_class(&self) -> Option<ESR_EL1::EC::Value> {
@@ -171,17 +198,33 @@ impl fmt::Display for EsrEL1 {
 }
 
 #[repr(C)]
-struct ExceptionContext {
+pub struct ExceptionContext {
     /// General purpose registers
-    gpr: [u64; 30],
+    pub gpr: [u64; 30],
 
     /// Link register
-    lr: u64,
+    pub lr: u64,
 
-    elr_el1: u64,
+    pub elr_el1: u64,
     spsr_el1: SpsrEL1,
     esr_el1: EsrEL1,
 }
+impl ExceptionContext {
+    pub fn spsr(&self) -> u64 {
+        self.spsr_el1.0.get()
+    }
+
+    pub fn set_spsr(&mut self, spsr: u64) {
+        self.spsr_el1.0.set(spsr)
+    }
+
+    // the stack pointer of the interrupted code, when the exception was taken to EL1 on SP_EL1
+    // and the context saved on top of it
+    pub fn sp(&self) -> u64 {
+        (self as *const Self as usize + core::mem::size_of::<Self>()) as u64
+    }
+}
+
 impl fmt::Display for ExceptionContext {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "ExceptionContext:")?;
@@ -205,12 +248,105 @@ impl fmt::Display for ExceptionContext {
         writeln!(f, "Link Reg = {:#018x}", self.lr)?;
         writeln!(f, "ELR_EL1  = {:#018x}", self.elr_el1)?;
         writeln!(f, "{}", self.spsr_el1)?;
-        write!(f, "{}", self.esr_el1)
+        writeln!(f, "{}", self.esr_el1)?;
+        // the frames of a task are on its own stack and mean nothing to the kernel's symbols
+        if !self.spsr_el1.from_user() {
+            write!(
+                f,
+                "{}",
+                Backtrace::new(self.elr_el1 as usize, self.gpr[29] as usize)
+            )?;
+        }
+        Ok(())
     }
 }
 fn default_synchronous_exception_handler(exc: &ExceptionContext) {
     panic!("CPU Synchronous exception {}", exc);
 }
+
+// true if the fault hit a lazily mapped page of the current task, which is now mapped
+fn fix_up_page_fault(fault: &PageFault) -> bool {
+    if !fault.address.is_lower() {
+        return false;
+    }
+    let Some(mm) = SCHEDULER.get().and_then(|s| s.current_address_space()) else {
+        return false;
+    };
+    mm.handle_fault(fault).is_ok()
+}
+
+// the fault is the task's, not the kernel's
+fn kill_current(fault: &impl fmt::Display) -> ! {
+    let sched = SCHEDULER.get().unwrap();
+    println!("task {} killed: {}", sched.current_id(), fault);
+    sched.exit(-(EFAULT.errno() as i32))
+}
+
+// the default handlers of the fault registry
+
+// the instruction at `insn` may fault on user memory, the fault resumes at `fixup`. see
+// `copy_from_user`
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+fn search_exception_table(pc: usize) -> Option<usize> {
+    let table = unsafe {
+        let start = &__ex_table_start as *const u8 as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const u8 as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table
+        .iter()
+        .find(|entry| entry.insn == pc)
+        .map(|entry| entry.fixup)
+}
+
+// the kernel touching memory. a lazily mapped page of the current task is faulted in, a fault of
+// the uaccess helpers resumes at their fixup, which makes the syscall fail with EFAULT. the task
+// is never killed from here, it may hold locks
+fn kernel_page_fault(fault: &Fault, e: &mut ExceptionContext) -> Result<(), ErrorCode> {
+    let fault = fault.page_fault().ok_or(EFAULT)?;
+    // a block split on another core was unmapped for a moment
+    if wait_for_split(fault.address, fault.access == Access::Write) || fix_up_page_fault(&fault) {
+        return Ok(());
+    }
+    e.elr_el1 = search_exception_table(e.elr_el1 as usize).ok_or(EFAULT)? as u64;
+    Ok(())
+}
+
+fn user_page_fault(fault: &Fault, _e: &mut ExceptionContext) -> Result<(), ErrorCode> {
+    let fault = fault.page_fault().ok_or(EFAULT)?;
+    if !fix_up_page_fault(&fault) {
+        kill_current(&fault);
+    }
+    Ok(())
+}
+
+fn kill_on_fault(fault: &Fault, _e: &mut ExceptionContext) -> Result<(), ErrorCode> {
+    kill_current(fault)
+}
+
+fn svc(_fault: &Fault, e: &mut ExceptionContext) -> Result<(), ErrorCode> {
+    if e.gpr[8] as usize == syscall::SYS_FORK {
+        e.gpr[0] = fork(e) as u64;
+    } else {
+        let args = core::array::from_fn(|i| e.gpr[i] as usize);
+        // ELR_EL1 already points past the svc
+        e.gpr[0] = syscall::dispatch(e.gpr[8] as usize, &args) as u64;
+    }
+    Ok(())
+}
+
+// an irq without a handler was ended already, it is not worth a panic
+fn handle_irq() {
+    if let Err(e) = IRQ_CONTROLLER.get().unwrap().handle() {
+        println!("irq not handled: {}", e);
+    }
+}
+
 fn default_irq_exception_handler(exc: &ExceptionContext) {
     panic!("CPU Interrupt Request {}", exc);
 }
@@ -218,6 +354,22 @@ fn default_serro_exception_handler(exc: &ExceptionContext) {
     panic!("CPU SErrir {}", exc);
 }
 
+// entered from CHECK_STACK in exception.s on the emergency stack of this core, `sp` is where the
+// overflowed stack was
+#[no_mangle]
+extern "C" fn stack_overflow(sp: u64, far: u64, elr: u64) -> ! {
+    match SCHEDULER.get().and_then(|s| s.try_current_id()) {
+        Some(id) => panic!(
+            "stack overflow in task {}: sp {:#x}, far {:#x}, elr {:#x}",
+            id, sp, far, elr
+        ),
+        None => panic!(
+            "stack overflow: sp {:#x}, far {:#x}, elr {:#x}",
+            sp, far, elr
+        ),
+    }
+}
+
 // Current, SP_EL0
 
 #[no_mangle]
@@ -238,33 +390,39 @@ extern "C" fn current_el0_serror(_e: &mut ExceptionContext) {
 // Current, SP_ELx
 #[no_mangle]
 extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
-    default_synchronous_exception_handler(e);
+    handle_fault(PrivilegeLevel::Kernel, e);
 }
 
 #[no_mangle]
 extern "C" fn current_elx_irq(e: &mut ExceptionContext) {
-    IRQ_CONTROLLER.get().unwrap().handle().unwrap();
+    handle_irq();
+    if let Some(sched) = SCHEDULER.get() {
+        sched.preempt();
+    }
 }
 
 #[no_mangle]
 extern "C" fn current_elx_serror(e: &mut ExceptionContext) {
-    default_serro_exception_handler(e);
+    handle_fault(PrivilegeLevel::Kernel, e);
 }
 
 // Lower, AArch64
 #[no_mangle]
 extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
-    default_synchronous_exception_handler(e);
+    handle_fault(PrivilegeLevel::User, e);
 }
 
 #[no_mangle]
 extern "C" fn lower_aarch64_irq(e: &mut ExceptionContext) {
-    IRQ_CONTROLLER.get().unwrap().handle().unwrap();
+    handle_irq();
+    if let Some(sched) = SCHEDULER.get() {
+        sched.preempt();
+    }
 }
 
 #[no_mangle]
 extern "C" fn lower_aarch64_serror(e: &mut ExceptionContext) {
-    default_serro_exception_handler(e);
+    handle_fault(PrivilegeLevel::User, e);
 }
 // Lower, AArch32
 #[no_mangle]
@@ -282,6 +440,87 @@ extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext) {
     default_serro_exception_handler(e);
 }
 
+// what the child of a fork returns to EL0 with
+struct ForkedContext {
+    context: ExceptionContext,
+    sp_el0: u64,
+}
+
+// the child gets a copy of the caller's registers, it sees 0 where the parent sees its id
+fn fork(e: &ExceptionContext) -> isize {
+    let mut forked = Box::new(ForkedContext {
+        context: unsafe { core::ptr::read(e) },
+        sp_el0: SP_EL0.get(),
+    });
+    forked.context.gpr[0] = 0;
+    let arg = Box::into_raw(forked);
+    match syscall::fork(resume_forked, arg as usize) {
+        Ok(id) => id.value() as isize,
+        Err(err) => {
+            drop(unsafe { Box::from_raw(arg) });
+            -(err.errno() as isize)
+        }
+    }
+}
+
+fn resume_forked(arg: usize) -> i32 {
+    let forked = unsafe { Box::from_raw(arg as *mut ForkedContext) };
+    let context = unsafe { core::ptr::read(&forked.context) };
+    SP_EL0.set(forked.sp_el0);
+    drop(forked);
+    return_to_el0(&context)
+}
+
+// like __exception_restore_context, but from `context` instead of the exception frame on the
+// stack. the current kernel stack is kept for exceptions
+fn return_to_el0(context: &ExceptionContext) -> ! {
+    local_irq_mask();
+    unsafe {
+        asm!(
+            "ldr x1, [x0, #16 * 16]",
+            "msr SPSR_EL1, x1",
+            "ldp lr, x1, [x0, #16 * 15]",
+            "msr ELR_EL1, x1",
+            "ldp x2, x3, [x0, #16 * 1]",
+            "ldp x4, x5, [x0, #16 * 2]",
+            "ldp x6, x7, [x0, #16 * 3]",
+            "ldp x8, x9, [x0, #16 * 4]",
+            "ldp x10, x11, [x0, #16 * 5]",
+            "ldp x12, x13, [x0, #16 * 6]",
+            "ldp x14, x15, [x0, #16 * 7]",
+            "ldp x16, x17, [x0, #16 * 8]",
+            "ldp x18, x19, [x0, #16 * 9]",
+            "ldp x20, x21, [x0, #16 * 10]",
+            "ldp x22, x23, [x0, #16 * 11]",
+            "ldp x24, x25, [x0, #16 * 12]",
+            "ldp x26, x27, [x0, #16 * 13]",
+            "ldp x28, x29, [x0, #16 * 14]",
+            "ldp x0, x1, [x0, #16 * 0]",
+            "eret",
+            in("x0") context as *const ExceptionContext,
+            options(noreturn),
+        );
+    }
+}
+
+// drop to EL0t at `pc` with `sp` as SP_EL0, the current kernel stack is kept for exceptions
+pub fn enter_el0(pc: usize, sp: usize) -> ! {
+    SPSR_EL1.write(
+        SPSR_EL1::M::EL0t
+            + SPSR_EL1::D::Unmasked
+            + SPSR_EL1::A::Unmasked
+            + SPSR_EL1::I::Unmasked
+            + SPSR_EL1::F::Unmasked,
+    );
+    SP_EL0.set(sp as u64);
+    ELR_EL1.set(pc as u64);
+
+    barrier::isb(barrier::SY);
+    unsafe {
+        asm!("eret", options(noreturn));
+    }
+}
+
 const DAIF_BITS: u8 = 0b0011; // mask IRQ and FIQ
 
 #[inline(always)]
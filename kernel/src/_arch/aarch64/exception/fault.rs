@@ -0,0 +1,345 @@
+//! Synchronous exceptions and SErrors by class
+//!
+//! ESR_EL1 is decoded into a `Fault` and handed to the handler registered for its class and the
+//! level it was taken from, EL0 and EL1 have separate tables. Without a handler, or when the
+//! handler returns an error, the kernel panics with the fault and the exception context.
+use super::{ExceptionContext, PrivilegeLevel};
+use crate::{
+    errno::{ErrorCode, EINVAL, ESUPPORTED},
+    memory::{
+        address::VirtualAddress,
+        address_space::{Access, PageFault},
+    },
+    synchronization::Spinlock,
+};
+use aarch64_cpu::registers::{ESR_EL1, FAR_EL1};
+use core::fmt;
+use tock_registers::{interfaces::Readable, registers::InMemoryRegister};
+
+// data and instruction abort ISS
+const ISS_ISV: u32 = 1 << 24;
+const ISS_SAS_SHIFT: u32 = 22;
+const ISS_SSE: u32 = 1 << 21;
+const ISS_SRT_SHIFT: u32 = 16;
+const ISS_SF: u32 = 1 << 15;
+const ISS_AR: u32 = 1 << 14;
+const ISS_FNV: u32 = 1 << 10;
+const ISS_WNR: u32 = 1 << 6;
+const ISS_FSC_MASK: u32 = 0b11_1111;
+const ISS_IMM16_MASK: u32 = 0xffff;
+
+const FSC_ALIGNMENT: u8 = 0b10_0001;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionClass {
+    DataAbort,
+    InstrAbort,
+    Svc,
+    Brk,
+    // PC and SP alignment, and data aborts with an alignment fault status
+    Alignment,
+    FpAccess,
+    SError,
+    SoftwareStep,
+    Other,
+}
+
+const NUM_CLASSES: usize = 9;
+
+// a single register load or store, valid when ISV is set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessSyndrome {
+    pub size: usize,
+    pub sign_extend: bool,
+    pub register: u8,
+    pub sixty_four: bool,
+    pub acquire_release: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fault {
+    pub class: ExceptionClass,
+    pub level: PrivilegeLevel,
+    pub ec: u8,
+    pub iss: u32,
+    pub pc: VirtualAddress,
+    // FAR_EL1, for aborts whose FnV is clear and PC alignment faults
+    pub address: Option<VirtualAddress>,
+    // DFSC or IFSC
+    pub status: Option<u8>,
+    // WnR
+    pub write: bool,
+    pub syndrome: Option<AccessSyndrome>,
+    // the comment of an svc or brk
+    pub imm: Option<u16>,
+}
+
+impl Fault {
+    pub fn new(level: PrivilegeLevel, esr: u64, far: u64, elr: u64) -> Self {
+        let esr = InMemoryRegister::<u64, ESR_EL1::Register>::new(esr);
+        let ec = esr.read(ESR_EL1::EC) as u8;
+        let iss = esr.read(ESR_EL1::ISS) as u32;
+        let mut fault = Self {
+            class: ExceptionClass::Other,
+            level,
+            ec,
+            iss,
+            pc: VirtualAddress::from(elr as usize),
+            address: None,
+            status: None,
+            write: false,
+            syndrome: None,
+            imm: None,
+        };
+        let far = VirtualAddress::from(far as usize);
+        let class = esr.read_as_enum(ESR_EL1::EC);
+        match class {
+            Some(
+                ESR_EL1::EC::Value::DataAbortLowerEL
+                | ESR_EL1::EC::Value::DataAbortCurrentEL
+                | ESR_EL1::EC::Value::InstrAbortLowerEL
+                | ESR_EL1::EC::Value::InstrAbortCurrentEL,
+            ) => {
+                let status = (iss & ISS_FSC_MASK) as u8;
+                let data = matches!(
+                    class,
+                    Some(
+                        ESR_EL1::EC::Value::DataAbortLowerEL
+                            | ESR_EL1::EC::Value::DataAbortCurrentEL
+                    )
+                );
+                fault.class = match (data, status) {
+                    (true, FSC_ALIGNMENT) => ExceptionClass::Alignment,
+                    (true, _) => ExceptionClass::DataAbort,
+                    (false, _) => ExceptionClass::InstrAbort,
+                };
+                fault.status = Some(status);
+                fault.address = (iss & ISS_FNV == 0).then_some(far);
+                fault.write = data && iss & ISS_WNR != 0;
+                if data && iss & ISS_ISV != 0 {
+                    fault.syndrome = Some(AccessSyndrome {
+                        size: 1 << ((iss >> ISS_SAS_SHIFT) & 0b11),
+                        sign_extend: iss & ISS_SSE != 0,
+                        register: ((iss >> ISS_SRT_SHIFT) & 0b1_1111) as u8,
+                        sixty_four: iss & ISS_SF != 0,
+                        acquire_release: iss & ISS_AR != 0,
+                    });
+                }
+            }
+            Some(ESR_EL1::EC::Value::PCAlignmentFault) => {
+                fault.class = ExceptionClass::Alignment;
+                fault.address = Some(far);
+            }
+            Some(ESR_EL1::EC::Value::SPAlignmentFault) => fault.class = ExceptionClass::Alignment,
+            Some(ESR_EL1::EC::Value::SVC64) => {
+                fault.class = ExceptionClass::Svc;
+                fault.imm = Some((iss & ISS_IMM16_MASK) as u16);
+            }
+            Some(ESR_EL1::EC::Value::Brk64) => {
+                fault.class = ExceptionClass::Brk;
+                fault.imm = Some((iss & ISS_IMM16_MASK) as u16);
+            }
+            Some(ESR_EL1::EC::Value::TrappedFP) => fault.class = ExceptionClass::FpAccess,
+            Some(ESR_EL1::EC::Value::SError) => fault.class = ExceptionClass::SError,
+            Some(
+                ESR_EL1::EC::Value::SoftwareStepLowerEL | ESR_EL1::EC::Value::SoftwareStepCurrentEL,
+            ) => fault.class = ExceptionClass::SoftwareStep,
+            _ => {}
+        }
+        fault
+    }
+
+    // only data and instruction aborts are page faults, an abort without a valid FAR is taken to
+    // be at 0
+    pub fn page_fault(&self) -> Option<PageFault> {
+        let access = match self.class {
+            ExceptionClass::InstrAbort => Access::Execute,
+            ExceptionClass::DataAbort if self.write => Access::Write,
+            ExceptionClass::DataAbort => Access::Read,
+            _ => return None,
+        };
+        Some(PageFault::new(
+            self.address.unwrap_or(VirtualAddress::from(0)),
+            self.status? as u64,
+            access,
+        ))
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let el = match self.level {
+            PrivilegeLevel::User => "EL0",
+            _ => "EL1",
+        };
+        write!(
+            f,
+            "{:?} (EC {:#04x}, ISS {:#x}) from {} at pc {:#x}",
+            self.class,
+            self.ec,
+            self.iss,
+            el,
+            self.pc.value()
+        )?;
+        if let Some(address) = self.address {
+            write!(f, ", address {:#x}", address.value())?;
+        }
+        if let Some(status) = self.status {
+            write!(f, ", status {:#04x}", status)?;
+        }
+        if self.write {
+            write!(f, ", write")?;
+        }
+        if let Some(s) = self.syndrome {
+            write!(f, ", {} byte access with x{}", s.size, s.register)?;
+        }
+        if let Some(imm) = self.imm {
+            write!(f, ", imm {:#x}", imm)?;
+        }
+        Ok(())
+    }
+}
+
+pub type FaultHandler = fn(&Fault, &mut ExceptionContext) -> Result<(), ErrorCode>;
+
+struct Handlers {
+    user: [Option<FaultHandler>; NUM_CLASSES],
+    kernel: [Option<FaultHandler>; NUM_CLASSES],
+}
+
+impl Handlers {
+    fn of(
+        &mut self,
+        level: PrivilegeLevel,
+    ) -> Result<&mut [Option<FaultHandler>; NUM_CLASSES], ErrorCode> {
+        match level {
+            PrivilegeLevel::User => Ok(&mut self.user),
+            PrivilegeLevel::Kernel => Ok(&mut self.kernel),
+            _ => Err(EINVAL),
+        }
+    }
+}
+
+// what the kernel handles itself until a subsystem takes the class over. a plain spinlock, the
+// unlock of an IRQ safe one would unmask interrupts in the middle of an exception
+static HANDLERS: Spinlock<Handlers> = Spinlock::new(Handlers {
+    user: {
+        let mut user: [Option<FaultHandler>; NUM_CLASSES] = [None; NUM_CLASSES];
+        user[ExceptionClass::DataAbort as usize] = Some(super::user_page_fault);
+        user[ExceptionClass::InstrAbort as usize] = Some(super::user_page_fault);
+        user[ExceptionClass::Svc as usize] = Some(super::svc);
+        user[ExceptionClass::Alignment as usize] = Some(super::kill_on_fault);
+        user
+    },
+    kernel: {
+        let mut kernel: [Option<FaultHandler>; NUM_CLASSES] = [None; NUM_CLASSES];
+        kernel[ExceptionClass::DataAbort as usize] = Some(super::kernel_page_fault);
+        kernel
+    },
+});
+
+// replaces the handler of `class` for exceptions taken from `level`, the previous one is returned
+// so it can be chained to or put back
+pub fn register_fault_handler(
+    level: PrivilegeLevel,
+    class: ExceptionClass,
+    handler: FaultHandler,
+) -> Result<Option<FaultHandler>, ErrorCode> {
+    Ok(HANDLERS.lock().of(level)?[class as usize].replace(handler))
+}
+
+pub fn unregister_fault_handler(
+    level: PrivilegeLevel,
+    class: ExceptionClass,
+) -> Result<Option<FaultHandler>, ErrorCode> {
+    Ok(HANDLERS.lock().of(level)?[class as usize].take())
+}
+
+// the handler is called without the lock held, it may well not return
+pub(super) fn handle_fault(level: PrivilegeLevel, e: &mut ExceptionContext) {
+    let fault = Fault::new(level, e.esr_el1.0.get(), FAR_EL1.get(), e.elr_el1);
+    let handler = HANDLERS
+        .lock()
+        .of(level)
+        .ok()
+        .and_then(|handlers| handlers[fault.class as usize]);
+    let result = match handler {
+        Some(handler) => handler(&fault, e),
+        None => Err(ESUPPORTED),
+    };
+    if let Err(err) = result {
+        panic!("Unhandled {}: {:?}\n{}", fault, err, e);
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+    use tock_registers::fields::FieldValue;
+
+    fn ignore(fault: &Fault, e: &mut ExceptionContext) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    fn esr(class: FieldValue<u64, ESR_EL1::Register>, iss: u32) -> u64 {
+        (class + ESR_EL1::ISS.val(iss as u64)).value
+    }
+
+    #[kernel_test]
+    fn test_fault_decode() {
+        // str w1, [x2] to an unmapped page from EL0: ISV, SAS 0b10, SRT 1, WnR, level 3
+        // translation fault
+        let iss = ISS_ISV | (0b10 << ISS_SAS_SHIFT) | (1 << ISS_SRT_SHIFT) | ISS_WNR | 0x07;
+        let data_abort = esr(ESR_EL1::EC::DataAbortLowerEL, iss);
+        let fault = Fault::new(PrivilegeLevel::User, data_abort, 0x1234, 0x400000);
+        assert_eq!(fault.class, ExceptionClass::DataAbort);
+        assert_eq!(fault.status, Some(0x07));
+        assert!(fault.write);
+        assert_eq!(fault.address.unwrap().value(), 0x1234);
+        let syndrome = fault.syndrome.unwrap();
+        assert_eq!((syndrome.size, syndrome.register), (4, 1));
+        assert_eq!(fault.page_fault().unwrap().access, Access::Write);
+
+        // FnV hides the address, an alignment status moves a data abort to its own class
+        let fault = Fault::new(
+            PrivilegeLevel::Kernel,
+            esr(
+                ESR_EL1::EC::DataAbortCurrentEL,
+                ISS_FNV | FSC_ALIGNMENT as u32,
+            ),
+            0x1234,
+            0,
+        );
+        assert_eq!(fault.class, ExceptionClass::Alignment);
+        assert!(fault.address.is_none());
+        assert!(fault.page_fault().is_none());
+
+        let fault = Fault::new(PrivilegeLevel::User, esr(ESR_EL1::EC::SVC64, 0x42), 0, 0);
+        assert_eq!((fault.class, fault.imm), (ExceptionClass::Svc, Some(0x42)));
+        let fault = Fault::new(
+            PrivilegeLevel::Kernel,
+            esr(ESR_EL1::EC::Brk64, 0xf000),
+            0,
+            0,
+        );
+        assert_eq!(
+            (fault.class, fault.imm),
+            (ExceptionClass::Brk, Some(0xf000))
+        );
+    }
+
+    #[kernel_test]
+    fn test_fault_handler_registry() {
+        let previous =
+            register_fault_handler(PrivilegeLevel::Kernel, ExceptionClass::Brk, ignore).unwrap();
+        assert!(previous.is_none());
+        let ours = unregister_fault_handler(PrivilegeLevel::Kernel, ExceptionClass::Brk).unwrap();
+        assert!(ours.is_some());
+        assert!(
+            register_fault_handler(PrivilegeLevel::Hypervisor, ExceptionClass::Brk, ignore)
+                .is_err()
+        );
+    }
+}
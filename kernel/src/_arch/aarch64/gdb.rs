@@ -0,0 +1,264 @@
+//! Registers go out in the order GDB uses without a target description: x0-x30, sp, pc and cpsr.
+//! The FP registers are left out and show as unavailable. Breakpoints replace the instruction with
+//! `brk #BRK_IMM`, single steps use MDSCR_EL1.SS. Only exceptions taken from EL1 stop in the stub.
+use super::{Connection, Resume, Stub, Target, SIGTRAP};
+use crate::{
+    bsp::device_driver::pl011_uart::{UnSafePl011Uart, VIRTUAL_DEBUG_UART_START},
+    errno::*,
+    exception::{register_fault_handler, ExceptionClass, ExceptionContext, Fault, PrivilegeLevel},
+    memory::{address::VirtualAddress, config, probe, translation_table::FixedPage, MMU},
+    synchronization::Spinlock,
+};
+use core::arch::asm;
+use spin::once::Once;
+
+const BRK_IMM: u16 = 0x400;
+const BRK: u32 = 0xd420_0000 | ((BRK_IMM as u32) << 5);
+const INSTRUCTION_SIZE: usize = 4;
+const MAX_BREAKPOINTS: usize = 32;
+
+const REG_LR: usize = 30;
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const REG_CPSR: usize = 33;
+const NUM_REGS: usize = 34;
+
+const MDSCR_SS: u64 = 1 << 0;
+const MDSCR_KDE: u64 = 1 << 13;
+const SPSR_D: u64 = 1 << 9;
+const SPSR_SS: u64 = 1 << 21;
+
+// the address and the instruction the brk replaced
+static BREAKPOINTS: Spinlock<[Option<(usize, u32)>; MAX_BREAKPOINTS]> =
+    Spinlock::new([None; MAX_BREAKPOINTS]);
+
+// read only memory, e.g. the kernel code, is written through this page
+static ALIAS: Once<FixedPage> = Once::new();
+
+// a UART of its own, the console and its lock are left to the stopped code
+struct Uart(UnSafePl011Uart);
+
+impl Connection for Uart {
+    fn read_byte(&mut self) -> u8 {
+        self.0.read_byte()
+    }
+    fn write_byte(&mut self, b: u8) {
+        self.0.send_byte(b)
+    }
+}
+
+struct Kernel<'a> {
+    context: &'a mut ExceptionContext,
+}
+
+impl Kernel<'_> {
+    fn size(n: usize) -> usize {
+        if n == REG_CPSR {
+            4
+        } else {
+            8
+        }
+    }
+
+    fn get(&self, n: usize) -> Option<u64> {
+        match n {
+            0..=29 => Some(self.context.gpr[n]),
+            REG_LR => Some(self.context.lr),
+            REG_SP => Some(self.context.sp()),
+            REG_PC => Some(self.context.elr_el1),
+            REG_CPSR => Some(self.context.spsr()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, n: usize, value: &[u8]) -> Result<(), ErrorCode> {
+        if value.len() != Self::size(n) {
+            return Err(EPARAM);
+        }
+        let mut bytes = [0u8; 8];
+        bytes[..value.len()].copy_from_slice(value);
+        let value = u64::from_le_bytes(bytes);
+        match n {
+            0..=29 => self.context.gpr[n] = value,
+            REG_LR => self.context.lr = value,
+            REG_PC => self.context.elr_el1 = value,
+            REG_CPSR => self.context.set_spsr(value),
+            // the context is saved on that stack, moving it would lose the context
+            REG_SP if value == self.context.sp() => {}
+            REG_SP => return Err(ESUPPORTED),
+            _ => return Err(EPARAM),
+        }
+        Ok(())
+    }
+}
+
+impl Target for Kernel<'_> {
+    fn registers(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for n in 0..NUM_REGS {
+            let size = Self::size(n);
+            let value = self.get(n).unwrap().to_le_bytes();
+            buf[len..len + size].copy_from_slice(&value[..size]);
+            len += size;
+        }
+        len
+    }
+
+    fn set_registers(&mut self, buf: &[u8]) -> Result<(), ErrorCode> {
+        let mut at = 0;
+        for n in 0..NUM_REGS {
+            let size = Self::size(n);
+            self.set(n, buf.get(at..at + size).ok_or(EPARAM)?)?;
+            at += size;
+        }
+        Ok(())
+    }
+
+    fn register(&self, n: usize, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+        let size = Self::size(n);
+        let value = self.get(n).ok_or(EPARAM)?.to_le_bytes();
+        buf[..size].copy_from_slice(&value[..size]);
+        Ok(size)
+    }
+
+    fn set_register(&mut self, n: usize, value: &[u8]) -> Result<(), ErrorCode> {
+        self.set(n, value)
+    }
+
+    // through the current translation tables, a page that is not mapped is an error instead of
+    // a fault
+    fn read_memory(&self, address: usize, buf: &mut [u8]) -> Result<(), ErrorCode> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            let va = VirtualAddress::from(address + i);
+            if i == 0 || va.value() & config::MASK_4K == 0 {
+                probe(va, false).ok_or(EFAULT)?;
+            }
+            *b = unsafe { core::ptr::read_volatile(va.value() as *const u8) };
+        }
+        Ok(())
+    }
+
+    // memory the kernel cannot write to, e.g. its code, is written through an alias
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        let writable = (0..data.len())
+            .filter(|i| *i == 0 || (address + i) & config::MASK_4K == 0)
+            .all(|i| probe(VirtualAddress::from(address + i), true).is_some());
+        if !writable {
+            return write_alias(address, data);
+        }
+        for (i, b) in data.iter().enumerate() {
+            unsafe { core::ptr::write_volatile((address + i) as *mut u8, *b) };
+        }
+        Ok(())
+    }
+
+    fn insert_breakpoint(&mut self, address: usize) -> Result<(), ErrorCode> {
+        if address % INSTRUCTION_SIZE != 0 {
+            return Err(EALIGN);
+        }
+        let mut breakpoints = BREAKPOINTS.lock();
+        if breakpoints.iter().flatten().any(|(a, _)| *a == address) {
+            return Ok(());
+        }
+        let slot = breakpoints.iter_mut().find(|b| b.is_none()).ok_or(EBOUND)?;
+        let mut original = [0u8; INSTRUCTION_SIZE];
+        self.read_memory(address, &mut original)?;
+        // always through the alias, it also brings the instruction cache up to date
+        write_alias(address, &BRK.to_le_bytes())?;
+        *slot = Some((address, u32::from_le_bytes(original)));
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, address: usize) -> Result<(), ErrorCode> {
+        let mut breakpoints = BREAKPOINTS.lock();
+        let slot = breakpoints
+            .iter_mut()
+            .find(|b| matches!(b, Some((a, _)) if *a == address))
+            .ok_or(EPARAM)?;
+        let (_, original) = slot.take().unwrap();
+        write_alias(address, &original.to_le_bytes())
+    }
+
+    fn set_pc(&mut self, pc: usize) {
+        self.context.elr_el1 = pc as u64;
+    }
+}
+
+fn write_alias(address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+    let alias = ALIAS.get().ok_or(EINIT)?;
+    MMU.get()
+        .ok_or(EFAULT)?
+        .write_alias(alias, VirtualAddress::from(address), data)
+}
+
+fn is_breakpoint(address: usize) -> bool {
+    BREAKPOINTS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|(a, _)| *a == address)
+}
+
+fn read_mdscr() -> u64 {
+    let mdscr: u64;
+    unsafe { asm!("MRS {}, MDSCR_EL1", out(reg) mdscr) };
+    mdscr
+}
+
+fn write_mdscr(mdscr: u64) {
+    unsafe { asm!("MSR MDSCR_EL1, {}", "ISB", in(reg) mdscr) };
+}
+
+fn debug_exception(fault: &Fault, e: &mut ExceptionContext) -> Result<(), ErrorCode> {
+    match fault.class {
+        ExceptionClass::SoftwareStep => write_mdscr(read_mdscr() & !MDSCR_SS),
+        ExceptionClass::Brk if fault.imm == Some(BRK_IMM) => {
+            // one built into the kernel, e.g. `breakpoint`, is stepped over on resume
+            if !is_breakpoint(e.elr_el1 as usize) {
+                e.elr_el1 += INSTRUCTION_SIZE as u64;
+            }
+        }
+        // brk with other immediates are not the debugger's
+        _ => return Err(ESUPPORTED),
+    }
+
+    let uart = Uart(UnSafePl011Uart::new(VIRTUAL_DEBUG_UART_START));
+    let resume = Stub::new(uart).serve(&mut Kernel { context: e }, SIGTRAP);
+
+    // PSTATE.D masks the step exception, and is set again on every exception entry
+    let spsr = e.spsr();
+    if resume == Resume::Step {
+        write_mdscr(read_mdscr() | MDSCR_SS | MDSCR_KDE);
+        e.set_spsr((spsr | SPSR_SS) & !SPSR_D);
+    } else {
+        e.set_spsr(spsr & !SPSR_SS);
+    }
+    Ok(())
+}
+
+pub fn init() -> Result<(), ErrorCode> {
+    #[cfg(not(feature = "build_qemu"))]
+    crate::bsp::device_driver::gpio::GPIO
+        .get()
+        .ok_or(EINIT)?
+        .init_debug_uart();
+    UnSafePl011Uart::new(VIRTUAL_DEBUG_UART_START).init();
+    // reserved now, patching code later must not allocate or lock
+    let alias = MMU.get().ok_or(EINIT)?.reserve_fixed_page()?;
+    ALIAS.call_once(|| alias);
+    // the OS lock is set out of reset and holds back step exceptions
+    unsafe { asm!("MSR OSLAR_EL1, xzr", "ISB") };
+    register_fault_handler(PrivilegeLevel::Kernel, ExceptionClass::Brk, debug_exception)?;
+    register_fault_handler(
+        PrivilegeLevel::Kernel,
+        ExceptionClass::SoftwareStep,
+        debug_exception,
+    )?;
+    Ok(())
+}
+
+// stop in the stub, the first time it waits for GDB to attach
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { asm!("BRK #{}", const BRK_IMM) };
+}
@@ -1,37 +1,127 @@
 extern crate alloc;
 use crate::{
-    bsp::device_driver::interrupt_controller,
-    errno::ErrorCode,
-    synchronization::{IRQSafeSpinlock, Spinlock},
+    bsp::device_driver::interrupt_controller, cpu::smp::core_id, errno::*, exception,
+    synchronization::Spinlock,
 };
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap};
 use spin::{Once, Spin};
 
+pub use interrupt_controller::IRQNum;
+
 pub trait IRQHandler {
     fn handle(&self) -> Result<(), ErrorCode>;
 }
 
+pub type IRQHandlerFn = fn() -> Result<(), ErrorCode>;
+
 pub trait IRQController {
     fn init(&mut self) -> Result<(), ErrorCode>;
-    fn handle(&self) -> Result<(), ErrorCode>;
+    // the per core part of the controller, on a secondary core
+    fn init_secondary(&mut self) -> Result<(), ErrorCode>;
+    // the pending irq to handle next, None if there is none e.g. a spurious interrupt
+    fn acknowledge(&self) -> Option<IRQNum>;
+    fn end(&self, irq: IRQNum);
+    // priority 0 is the highest, controllers without priorities ignore it
+    fn enable(&mut self, irq: IRQNum, priority: u8, target_cpu: u8) -> Result<(), ErrorCode>;
+    fn disable(&mut self, irq: IRQNum) -> Result<(), ErrorCode>;
+}
+
+pub struct IRQDescriptor {
+    pub num: IRQNum,
+    pub handler: IRQHandlerFn,
+    pub priority: u8,
+    pub target_cpu: u8,
 }
 
+// both locks are taken in interrupt context, so task context holds them with irqs masked
 pub struct GenericIRQController {
-    controller: IRQSafeSpinlock<Box<dyn IRQController + Sync + Send>>,
+    controller: Spinlock<Box<dyn IRQController + Sync + Send>>,
+    descriptors: Spinlock<BTreeMap<IRQNum, IRQDescriptor>>,
 }
 
 impl GenericIRQController {
     fn new() -> Self {
         Self {
-            controller: IRQSafeSpinlock::new(Box::new(interrupt_controller::create())),
+            controller: Spinlock::new(Box::new(interrupt_controller::create())),
+            descriptors: Spinlock::new(BTreeMap::new()),
         }
     }
 
+    fn with_irqs_masked<R>(&self, f: impl FnOnce() -> R) -> R {
+        let daif = exception::local_irq_mask_save();
+        let r = f();
+        exception::local_irq_restore(daif);
+        r
+    }
+
     pub fn init(&self) -> Result<(), ErrorCode> {
-        self.controller.lock().init()
+        self.with_irqs_masked(|| self.controller.lock().init())
+    }
+
+    // per core irqs requested so far are enabled on the secondary core as well
+    pub fn init_secondary(&self) -> Result<(), ErrorCode> {
+        self.with_irqs_masked(|| {
+            let mut controller = self.controller.lock();
+            controller.init_secondary()?;
+            let descriptors = self.descriptors.lock();
+            for d in descriptors.values().filter(|d| d.num.is_per_core()) {
+                controller.enable(d.num, d.priority, core_id() as u8)?;
+            }
+            Ok(())
+        })
     }
+
+    // the handler runs in interrupt context without any lock held
+    pub fn request_irq(
+        &self,
+        num: IRQNum,
+        handler: IRQHandlerFn,
+        priority: u8,
+        target_cpu: u8,
+    ) -> Result<(), ErrorCode> {
+        self.with_irqs_masked(|| {
+            let mut controller = self.controller.lock();
+            let mut descriptors = self.descriptors.lock();
+            if descriptors.contains_key(&num) {
+                return Err(EINVAL);
+            }
+            controller.enable(num, priority, target_cpu)?;
+            descriptors.insert(
+                num,
+                IRQDescriptor {
+                    num,
+                    handler,
+                    priority,
+                    target_cpu,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    pub fn free_irq(&self, num: IRQNum) -> Result<(), ErrorCode> {
+        self.with_irqs_masked(|| {
+            let mut controller = self.controller.lock();
+            let mut descriptors = self.descriptors.lock();
+            if !descriptors.contains_key(&num) {
+                return Err(EPARAM);
+            }
+            controller.disable(num)?;
+            descriptors.remove(&num);
+            Ok(())
+        })
+    }
+
+    // called with irqs masked. the irq is ended even if it has no handler, e.g. it was freed on
+    // another core after it became pending
     pub fn handle(&self) -> Result<(), ErrorCode> {
-        self.controller.lock().handle()
+        let Some(irq) = self.controller.lock().acknowledge() else {
+            return Ok(());
+        };
+        let handler = self.descriptors.lock().get(&irq).map(|d| d.handler);
+        let result = handler.ok_or(ESUPPORTED).and_then(|handler| handler());
+        self.controller.lock().end(irq);
+        result
     }
 }
 
@@ -41,3 +131,25 @@ pub fn init() -> Result<(), ErrorCode> {
 }
 
 pub static IRQ_CONTROLLER: Once<GenericIRQController> = Once::new();
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::{bsp::device_driver::interrupt_controller::CORE_PS_TIMER_IRQ, cpu::timer};
+    use test_macros::kernel_test;
+
+    #[kernel_test]
+    fn test_request_irq() {
+        let controller = IRQ_CONTROLLER.get().unwrap();
+        // the timer is requested while booting
+        assert!(controller
+            .request_irq(CORE_PS_TIMER_IRQ, timer::handle_interrupt, 0, 0)
+            .is_err());
+        controller.free_irq(CORE_PS_TIMER_IRQ).unwrap();
+        assert!(controller.free_irq(CORE_PS_TIMER_IRQ).is_err());
+        controller
+            .request_irq(CORE_PS_TIMER_IRQ, timer::handle_interrupt, 0, 0)
+            .unwrap();
+    }
+}
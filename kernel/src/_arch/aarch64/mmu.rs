@@ -1,17 +1,22 @@
-use crate::{errno::*, println, BootInfo};
+use crate::{errno::*, fdt, println, BootInfo};
 use aarch64_cpu::registers::*;
+use core::arch::asm;
 use spin::once::Once;
 use tock_registers::interfaces::{Readable, Writeable};
 extern crate alloc;
 use crate::synchronization::Spinlock;
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap};
 
 #[path = "mmu/address.rs"]
 pub mod address;
+#[path = "mmu/address_space.rs"]
+pub mod address_space;
 #[path = "mmu/cache.rs"]
 mod cache;
 #[path = "mmu/config.rs"]
 pub mod config;
+#[path = "mmu/tlb.rs"]
+pub mod tlb;
 #[path = "mmu/translation_entry.rs"]
 pub mod translation_entry;
 #[path = "mmu/translation_table.rs"]
@@ -19,8 +24,13 @@ pub mod translation_table;
 
 #[path = "mmu/allocator.rs"]
 mod allocator;
+#[path = "mmu/buddy.rs"]
+mod buddy;
 #[path = "mmu/heap.rs"]
 pub mod heap;
+#[cfg(feature = "kasan")]
+#[path = "mmu/kasan.rs"]
+mod kasan;
 
 use address::*;
 use allocator::*;
@@ -29,6 +39,10 @@ pub use translation_entry::*;
 use translation_table::*;
 
 const INIT_HEAP_PAGE: usize = 6;
+// 2M blocks at the top of the higher free pages for the device tree blob
+const DEVICE_TREE_BLOCKS: usize = 2;
+
+static DEVICE_TREE: Once<&'static [u8]> = Once::new();
 
 extern "C" {
     fn clear_memory_range(start: usize, end_exclusive: usize);
@@ -115,11 +129,163 @@ pub fn init(boot_info: &BootInfo) -> Result<(), ErrorCode> {
 
     heap::heap_init(va_range)?;
 
-    allocator::init(&boot_info_copy)?;
+    let memory =
+        map_device_tree(boot_info.dtb, &mut boot_info_copy.higher_free_page).and_then(|blob| {
+            let mut map = fdt::Fdt::new(blob)?.memory_map()?;
+            let dtb = boot_info.dtb.value();
+            map.reserved.push(PaRange::new(dtb, dtb + blob.len()))?;
+            DEVICE_TREE.call_once(|| blob);
+            Ok(map)
+        });
+    match memory {
+        Ok(ref map) => {
+            for range in map.memory.iter().flatten() {
+                println!("Memory {}", range);
+            }
+            for range in map.reserved.iter().flatten() {
+                println!("Reserved {}", range);
+            }
+        }
+        Err(e) => println!(
+            "No device tree at {} ({}), using {}",
+            boot_info.dtb, e, boot_info.free_frame
+        ),
+    }
+
+    allocator::init(&boot_info_copy, memory.as_ref().ok())?;
+
+    let split_page = MMU.get().unwrap().reserve_fixed_page()?;
+    SPLIT_PAGE.call_once(|| Spinlock::new(split_page));
+
+    address_space::init()?;
 
     Ok(())
 }
 
+// the blob the firmware passed in x0, read-only. it is mapped with 2M blocks into the higher L2
+// table that the boot code left for the stacks and MMIO, so no frame has to be allocated. never
+// through the identity mapping of the lower half, which goes away with the first address space.
+// `free` gives up the window
+fn map_device_tree(dtb: PhysicalAddress, free: &mut VaRange) -> Result<&'static [u8], ErrorCode> {
+    if dtb.value() == 0 {
+        return Err(EINVAL);
+    }
+    let mmu = MMU.get().unwrap();
+    let window_size = DEVICE_TREE_BLOCKS << config::SHIFT_2M;
+    let end = free.end().value() & config::ALIGN_2M;
+    let start = end.checked_sub(window_size).ok_or(EBOUND)?;
+    if start < free.start().value() {
+        return Err(EBOUND);
+    }
+    let base = dtb.value() & config::ALIGN_2M;
+    let offset = dtb.value() - base;
+    let blob = start + offset;
+
+    mmu.map(
+        VirtualAddress::from(start),
+        PhysicalAddress::from(base),
+        RONORMAL,
+        BLOCK_2M,
+    )?;
+    *free = VaRange::new(free.start(), VirtualAddress::from(start));
+
+    let header = unsafe { core::slice::from_raw_parts(blob as *const u8, fdt::HEADER_SIZE) };
+    let size = fdt::total_size(header)?;
+    if offset + size > window_size {
+        return Err(EBOUND);
+    }
+    for block in 1..DEVICE_TREE_BLOCKS {
+        let mapped = block << config::SHIFT_2M;
+        if offset + size > mapped {
+            mmu.map(
+                VirtualAddress::from(start + mapped),
+                PhysicalAddress::from(base + mapped),
+                RONORMAL,
+                BLOCK_2M,
+            )?;
+        }
+    }
+    Ok(unsafe { core::slice::from_raw_parts(blob as *const u8, size) })
+}
+
+// the device tree blob, if the firmware passed one
+pub fn device_tree() -> Option<&'static [u8]> {
+    DEVICE_TREE.get().copied()
+}
+
+const PAR_F: u64 = 1;
+const PAR_PA_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+// what the MMU itself makes of an EL1 access to `va` right now. asks the hardware instead of
+// walking the tables, so it takes no lock and is safe while handling an exception or a panic
+pub fn probe(va: VirtualAddress, write: bool) -> Option<PhysicalAddress> {
+    let par: u64;
+    unsafe {
+        if write {
+            asm!("AT S1E1W, {}", "ISB", "MRS {}, PAR_EL1", in(reg) va.value(), out(reg) par);
+        } else {
+            asm!("AT S1E1R, {}", "ISB", "MRS {}, PAR_EL1", in(reg) va.value(), out(reg) par);
+        }
+    }
+    par_to_pa(par, va)
+}
+
+// the same for an access from EL0, i.e. whether user code could touch `va`
+pub fn probe_user(va: VirtualAddress, write: bool) -> Option<PhysicalAddress> {
+    let par: u64;
+    unsafe {
+        if write {
+            asm!("AT S1E0W, {}", "ISB", "MRS {}, PAR_EL1", in(reg) va.value(), out(reg) par);
+        } else {
+            asm!("AT S1E0R, {}", "ISB", "MRS {}, PAR_EL1", in(reg) va.value(), out(reg) par);
+        }
+    }
+    par_to_pa(par, va)
+}
+
+fn par_to_pa(par: u64, va: VirtualAddress) -> Option<PhysicalAddress> {
+    if par & PAR_F != 0 {
+        return None;
+    }
+    let offset = va.value() & (config::PAGE_SIZE - 1);
+    Some(PhysicalAddress::from((par & PAR_PA_MASK) as usize | offset))
+}
+
+// copy from the user address `src` into `dst` with unprivileged loads, so EL0's permissions
+// apply. the load has an entry in the exception table: a fault the kernel cannot fix up ends the
+// copy early and it is EFAULT
+pub fn copy_from_user(dst: &mut [u8], src: VirtualAddress) -> Result<(), ErrorCode> {
+    let end = src.value().checked_add(dst.len()).ok_or(EFAULT)?;
+    if !src.is_lower() || !VirtualAddress::from(end).is_lower() {
+        return Err(EFAULT);
+    }
+    let left: usize;
+    unsafe {
+        asm!(
+            "cbz {n}, 2f",
+            "1: ldtrb {b:w}, [{src}]",
+            "strb {b:w}, [{dst}], #1",
+            "add {src}, {src}, #1",
+            "subs {n}, {n}, #1",
+            "b.ne 1b",
+            "2:",
+            ".pushsection __ex_table, \"a\"",
+            ".balign 8",
+            ".quad 1b, 2b",
+            ".popsection",
+            src = inout(reg) src.value() => _,
+            dst = inout(reg) dst.as_mut_ptr() => _,
+            n = inout(reg) dst.len() => left,
+            b = out(reg) _,
+            options(nostack),
+        );
+    }
+    match left {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum BlockSize {
     _4K,
@@ -143,6 +309,8 @@ pub struct MemoryManagementUnit {
     lower_l1: Spinlock<UnsafeTranslationTable<Level1>>,
     higher_l1: Spinlock<UnsafeTranslationTable<Level1>>,
     cache: A64CacheSet,
+    // the start of every vmalloc area and its number of pages, without the guard pages
+    vmalloc_areas: Spinlock<BTreeMap<VirtualAddress, usize>>,
 }
 impl MemoryManagementUnit {
     pub fn new(
@@ -153,6 +321,7 @@ impl MemoryManagementUnit {
             lower_l1: Spinlock::new(lower_l1_table),
             higher_l1: Spinlock::new(higher_l1_table),
             cache: A64CacheSet::new().unwrap(),
+            vmalloc_areas: Spinlock::new(BTreeMap::new()),
         }
     }
 
@@ -169,6 +338,22 @@ impl MemoryManagementUnit {
             self.higher_l1.lock().map(va, pa, mt, sz)
         }
     }
+    // map `va` to `pa` with the largest blocks their alignment allows
+    pub fn map_range(&self, va: VaRange, pa: PaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        if va.start().is_lower() {
+            self.lower_l1.lock().map_range(va, pa, mt)
+        } else {
+            self.higher_l1.lock().map_range(va, pa, mt)
+        }
+    }
+    // change the type of a mapped range, blocks it covers in part are split
+    pub fn protect_range(&self, va: VaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        if va.start().is_lower() {
+            self.lower_l1.lock().protect_range(va, mt)
+        } else {
+            self.higher_l1.lock().protect_range(va, mt)
+        }
+    }
     fn translate(&self, va: VirtualAddress) -> Option<PhysicalAddress> {
         if va.is_lower() {
             self.lower_l1.lock().translate(va)
@@ -191,24 +376,181 @@ impl MemoryManagementUnit {
             .get()
             .unwrap()
             .allocate_n(npage)?;
-        va.start()
-            .iter_4K_for(npage)
+        self.map_range(va, pa, mt).unwrap();
+        unsafe {
+            clear_memory_range(va.start().value(), va.end().value());
+        }
+        Ok(Mapped { va, pa })
+    }
+
+    // map frames that are not managed by the frame allocator, e.g. firmware tables
+    pub fn map_physical(&self, pa: PaRange, mt: &MemoryType) -> Result<Mapped, ErrorCode> {
+        let npage = pa.count_4K()?;
+        let va = allocator::PAGE_ALLOCATOR
+            .get()
+            .unwrap()
+            .allocate_n(npage, HIGHER_PAGE)?;
+        self.map_range(va, pa, mt).unwrap();
+        Ok(Mapped { va, pa })
+    }
+
+    // `npage` zeroed pages that are contiguous in virtual memory only, every page has a frame of
+    // its own. an unmapped guard page is left on both sides.
+    pub fn vmalloc(&self, npage: usize) -> Result<VaRange, ErrorCode> {
+        if npage == 0 {
+            return Err(EPARAM);
+        }
+        let area = allocator::PAGE_ALLOCATOR
+            .get()
             .unwrap()
-            .zip(pa.start().iter_4K_for(npage).unwrap())
-            .for_each(|(va, pa)| {
-                self.map(va, pa, mt, BLOCK_4K).unwrap();
+            .allocate_n(npage + 2, HIGHER_PAGE)?;
+        let start = area.start() + VirtualAddress::_4K;
+        let va = VaRange::new(
+            start,
+            start + VirtualAddress::from(npage << config::SHIFT_4K),
+        );
+
+        for (i, page) in va.start().iter_4K_for(npage).ok_or(EPARAM)?.enumerate() {
+            let frames = allocator::FRAME_ALLOCATOR.get().unwrap();
+            let mapped = frames.allocate(BLOCK_4K).and_then(|pa| {
+                self.map(page, pa.start(), RWNORMAL, BLOCK_4K).map_err(|e| {
+                    frames.free_range(pa).unwrap();
+                    e
+                })
             });
+            if let Err(e) = mapped {
+                self.unmap_pages(va.start(), i);
+                allocator::PAGE_ALLOCATOR.get().unwrap().free_range(area);
+                return Err(e);
+            }
+        }
         unsafe {
             clear_memory_range(va.start().value(), va.end().value());
         }
-        Ok(Mapped { va, pa })
+        self.vmalloc_areas.lock().insert(va.start(), npage);
+        Ok(va)
+    }
+
+    // `va` has to be the start of an area from vmalloc
+    pub fn vfree(&self, va: VirtualAddress) -> Result<(), ErrorCode> {
+        let npage = self.vmalloc_areas.lock().remove(&va).ok_or(EPARAM)?;
+        self.unmap_pages(va, npage);
+        let guard = va - VirtualAddress::_4K;
+        let end = va + VirtualAddress::from((npage + 1) << config::SHIFT_4K);
+        allocator::PAGE_ALLOCATOR
+            .get()
+            .unwrap()
+            .free_range(VaRange::new(guard, end));
+        Ok(())
     }
 
-    pub fn allocate_stack(&self, npage: usize) -> Result<Mapped, ErrorCode> {
-        self.kzalloc(npage, RWNORMAL, HIGHER_PAGE)
+    // unmap `npage` higher half 4K pages from `va` and free their frames, the virtual addresses
+    // are left to the caller
+    fn unmap_pages(&self, va: VirtualAddress, npage: usize) {
+        for page in va.iter_4K_for(npage).into_iter().flatten() {
+            if let Ok(pa) = self.higher_l1.lock().unmap(page) {
+                allocator::FRAME_ALLOCATOR
+                    .get()
+                    .unwrap()
+                    .free_range(pa)
+                    .unwrap();
+            }
+        }
+    }
+
+    // a page of its own for a FixedPage, its tables are made by mapping it once
+    pub fn reserve_fixed_page(&self) -> Result<FixedPage, ErrorCode> {
+        let pages = allocator::PAGE_ALLOCATOR.get().unwrap();
+        let frames = allocator::FRAME_ALLOCATOR.get().unwrap();
+        let va = pages.allocate(BLOCK_4K, HIGHER_PAGE)?;
+        let pa = frames.allocate(BLOCK_4K).map_err(|e| {
+            pages.free_range(va);
+            e
+        })?;
+        let mapped = self.map(va.start(), pa.start(), RWNORMAL, BLOCK_4K);
+        if mapped.is_ok() {
+            self.higher_l1.lock().unmap(va.start())?;
+        }
+        frames.free_range(pa)?;
+        if let Err(e) = mapped {
+            pages.free_range(va);
+            return Err(e);
+        }
+        Ok(FixedPage::new(va.start()))
+    }
+
+    // an overflow runs into the guard page below and faults, see CHECK_STACK in exception.s
+    pub fn allocate_stack(&self, npage: usize) -> Result<VaRange, ErrorCode> {
+        self.vmalloc(npage)
+    }
+    pub fn free_stack(&self, stack: VaRange) -> Result<(), ErrorCode> {
+        self.vfree(stack.start())
+    }
+
+    // write `data` to kernel memory at `va` through `alias` pointed at its frames one by one,
+    // e.g. a breakpoint into the read only kernel code. takes no lock, so it is fine while the
+    // stopped code holds one. the instruction caches see the write
+    pub fn write_alias(
+        &self,
+        alias: &FixedPage,
+        va: VirtualAddress,
+        data: &[u8],
+    ) -> Result<(), ErrorCode> {
+        let mut done = 0;
+        while done < data.len() {
+            let cur = va + VirtualAddress::from(done);
+            let offset = cur.value() & (config::PAGE_SIZE - 1);
+            let n = (data.len() - done).min(config::PAGE_SIZE - offset);
+            let pa = probe(cur, false).ok_or(EUNMAP)?;
+            let frame = PhysicalAddress::from(pa.value() - offset);
+            let start = alias.map(frame, RWNORMAL)?;
+            let dst = start + VirtualAddress::from(offset);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), dst.value() as *mut u8, n);
+            }
+            // the whole page, the cache line helpers want aligned ranges
+            self.cache
+                .dc_clean_va_range_pou(start, start + VirtualAddress::_4K);
+            let page = VirtualAddress::from(cur.value() - offset);
+            self.cache
+                .ic_inalidate_va_range_pou(page, page + VirtualAddress::_4K);
+            alias.unmap()?;
+            done += n;
+        }
+        Ok(())
     }
 
     pub fn unmap(&self, va: VirtualAddress) -> Result<(), ErrorCode> {
+        let pa = self.unmap_keep_frame(va)?;
+        allocator::FRAME_ALLOCATOR.get().unwrap().free_range(pa)
+    }
+
+    // unmap the 4K pages in `range` and free their frames and virtual addresses, whatever
+    // descriptors they are mapped with
+    pub fn unmap_range(&self, range: VaRange) -> Result<(), ErrorCode> {
+        if !range.start().is_4K_aligned() || !range.is_4K_multiple() {
+            return Err(EALIGN);
+        }
+        let npage = range.count_4K()?;
+        for page in range.start().iter_4K_for(npage).ok_or(EPARAM)? {
+            let pa = self.translate(page).ok_or(EUNMAP)?;
+            let page_range = page.to_4K_range();
+            if page.is_lower() {
+                self.lower_l1.lock().unmap_range(page_range)
+            } else {
+                self.higher_l1.lock().unmap_range(page_range)
+            }?;
+            allocator::FRAME_ALLOCATOR
+                .get()
+                .unwrap()
+                .free_range(pa.to_4K_range())?;
+        }
+        allocator::PAGE_ALLOCATOR.get().unwrap().free_range(range);
+        Ok(())
+    }
+
+    // the frame is left to the caller, e.g. it is still mapped in a user address space
+    fn unmap_keep_frame(&self, va: VirtualAddress) -> Result<PaRange, ErrorCode> {
         let pa = if va.is_lower() {
             self.lower_l1.lock().unmap(va)
         } else {
@@ -224,9 +566,39 @@ impl MemoryManagementUnit {
         };
 
         allocator::PAGE_ALLOCATOR.get().unwrap().free_range(va);
-        allocator::FRAME_ALLOCATOR.get().unwrap().free_range(pa);
 
-        Ok(())
+        Ok(pa)
+    }
+
+    // the lower half through the recursive entry, so these act on the active user address space
+    fn map_user(
+        &self,
+        va: VirtualAddress,
+        pa: PhysicalAddress,
+        mt: &MemoryType,
+    ) -> Result<Mapped, ErrorCode> {
+        self.lower_l1.lock().map_user(va, pa, mt)
+    }
+    fn unmap_user(&self, va: VirtualAddress) -> Result<PaRange, ErrorCode> {
+        self.lower_l1.lock().unmap_user(va)
+    }
+    fn remap_user(
+        &self,
+        va: VirtualAddress,
+        pa: PhysicalAddress,
+        mt: &MemoryType,
+    ) -> Result<PaRange, ErrorCode> {
+        self.lower_l1.lock().remap_user(va, pa, mt)
+    }
+    fn for_each_user_page(
+        &self,
+        shared: &UnsafeTranslationTable<Level1>,
+        f: impl FnMut(VirtualAddress, &Descriptor),
+    ) {
+        self.lower_l1.lock().for_each_user_page(shared, f)
+    }
+    fn release_lower(&self, shared: &UnsafeTranslationTable<Level1>) {
+        self.lower_l1.lock().release_lower(shared)
     }
 }
 
@@ -240,13 +612,53 @@ mod tests {
 
     #[kernel_test]
     fn test_mmu() {
-        let mapped = MMU.get().unwrap().allocate_stack(1).unwrap();
-        let start = mapped.va.start();
+        let stack = MMU.get().unwrap().allocate_stack(1).unwrap();
+        let start = stack.start();
         unsafe {
             core::ptr::write_volatile(start.value() as *mut u8, 1);
         }
         println!("write success!");
+        assert!(MMU
+            .get()
+            .unwrap()
+            .translate(start - VirtualAddress::_4K)
+            .is_none());
+
+        MMU.get().unwrap().free_stack(stack).unwrap();
+    }
+
+    #[kernel_test]
+    fn test_vmalloc() {
+        let mmu = MMU.get().unwrap();
+        let frames = allocator::FRAME_ALLOCATOR.get().unwrap();
+        let free = frames.stats().free;
+
+        let va = mmu.vmalloc(3).unwrap();
+        assert_eq!(va.count_4K().unwrap(), 3);
+        // new translation tables may take frames as well
+        let used = frames.stats().free;
+        assert!(used <= free - 3);
+        assert!(mmu.translate(va.start() - VirtualAddress::_4K).is_none());
+        assert!(mmu.translate(va.end()).is_none());
+        for page in va.start().iter_4K_for(3).unwrap() {
+            let p = page.value() as *mut u64;
+            unsafe {
+                assert_eq!(core::ptr::read_volatile(p), 0);
+                core::ptr::write_volatile(p, page.value() as u64);
+            }
+        }
 
-        MMU.get().unwrap().unmap(start).unwrap();
+        // only the start of an area can be freed
+        assert!(mmu.vfree(va.start() + VirtualAddress::_4K).is_err());
+        mmu.vfree(va.start()).unwrap();
+        assert!(mmu.translate(va.start()).is_none());
+        assert_eq!(frames.stats().free, used + 3);
+        assert!(mmu.vfree(va.start()).is_err());
+
+        // nor memory that did not come from vmalloc
+        let other = mmu.kzalloc(1, RWNORMAL, HIGHER_PAGE).unwrap();
+        assert!(mmu.vfree(other.va.start()).is_err());
+        assert!(mmu.translate(other.va.start()).is_some());
+        mmu.unmap(other.va.start()).unwrap();
     }
 }
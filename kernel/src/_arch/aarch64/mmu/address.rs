@@ -70,6 +70,11 @@ macro_rules! impl_address {
                 (self.0 & (!(alignment - 1))) == self.0
             }
 
+            // the start of the 4K page the address lies in
+            pub fn page_start(self) -> Self {
+                Self(self.0 & config::ALIGN_4K)
+            }
+
             pub fn align_to_4K_up(self) -> Self {
                 Self(self.0 & config::ALIGN_4K)
             }
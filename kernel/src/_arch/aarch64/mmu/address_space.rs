@@ -0,0 +1,845 @@
+//! Per process user address spaces on TTBR0
+//!
+//! Every address space has its own level 1 table and ASID, so switching between them needs no TLB
+//! maintenance. Like the boot table it maps itself at RECURSIVE_L1_INDEX, which means its lower
+//! half can only be edited while it is the active one on this core. Kernel tasks run on the boot
+//! table with ASID 0.
+//!
+//! In the qemu build the kernel lives in the lower half, so every address space starts with the
+//! level 1 entries of the boot table. Those are shared and never released here. A kernel mapping
+//! that needs a new level 1 entry while a user address space is active would only land in that
+//! address space, the boot mappings are expected to cover the kernel.
+//!
+//! What may be mapped where is described by the VMAs of the address space. Pages of a VMA are
+//! either mapped up front or on their first touch, see `handle_fault`.
+//!
+//! `mmap` places anonymous VMAs between MMAP_BASE and MMAP_END unless told where, `munmap` and
+//! `mprotect` cut the VMAs they only partly cover.
+//!
+//! `fork` shares every page of an address space with its copy. Writable pages are mapped
+//! read-only in both and the frame allocator counts their owners, the first write to one of them
+//! faults and gets a private copy.
+
+extern crate alloc;
+use super::{
+    address::*, allocator::FRAME_ALLOCATOR, config, tlb, translation_entry::*,
+    translation_table::*, HIGHER_PAGE, MMU, RWNORMAL,
+};
+use crate::{errno::*, exception, synchronization::Spinlock};
+use aarch64_cpu::{asm::barrier, registers::TTBR0_EL1};
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt;
+use intrusive_collections::{intrusive_adapter, Bound, KeyAdapter, RBTree, RBTreeLink};
+use tock_registers::interfaces::{Readable, Writeable};
+
+const NUM_OF_ASIDS: usize = 1 << 8; // TCR_EL1.AS = 0
+const KERNEL_ASID: u8 = 0;
+const ASID_SHIFT: usize = 48;
+
+// where mmap looks for room, clear of the boot table, executables and the stack
+pub const MMAP_BASE: usize = 0x50_0000_0000;
+pub const MMAP_END: usize = 0x70_0000_0000;
+
+extern "C" {
+    static l1_lower_page_table: u8;
+}
+
+// bit i is set while ASID i is in use
+static ASIDS: Spinlock<[u64; NUM_OF_ASIDS / 64]> = Spinlock::new([1 << KERNEL_ASID, 0, 0, 0]);
+
+fn allocate_asid() -> Result<u8, ErrorCode> {
+    let mut asids = ASIDS.lock();
+    for (i, word) in asids.iter_mut().enumerate() {
+        if *word != u64::MAX {
+            let bit = word.trailing_ones() as usize;
+            *word |= 1 << bit;
+            return Ok((i * 64 + bit) as u8);
+        }
+    }
+    Err(EAGAIN)
+}
+
+fn free_asid(asid: u8) {
+    ASIDS.lock()[asid as usize / 64] &= !(1 << (asid as usize % 64));
+}
+
+// the boot table through the kernel image, independent of what TTBR0 points to
+fn boot_l1() -> UnsafeTranslationTable<Level1> {
+    UnsafeTranslationTable::new(unsafe { &l1_lower_page_table as *const u8 as *mut L1Entry })
+}
+
+fn boot_l1_pa() -> PhysicalAddress {
+    PhysicalAddress::from(
+        unsafe { &l1_lower_page_table as *const u8 as usize } - config::KERNEL_BASE,
+    )
+}
+
+// both fields in one write, otherwise the walker could cache the old table under the new ASID
+fn write_ttbr0(pa: PhysicalAddress, asid: u8) {
+    TTBR0_EL1.set(((asid as u64) << ASID_SHIFT) | pa.value() as u64);
+    barrier::isb(barrier::SY);
+}
+
+// the kernel's own recursive window must not survive a switch in the TLB either
+pub(super) fn init() -> Result<(), ErrorCode> {
+    let boot = boot_l1();
+    let mut recursive = boot[config::RECURSIVE_L1_INDEX].get();
+    recursive.set_nG()?;
+    boot.set_entry(
+        config::RECURSIVE_L1_INDEX,
+        TranslationTableEntry::from(recursive),
+    )?;
+    Ok(())
+}
+
+// back to the boot table, used when switching to a kernel task
+pub fn activate_kernel() {
+    write_ttbr0(boot_l1_pa(), KERNEL_ASID);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FaultKind {
+    Translation,
+    AccessFlag,
+    Permission,
+    Other,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn allowed_by(&self, mt: &MemoryType) -> bool {
+        match self {
+            Access::Read => true,
+            Access::Write => matches!(
+                mt,
+                MemoryType::RwNormal | MemoryType::RWXNormal | MemoryType::RwDevice
+            ),
+            Access::Execute => matches!(mt, MemoryType::XNormal | MemoryType::RWXNormal),
+        }
+    }
+}
+
+// how a writable page is mapped while its frame may be shared, other pages are shared as they are
+fn copy_on_write(mt: &MemoryType) -> Option<&'static MemoryType> {
+    match mt {
+        MemoryType::RwNormal => Some(RONORMAL),
+        MemoryType::RWXNormal => Some(XNORMAL),
+        _ => None,
+    }
+}
+
+// a data or instruction abort
+#[derive(Copy, Clone)]
+pub struct PageFault {
+    pub address: VirtualAddress,
+    pub kind: FaultKind,
+    pub access: Access,
+}
+
+impl PageFault {
+    // `status` is the DFSC or IFSC field of ESR_EL1
+    pub fn new(address: VirtualAddress, status: u64, access: Access) -> Self {
+        let kind = match status & 0b11_1100 {
+            0b00_0100 => FaultKind::Translation,
+            0b00_1000 => FaultKind::AccessFlag,
+            0b00_1100 => FaultKind::Permission,
+            _ => FaultKind::Other,
+        };
+        Self {
+            address,
+            kind,
+            access,
+        }
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} fault on {:?} at {:#x}",
+            self.kind,
+            self.access,
+            self.address.value()
+        )
+    }
+}
+
+struct Vma {
+    link: RBTreeLink,
+    range: VaRange,
+    mt: MemoryType,
+}
+
+impl Vma {
+    fn new(range: VaRange, mt: MemoryType) -> Box<Self> {
+        Box::new(Self {
+            link: RBTreeLink::new(),
+            range,
+            mt,
+        })
+    }
+}
+
+// keyed by the end like AddressRangeAdaptor, the first VMA ending above an address is the only
+// one that can contain it
+intrusive_adapter!(VmaAdaptor = Box<Vma> : Vma { link: RBTreeLink });
+impl<'a> KeyAdapter<'a> for VmaAdaptor {
+    type Key = VirtualAddress;
+    fn get_key(&self, vma: &'a Vma) -> Self::Key {
+        vma.range.end()
+    }
+}
+
+// page aligned, not empty and below the recursive window, without touching the boot table
+fn check_user_range(va: VaRange) -> Result<(), ErrorCode> {
+    if !va.start().is_4K_aligned() || !va.end().is_4K_aligned() || va.empty() {
+        return Err(EALIGN);
+    }
+    if va.end() < va.start()
+        || !va.end().is_lower()
+        || va.end().value() > config::RECURSIVE_L1_INDEX << config::L1_INDEX_SHIFT
+    {
+        return Err(EPARAM);
+    }
+    let boot = boot_l1();
+    let first = va.start().level1();
+    let last = (va.end() - VirtualAddress::from(1)).level1();
+    if (first..=last).any(|idx| boot[idx].is_valid()) {
+        return Err(EPARAM);
+    }
+    Ok(())
+}
+
+fn overlaps(vmas: &RBTree<VmaAdaptor>, va: VaRange) -> bool {
+    vmas.lower_bound(Bound::Excluded(&va.start()))
+        .get()
+        .is_some_and(|next| next.range.start() < va.end())
+}
+
+// true if there is no hole in the VMAs over `va`
+fn covers(vmas: &RBTree<VmaAdaptor>, va: VaRange) -> bool {
+    let mut next = va.start();
+    let mut cursor = vmas.lower_bound(Bound::Excluded(&next));
+    while let Some(vma) = cursor.get() {
+        if vma.range.start() > next {
+            return false;
+        }
+        next = vma.range.end();
+        if next >= va.end() {
+            return true;
+        }
+        cursor.move_next();
+    }
+    false
+}
+
+// first fit between MMAP_BASE and MMAP_END, like PageAllocator::allocate_n
+fn find_free(vmas: &RBTree<VmaAdaptor>, len: usize) -> Result<VaRange, ErrorCode> {
+    if len > MMAP_END - MMAP_BASE {
+        return Err(EPAGE);
+    }
+    let mut start = MMAP_BASE;
+    let mut cursor = vmas.lower_bound(Bound::Excluded(&VirtualAddress::from(MMAP_BASE)));
+    while let Some(vma) = cursor.get() {
+        if vma.range.start().value() >= start + len {
+            break;
+        }
+        start = start.max(vma.range.end().value());
+        cursor.move_next();
+    }
+    if start + len > MMAP_END {
+        return Err(EPAGE);
+    }
+    Ok(VaRange::new(start, start + len))
+}
+
+// take the parts of the VMAs inside `va` out of the tree, what sticks out of it stays
+fn carve(vmas: &mut RBTree<VmaAdaptor>, va: VaRange) -> Vec<(VaRange, MemoryType)> {
+    let mut carved = Vec::new();
+    let mut rest = Vec::new();
+    let mut cursor = vmas.lower_bound_mut(Bound::Excluded(&va.start()));
+    while let Some(vma) = cursor.get() {
+        if vma.range.start() >= va.end() {
+            break;
+        }
+        let vma = cursor.remove().unwrap();
+        let start = vma.range.start().max(va.start());
+        let end = vma.range.end().min(va.end());
+        if vma.range.start() < start {
+            rest.push(Vma::new(VaRange::new(vma.range.start(), start), vma.mt));
+        }
+        if end < vma.range.end() {
+            rest.push(Vma::new(VaRange::new(end, vma.range.end()), vma.mt));
+        }
+        carved.push((VaRange::new(start, end), vma.mt));
+    }
+    for vma in rest {
+        vmas.insert(vma);
+    }
+    carved
+}
+
+pub struct AddressSpace {
+    l1: Mapped, // the level 1 table in the higher half
+    asid: u8,
+    vmas: Spinlock<RBTree<VmaAdaptor>>,
+}
+
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, ErrorCode> {
+        let asid = allocate_asid()?;
+        let l1 = match MMU.get().unwrap().kzalloc(1, RWNORMAL, HIGHER_PAGE) {
+            Ok(l1) => l1,
+            Err(e) => {
+                free_asid(asid);
+                return Err(e);
+            }
+        };
+
+        let table = UnsafeTranslationTable::<Level1>::new(l1.va.start().as_mut_ptr());
+        let boot = boot_l1();
+        for idx in 0..config::RECURSIVE_L1_INDEX {
+            if boot[idx].is_valid() {
+                table.set_entry(idx, TranslationTableEntry::from(boot[idx].get()))?;
+            }
+        }
+
+        let mut recursive = Descriptor::INVALID.set_table()?;
+        recursive.set_attributes(TABLE_PAGE)?;
+        recursive.set_nG()?;
+        recursive.set_address(l1.pa.start())?;
+        table.set_entry(
+            config::RECURSIVE_L1_INDEX,
+            TranslationTableEntry::from(recursive),
+        )?;
+
+        Ok(Self {
+            l1,
+            asid,
+            vmas: Spinlock::new(RBTree::new(VmaAdaptor::new())),
+        })
+    }
+
+    // reserve `va` for pages of type `mt` without mapping anything yet
+    pub fn map_lazy(&self, va: VaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        check_user_range(va)?;
+        let mut vmas = self.vmas.lock();
+        if overlaps(&vmas, va) {
+            return Err(EPARAM);
+        }
+        vmas.insert(Vma::new(va, *mt));
+        Ok(())
+    }
+
+    // the VMA containing `va`
+    pub fn find_vma(&self, va: VirtualAddress) -> Option<(VaRange, MemoryType)> {
+        let vmas = self.vmas.lock();
+        let vma = vmas.lower_bound(Bound::Excluded(&va)).get()?;
+        (vma.range.start() <= va).then_some((vma.range, vma.mt))
+    }
+
+    // reserve `len` bytes, rounded up to pages, like map_lazy. `hint` is only tried first unless
+    // the mapping is `fixed`, then whatever was mapped there before is unmapped
+    pub fn mmap(
+        &self,
+        hint: Option<VirtualAddress>,
+        len: usize,
+        mt: &MemoryType,
+        fixed: bool,
+    ) -> Result<VaRange, ErrorCode> {
+        if len == 0 {
+            return Err(EPARAM);
+        }
+        let len =
+            len.checked_add(config::PAGE_SIZE - 1).ok_or(EOVERFLOW)? & !(config::PAGE_SIZE - 1);
+        let at = |start: VirtualAddress| -> Result<VaRange, ErrorCode> {
+            let end = start.value().checked_add(len).ok_or(EOVERFLOW)?;
+            let va = VaRange::new(start.value(), end);
+            check_user_range(va)?;
+            Ok(va)
+        };
+
+        if fixed {
+            let va = at(hint.ok_or(EPARAM)?)?;
+            self.munmap(va)?;
+            self.map_lazy(va, mt)?;
+            return Ok(va);
+        }
+
+        let mut vmas = self.vmas.lock();
+        let va = match hint.map(at) {
+            Some(Ok(va)) if !overlaps(&vmas, va) => va,
+            _ => find_free(&vmas, len)?,
+        };
+        vmas.insert(Vma::new(va, *mt));
+        Ok(va)
+    }
+
+    // forget about `va` and release the pages mapped in it
+    pub fn munmap(&self, va: VaRange) -> Result<(), ErrorCode> {
+        check_user_range(va)?;
+        let carved = carve(&mut self.vmas.lock(), va);
+
+        let mmu = MMU.get().unwrap();
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        self.with_active(|| {
+            for (range, _) in carved {
+                for page in range
+                    .start()
+                    .iter_4K_for(range.count_4K().unwrap())
+                    .unwrap()
+                {
+                    // pages of a VMA are only mapped once touched
+                    if let Ok(frame) = mmu.unmap_user(page) {
+                        frames.release(frame).unwrap();
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // change the type of `va`, which has to be covered by VMAs. pages already mapped are
+    // changed in place, except that frames still shared since fork stay read-only
+    pub fn mprotect(&self, va: VaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        check_user_range(va)?;
+        {
+            let mut vmas = self.vmas.lock();
+            if !covers(&vmas, va) {
+                return Err(EUNMAP);
+            }
+            carve(&mut vmas, va);
+            vmas.insert(Vma::new(va, *mt));
+        }
+
+        let executable = matches!(*mt, MemoryType::XNormal | MemoryType::RWXNormal);
+        let mmu = MMU.get().unwrap();
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        self.with_active(|| -> Result<(), ErrorCode> {
+            for page in va.start().iter_4K_for(va.count_4K()?).unwrap() {
+                let Some(pa) = mmu.translate(page) else {
+                    continue;
+                };
+                let page_mt = match copy_on_write(mt) {
+                    Some(cow) if frames.is_shared(pa) => cow,
+                    _ => mt,
+                };
+                if executable {
+                    mmu.cache
+                        .dc_clean_va_range_pou(page, page + VirtualAddress::_4K);
+                }
+                mmu.remap_user(page, pa, page_mt)?;
+            }
+            if executable {
+                mmu.cache.ic_invalidate_all_pou_is();
+            }
+            Ok(())
+        })
+    }
+
+    // a copy of the address space sharing all of its pages, writable ones become copy-on-write
+    // in both. the copy has its own ASID and starts with the same VMAs
+    pub fn fork(&self) -> Result<AddressSpace, ErrorCode> {
+        let child = AddressSpace::new()?;
+        {
+            let vmas = self.vmas.lock();
+            let mut child_vmas = child.vmas.lock();
+            for vma in vmas.iter() {
+                child_vmas.insert(Vma::new(vma.range, vma.mt));
+            }
+        }
+
+        let mmu = MMU.get().unwrap();
+        let mut pages = Vec::new();
+        self.with_active(|| -> Result<(), ErrorCode> {
+            mmu.for_each_user_page(&boot_l1(), |va, entry| {
+                pages.push((va, entry.get_address().unwrap(), *entry.get_attributes()));
+            });
+            for (va, pa, mt) in pages.iter_mut() {
+                if let Some(cow) = copy_on_write(mt) {
+                    mmu.remap_user(*va, *pa, cow)?;
+                    *mt = *cow;
+                }
+            }
+            Ok(())
+        })?;
+
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        child.with_active(|| -> Result<(), ErrorCode> {
+            for (va, pa, mt) in pages.iter() {
+                frames.share(*pa);
+                if let Err(e) = mmu.map_user(*va, *pa, mt) {
+                    frames.release(pa.to_4K_range())?;
+                    return Err(e);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(child)
+    }
+
+    // demand paging and copy-on-write: the first touch of a page inside a VMA maps a zeroed
+    // frame, the first write to a page shared by fork copies it. everything else is left to the
+    // caller, the address space must be active on this core
+    pub fn handle_fault(&self, fault: &PageFault) -> Result<(), ErrorCode> {
+        let (_, mt) = self.find_vma(fault.address).ok_or(EFAULT)?;
+        if !fault.access.allowed_by(&mt) {
+            return Err(EFAULT);
+        }
+        let page = fault.address.page_start();
+        match fault.kind {
+            FaultKind::Translation => {
+                match self.map_zeroed(VaRange::new(page, page + VirtualAddress::_4K), &mt) {
+                    // another thread of the process got there first
+                    Err(_) if self.translate(page).is_some() => Ok(()),
+                    r => r,
+                }
+            }
+            FaultKind::Permission if fault.access == Access::Write => self.unshare(page, &mt),
+            _ => Err(EFAULT),
+        }
+    }
+
+    // make the page at `va` writable again, copying it if the frame is still shared
+    fn unshare(&self, va: VirtualAddress, mt: &MemoryType) -> Result<(), ErrorCode> {
+        let mmu = MMU.get().unwrap();
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        let pa = self.translate(va).ok_or(EFAULT)?;
+        // the other owners are gone
+        if !frames.is_shared(pa) {
+            mmu.remap_user(va, pa, mt)?;
+            return Ok(());
+        }
+
+        let frame = mmu.kzalloc(1, RWNORMAL, HIGHER_PAGE)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                va.as_mut_ptr::<u8>(),
+                frame.va.start().as_mut_ptr::<u8>(),
+                config::PAGE_SIZE,
+            );
+        }
+        let executable = matches!(*mt, MemoryType::RWXNormal);
+        if executable {
+            mmu.cache
+                .dc_clean_va_range_pou(frame.va.start(), frame.va.end());
+        }
+        let old = mmu.remap_user(va, frame.pa.start(), mt)?;
+        mmu.unmap_keep_frame(frame.va.start())?;
+        if executable {
+            mmu.cache.ic_invalidate_all_pou_is();
+        }
+        frames.release(old)
+    }
+
+    pub fn asid(&self) -> u8 {
+        self.asid
+    }
+
+    pub fn is_active(&self) -> bool {
+        TTBR0_EL1.get_baddr() as usize == self.l1.pa.start().value()
+    }
+
+    pub fn activate(&self) {
+        write_ttbr0(self.l1.pa.start(), self.asid);
+    }
+
+    // run `f` with the address space active on this core, e.g. to map into it from another task
+    pub fn with_active<R>(&self, f: impl FnOnce() -> R) -> R {
+        let daif = exception::local_irq_mask_save();
+        let saved = TTBR0_EL1.get();
+        self.activate();
+        let r = f();
+        TTBR0_EL1.set(saved);
+        barrier::isb(barrier::SY);
+        exception::local_irq_restore(daif);
+        r
+    }
+
+    // map fresh zeroed frames at `va`, the address space must be active on this core
+    pub fn map_zeroed(&self, va: VaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        self.map_pages(va, mt, |_, _| {})
+    }
+
+    // like map_zeroed, but `fill` gets to initialize every page through a writable kernel view
+    // before it is mapped with `mt`
+    pub fn map_pages(
+        &self,
+        va: VaRange,
+        mt: &MemoryType,
+        mut fill: impl FnMut(VirtualAddress, &mut [u8]),
+    ) -> Result<(), ErrorCode> {
+        if !self.is_active() {
+            return Err(EPARAM);
+        }
+        match self.find_vma(va.start()) {
+            Some((vma, vma_mt)) if va.end() <= vma.end() => {
+                if vma_mt != *mt {
+                    return Err(EPARAM);
+                }
+            }
+            _ => self.map_lazy(va, mt)?,
+        }
+
+        let executable = matches!(*mt, MemoryType::XNormal | MemoryType::RWXNormal);
+        let mmu = MMU.get().unwrap();
+        for page in va.start().iter_4K_for(va.count_4K()?).unwrap() {
+            let frame = mmu.kzalloc(1, RWNORMAL, HIGHER_PAGE)?;
+            fill(page, unsafe {
+                core::slice::from_raw_parts_mut(frame.va.start().as_mut_ptr(), config::PAGE_SIZE)
+            });
+            if executable {
+                mmu.cache
+                    .dc_clean_va_range_pou(frame.va.start(), frame.va.end());
+            }
+            mmu.map_user(page, frame.pa.start(), mt)?;
+            // the frame now belongs to the address space, only drop the kernel's view of it
+            mmu.unmap_keep_frame(frame.va.start())?;
+        }
+        if executable {
+            mmu.cache.ic_invalidate_all_pou_is();
+        }
+        Ok(())
+    }
+
+    pub fn translate(&self, va: VirtualAddress) -> Option<PhysicalAddress> {
+        if !self.is_active() {
+            return None;
+        }
+        MMU.get().unwrap().translate(va)
+    }
+}
+
+impl Drop for AddressSpace {
+    // tear down the lower half through the recursive entry, which needs the address space active
+    // for a moment. nobody else may be running on it
+    fn drop(&mut self) {
+        self.with_active(|| MMU.get().unwrap().release_lower(&boot_l1()));
+
+        MMU.get().unwrap().unmap(self.l1.va.start()).unwrap();
+        // it may have run on any core
+        tlb::invalidate_asid_is(self.asid);
+        free_asid(self.asid);
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::{
+        kthread,
+        scheduler::{Task, SCHEDULER},
+        syscall::user,
+    };
+    use alloc::sync::Arc;
+    use test_macros::kernel_test;
+
+    const USER_VA: usize = 0x40_0000_0000; // L1 index 256, unused by the boot table
+    const LAZY_VA: usize = USER_VA + 0x10_0000;
+    const USER_STACK_TOP: usize = USER_VA + 0x20_0000;
+
+    #[kernel_test]
+    fn test_address_space() {
+        let a = AddressSpace::new().unwrap();
+        let b = AddressSpace::new().unwrap();
+        assert_ne!(a.asid(), b.asid());
+
+        let va = VaRange::new(USER_VA, USER_VA + config::PAGE_SIZE);
+        let daif = exception::local_irq_mask_save();
+
+        a.activate();
+        a.map_zeroed(va, RWNORMAL).unwrap();
+        unsafe { core::ptr::write_volatile(USER_VA as *mut u64, 0xa) };
+
+        b.activate();
+        assert!(b.translate(va.start()).is_none());
+        b.map_zeroed(va, RWNORMAL).unwrap();
+        assert_eq!(
+            unsafe { core::ptr::read_volatile(USER_VA as *const u64) },
+            0
+        );
+        unsafe { core::ptr::write_volatile(USER_VA as *mut u64, 0xb) };
+
+        a.activate();
+        assert_eq!(
+            unsafe { core::ptr::read_volatile(USER_VA as *const u64) },
+            0xa
+        );
+
+        activate_kernel();
+        exception::local_irq_restore(daif);
+
+        let asid = a.asid();
+        drop(a);
+        drop(b);
+        // released ASIDs are handed out again
+        let c = AddressSpace::new().unwrap();
+        assert_eq!(c.asid(), asid);
+    }
+
+    fn touch_lazy(_: usize) -> i32 {
+        let p = LAZY_VA as *mut u64;
+        // zeroed on the first touch
+        let zero = unsafe { core::ptr::read_volatile(p) };
+        unsafe { core::ptr::write_volatile(p.add(1), 7) };
+        (zero + unsafe { core::ptr::read_volatile(p.add(1)) }) as i32
+    }
+
+    fn touch_outside() -> ! {
+        let value =
+            unsafe { core::ptr::read_volatile((LAZY_VA + 2 * config::PAGE_SIZE) as *const u64) };
+        user::exit(value as i32)
+    }
+
+    fn run_on(mm: &Arc<AddressSpace>, entry: fn(usize) -> i32) -> i32 {
+        run_on_with(mm, entry, 0)
+    }
+
+    fn run_on_with(mm: &Arc<AddressSpace>, entry: fn(usize) -> i32, arg: usize) -> i32 {
+        let mut t = Task::new(entry, arg).unwrap();
+        t.set_address_space(mm.clone());
+        let id = SCHEDULER.get().unwrap().spawn(t);
+        kthread::join(id).unwrap()
+    }
+
+    // the stack is mapped by the task, its address space has to be active for that
+    fn enter_el0(entry: usize) -> i32 {
+        let mm = SCHEDULER.get().unwrap().current_address_space().unwrap();
+        let stack = VaRange::new(USER_STACK_TOP - config::PAGE_SIZE, USER_STACK_TOP);
+        mm.map_zeroed(stack, RWNORMAL).unwrap();
+        exception::enter_el0(entry, USER_STACK_TOP)
+    }
+
+    // faults of EL0 kill the task, the kernel's never do
+    fn run_at_el0(mm: &Arc<AddressSpace>, entry: fn() -> !) -> i32 {
+        run_on_with(mm, enter_el0, entry as usize)
+    }
+
+    #[kernel_test]
+    fn test_demand_paging() {
+        let mm = Arc::new(AddressSpace::new().unwrap());
+        let lazy = VaRange::new(LAZY_VA, LAZY_VA + 2 * config::PAGE_SIZE);
+        mm.map_lazy(lazy, RWNORMAL).unwrap();
+        let overlapping =
+            VaRange::new(LAZY_VA + config::PAGE_SIZE, LAZY_VA + 3 * config::PAGE_SIZE);
+        assert!(mm.map_lazy(overlapping, RWNORMAL).is_err());
+
+        assert!(mm.with_active(|| mm.translate(lazy.start())).is_none());
+        assert_eq!(run_on(&mm, touch_lazy), 7);
+        assert!(mm.with_active(|| mm.translate(lazy.start())).is_some());
+
+        // outside of any VMA the task is killed, not the kernel
+        assert_eq!(run_at_el0(&mm, touch_outside), -(EFAULT.errno() as i32));
+    }
+
+    fn touch_unaligned(_: usize) -> i32 {
+        let p = (LAZY_VA + 0x808) as *mut u64;
+        unsafe { core::ptr::write_volatile(p, 3) };
+        unsafe { core::ptr::read_volatile(p) as i32 }
+    }
+
+    #[kernel_test]
+    fn test_unaligned_fault() {
+        let mm = Arc::new(AddressSpace::new().unwrap());
+        let lazy = VaRange::new(LAZY_VA, LAZY_VA + 2 * config::PAGE_SIZE);
+        mm.map_lazy(lazy, RWNORMAL).unwrap();
+        assert_eq!(run_on(&mm, touch_unaligned), 3);
+        // the page that was touched, not the one after it
+        let second = lazy.start() + VirtualAddress::_4K;
+        assert!(mm.with_active(|| mm.translate(lazy.start())).is_some());
+        assert!(mm.with_active(|| mm.translate(second)).is_none());
+    }
+
+    fn write_mmap_base(_: usize) -> i32 {
+        let p = MMAP_BASE as *mut u64;
+        unsafe { core::ptr::write_volatile(p, 5) };
+        unsafe { core::ptr::read_volatile(p) as i32 }
+    }
+
+    fn write_mmap_base_at_el0() -> ! {
+        user::exit(write_mmap_base(0))
+    }
+
+    #[kernel_test]
+    fn test_mmap() {
+        let mm = Arc::new(AddressSpace::new().unwrap());
+        let a = mm
+            .mmap(None, 3 * config::PAGE_SIZE, RWNORMAL, false)
+            .unwrap();
+        assert_eq!(a.start().value(), MMAP_BASE);
+        // a hint that is taken already is ignored
+        let b = mm.mmap(Some(a.start()), 1, RWNORMAL, false).unwrap();
+        assert_eq!(b.start().value(), a.end().value());
+
+        // a hole in the middle of `a` is the first fit from now on
+        let hole = VaRange::new(
+            MMAP_BASE + config::PAGE_SIZE,
+            MMAP_BASE + 2 * config::PAGE_SIZE,
+        );
+        mm.munmap(hole).unwrap();
+        assert!(mm.find_vma(hole.start()).is_none());
+        assert!(mm.find_vma(a.start()).is_some());
+        assert!(mm.find_vma(hole.end()).is_some());
+        let c = mm.mmap(None, config::PAGE_SIZE, RWNORMAL, false).unwrap();
+        assert_eq!(c.start().value(), hole.start().value());
+
+        assert_eq!(run_on(&mm, write_mmap_base), 5);
+        let page = VaRange::new(MMAP_BASE, MMAP_BASE + config::PAGE_SIZE);
+        mm.mprotect(page, RONORMAL).unwrap();
+        assert_eq!(mm.find_vma(page.start()).unwrap().1, *RONORMAL);
+        // writing is fatal now
+        assert_eq!(
+            run_at_el0(&mm, write_mmap_base_at_el0),
+            -(EFAULT.errno() as i32)
+        );
+        let unmapped = VaRange::new(MMAP_END - config::PAGE_SIZE, MMAP_END);
+        assert!(mm.mprotect(unmapped, RONORMAL).is_err());
+
+        mm.munmap(VaRange::new(MMAP_BASE, b.end().value())).unwrap();
+        assert!(mm.find_vma(page.start()).is_none());
+        assert!(mm.with_active(|| mm.translate(page.start())).is_none());
+    }
+
+    fn increment(_: usize) -> i32 {
+        let p = USER_VA as *mut u64;
+        unsafe { core::ptr::write_volatile(p, core::ptr::read_volatile(p) + 1) };
+        unsafe { core::ptr::read_volatile(p) as i32 }
+    }
+
+    #[kernel_test]
+    fn test_copy_on_write() {
+        let parent = Arc::new(AddressSpace::new().unwrap());
+        let va = VaRange::new(USER_VA, USER_VA + config::PAGE_SIZE);
+        let pa = parent.with_active(|| {
+            parent
+                .map_pages(va, RWNORMAL, |_, page| page[0] = 1)
+                .unwrap();
+            parent.translate(va.start()).unwrap()
+        });
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+
+        let child = Arc::new(parent.fork().unwrap());
+        assert!(frames.is_shared(pa));
+        assert!(child.with_active(|| child.translate(va.start())) == Some(pa));
+
+        // the first write gets the child a copy of its own
+        assert_eq!(run_on(&child, increment), 2);
+        assert!(child.with_active(|| child.translate(va.start())) != Some(pa));
+        assert!(!frames.is_shared(pa));
+
+        // the parent is the last owner and keeps the frame
+        assert_eq!(run_on(&parent, increment), 2);
+        assert!(parent.with_active(|| parent.translate(va.start())) == Some(pa));
+    }
+}
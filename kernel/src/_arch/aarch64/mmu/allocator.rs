@@ -1,15 +1,15 @@
 extern crate alloc;
 use core::alloc::Allocator;
 
-use super::{address::*, heap::*};
+use super::{address::*, buddy::*, config, heap::*};
 use crate::{
     errno::*,
+    fdt::MemoryMap,
     memory::{BlockSize, MemoryRegion, BLOCK_2M, BLOCK_4K},
     println, BootInfo,
 };
 use aarch64_cpu::registers::*;
-use alloc::boxed::Box;
-use core::fmt;
+use alloc::{boxed::Box, collections::BTreeMap};
 use intrusive_collections::{
     intrusive_adapter, Bound, KeyAdapter, LinkedList, LinkedListLink, RBTree, RBTreeLink,
 };
@@ -61,20 +61,6 @@ where
     }
 }
 
-struct UnsafeFrameAllocator {
-    pma: RBTree<AddressRangeAdaptor<PaRange>>,
-    placeholder: AddressRangeNode<PaRange>,
-}
-
-impl fmt::Display for UnsafeFrameAllocator {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for v in self.pma.iter() {
-            writeln!(f, "{}", v.range())?;
-        }
-        Ok(())
-    }
-}
-
 struct TrivialAlloc;
 
 unsafe impl Allocator for TrivialAlloc {
@@ -88,143 +74,90 @@ unsafe impl Allocator for TrivialAlloc {
     unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {}
 }
 
-impl UnsafeFrameAllocator {
-    pub fn new(pa_range: PaRange) -> Self {
-        let mut frame_allocator = Self {
-            pma: RBTree::new(AddressRangeAdaptor::new()),
-            placeholder: AddressRangeNode::new(PaRange::new(0, 0)),
-        };
-        frame_allocator.init(pa_range);
-        frame_allocator
-    }
-
-    fn init(&mut self, pa_range: PaRange) {
-        self.pma.insert(Box::new(AddressRangeNode::new(pa_range)));
-    }
-    pub fn allocate_n(&mut self, npage: usize) -> Result<PaRange, ErrorCode> {
-        let nbytes = npage * LEN_4K;
-
-        for n in self.pma.iter() {
-            if n.range().size_in_bytes() >= nbytes {
-                let mut range = n.range_copy();
-                let end = range.end();
-                let bound = Bound::Included(&end);
-                let mut cursor = self.pma.upper_bound_mut(bound);
-                if *(cursor.get().unwrap().range()) != range {
-                    return Err(EFRAME);
-                }
-                let pa = range.pop_bytes_front(nbytes).unwrap();
-                // cursor.replace_with(Box::new(AddressRangeNode::new(range))).unwrap();
-                let b = cursor.into_mut().ok_or(EFRAME)?;
-                b.replace_with(range);
-
-                return Ok(pa.to_bytes_range(nbytes));
-            }
-        }
-
-        Err(EFRAME)
-    }
-    pub fn allocate_4K(&mut self) -> Result<PaRange, ErrorCode> {
-        for n in self.pma.iter() {
-            if n.range().len().value() >= LEN_4K {
-                let mut range = n.range_copy();
-                let end = range.end();
-                let bound = Bound::Included(&end);
-                let mut cursor = self.pma.upper_bound_mut(bound);
-                if *(cursor.get().unwrap().range()) != range {
-                    return Err(EFRAME);
-                }
-                let pa = range.pop_4K_front().unwrap();
-                cursor
-                    .replace_with(Box::new(AddressRangeNode::new(range)))
-                    .unwrap();
-                return Ok(pa.to_4K_range());
-            }
-        }
-
-        Err(EFRAME)
-    }
-    pub fn allocate_2M(&mut self) -> Result<PaRange, ErrorCode> {
-        for n in self.pma.iter() {
-            if n.range().len().value() >= LEN_2M {
-                let mut range = n.range_copy();
-                let end = range.end();
-                let bound = Bound::Included(&end);
-                let mut cursor = self.pma.upper_bound_mut(bound);
-                if *(cursor.get().unwrap().range()) != range {
-                    return Err(EFRAME);
-                }
-                let pa = range.pop_2M_front().unwrap();
-                cursor
-                    .replace_with(Box::new(AddressRangeNode::new(range)))
-                    .unwrap();
-                return Ok(pa.to_2M_range());
-            }
-        }
-
-        Err(EFRAME)
-    }
-
-    pub fn free_range(&mut self, pa_range: PaRange) {
-        let mut to_free = pa_range;
-        let end = to_free.end();
-        let bound = Bound::Excluded(&end);
-
-        let mut upper_cursor = self.pma.upper_bound_mut(bound);
-        if !upper_cursor.is_null() {
-            let upper = upper_cursor.get().unwrap().range();
-            if to_free.merge(&upper).is_ok() {
-                upper_cursor.remove().unwrap();
-            }
-        }
-
-        let end = to_free.end();
-        let bound = Bound::Excluded(&end);
-
-        let mut lower_cursor = self.pma.lower_bound_mut(bound);
-        if !lower_cursor.is_null() {
-            let lower = lower_cursor.get().unwrap().range();
-            if to_free.merge(&lower).is_ok() {
-                lower_cursor.remove().unwrap();
-            }
-        }
-
-        self.pma.insert(Box::new(AddressRangeNode::new(to_free)));
-    }
-}
+// the buddy bitmaps cover all of physical memory, see buddy.rs. they are in .bss, which the boot
+// tables only map up to 2M into the image, kernel.ld checks that they still fit
+static mut FRAME_BITMAP: [u64; bitmap_words(config::NUMBER_OF_FRAMES)] =
+    [0; bitmap_words(config::NUMBER_OF_FRAMES)];
 
 pub struct FrameAllocator {
-    allocator: SpinMutex<UnsafeFrameAllocator>,
+    allocator: SpinMutex<BuddyAllocator>,
+    // extra owners of 4K frames shared between address spaces, frames not in here have one owner.
+    // a lock of its own, growing the map may have the heap ask the allocator for frames
+    shared: SpinMutex<BTreeMap<PhysicalAddress, usize>>,
 }
 
 impl FrameAllocator {
-    pub fn new(boot_info: &BootInfo) -> Self {
+    // without any frames until they are added
+    pub fn new() -> Self {
         Self {
-            allocator: SpinMutex::new(UnsafeFrameAllocator::new(boot_info.free_frame)),
+            allocator: SpinMutex::new(Self::buddy()),
+            shared: SpinMutex::new(BTreeMap::new()),
         }
     }
 
-    fn allocate_4K(&self) -> Result<PaRange, ErrorCode> {
-        self.allocator.lock().allocate_4K()
+    // only called once, FRAME_ALLOCATOR owns the bitmaps
+    fn buddy() -> BuddyAllocator {
+        let bitmap = unsafe { &mut *core::ptr::addr_of_mut!(FRAME_BITMAP) };
+        BuddyAllocator::new(config::NUMBER_OF_FRAMES, bitmap)
     }
-    fn allocate_2M(&self) -> Result<PaRange, ErrorCode> {
-        self.allocator.lock().allocate_2M()
+
+    // hand frames nobody uses to the allocator
+    fn add_range(&self, range: PaRange) -> Result<(), ErrorCode> {
+        self.allocator.lock().add_range(range)
     }
 
     pub fn allocate(&self, sz: &BlockSize) -> Result<PaRange, ErrorCode> {
         match *sz {
-            BlockSize::_4K => self.allocate_4K(),
-            BlockSize::_2M => self.allocate_2M(),
-            _ => Err(ESUPPORTED),
+            BlockSize::_4K => self.allocate_order(0),
+            BlockSize::_2M => self.allocate_order(config::SHIFT_2M - config::SHIFT_4K),
+            BlockSize::_1G => self.allocate_order(config::SHIFT_1G - config::SHIFT_4K),
         }
     }
+    // 2^order frames aligned to their size
+    pub fn allocate_order(&self, order: usize) -> Result<PaRange, ErrorCode> {
+        self.allocator.lock().allocate(order)
+    }
     pub fn allocate_n(&self, npage: usize) -> Result<PaRange, ErrorCode> {
         self.allocator.lock().allocate_n(npage)
     }
 
-    pub fn free_range(&self, pa_range: PaRange) {
+    // EPARAM if any of the frames is free already
+    pub fn free_range(&self, pa_range: PaRange) -> Result<(), ErrorCode> {
         self.allocator.lock().free_range(pa_range)
     }
+
+    pub fn stats(&self) -> FrameStats {
+        self.allocator.lock().stats()
+    }
+
+    // one more owner of the 4K frame at `pa`, e.g. a page shared copy-on-write
+    pub fn share(&self, pa: PhysicalAddress) {
+        *self.shared.lock().entry(pa).or_insert(0) += 1;
+    }
+
+    pub fn is_shared(&self, pa: PhysicalAddress) -> bool {
+        self.shared.lock().contains_key(&pa)
+    }
+
+    // drop one owner of a 4K frame, the last one frees it
+    pub fn release(&self, frame: PaRange) -> Result<(), ErrorCode> {
+        let mut shared = self.shared.lock();
+        match shared.get_mut(&frame.start()) {
+            Some(1) => {
+                shared.remove(&frame.start());
+                // an empty map still holds on to its root node
+                if shared.is_empty() {
+                    *shared = BTreeMap::new();
+                }
+            }
+            Some(n) => *n -= 1,
+            None => {
+                drop(shared);
+                return self.free_range(frame);
+            }
+        }
+        Ok(())
+    }
 }
 
 pub static FRAME_ALLOCATOR: Once<FrameAllocator> = Once::new();
@@ -405,8 +338,26 @@ impl PageAllocator {
 
 pub static PAGE_ALLOCATOR: Once<PageAllocator> = Once::new();
 
-pub fn init(boot_info: &BootInfo) -> Result<(), ErrorCode> {
-    FRAME_ALLOCATOR.call_once(|| FrameAllocator::new(boot_info));
+// the usable parts of `memory` above the boot info's free frames, everything below them belongs to
+// the firmware, the kernel image and the boot stacks. without a memory map the free frames are
+// taken as they are
+pub fn init(boot_info: &BootInfo, memory: Option<&MemoryMap>) -> Result<(), ErrorCode> {
+    FRAME_ALLOCATOR.call_once(FrameAllocator::new);
+    let frames = FRAME_ALLOCATOR.get().unwrap();
+    match memory {
+        Some(map) => {
+            let within = PaRange::new(
+                boot_info.free_frame.start().value(),
+                config::PHYSICAL_MEMORY_LIMIT,
+            );
+            let mut added = Ok(());
+            map.for_each_usable(within, |range| {
+                added = added.and_then(|_| frames.add_range(range))
+            });
+            added?;
+        }
+        None => frames.add_range(boot_info.free_frame)?,
+    }
     PAGE_ALLOCATOR.call_once(|| PageAllocator::new(boot_info));
     Ok(())
 }
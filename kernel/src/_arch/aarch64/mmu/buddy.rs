@@ -0,0 +1,326 @@
+//! Binary buddy allocator for physical frames
+//!
+//! A block of order k is 2^k frames aligned to its own size. Every order has a bitmap with one bit
+//! per block, set while the block is free as a whole and not part of a larger free block. A freed
+//! block is merged with its buddy as long as the buddy is free too.
+//!
+//! The allocator never touches the frames it manages, so the bitmaps live in the kernel image
+//! instead of intrusive lists inside the free frames.
+
+use super::{address::*, config};
+use crate::errno::*;
+use core::fmt;
+
+pub const MAX_ORDER: usize = 18; // 1G
+pub const NUM_ORDERS: usize = MAX_ORDER + 1;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+// words for the bitmaps of every order over `nr_frames` frames
+pub const fn bitmap_words(nr_frames: usize) -> usize {
+    let mut words = 0;
+    let mut order = 0;
+    while order < NUM_ORDERS {
+        words += ((nr_frames >> order) + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        order += 1;
+    }
+    words
+}
+
+// the smallest order holding `npage` frames
+pub fn order_for(npage: usize) -> usize {
+    npage.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct FrameStats {
+    pub total: usize, // frames handed to the allocator
+    pub free: usize,
+    pub free_blocks: [usize; NUM_ORDERS],
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "frames: {} free of {}", self.free, self.total)?;
+        for (order, n) in self.free_blocks.iter().enumerate() {
+            if *n != 0 {
+                writeln!(f, "    order {:>2}: {}", order, n)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct BuddyAllocator {
+    bitmap: &'static mut [u64],
+    nr_frames: usize,            // frames 0..nr_frames can be managed
+    offset: [usize; NUM_ORDERS], // first word of every order in `bitmap`
+    words: [usize; NUM_ORDERS],
+    hint: [usize; NUM_ORDERS], // every word of the order below this one is 0
+    stats: FrameStats,
+}
+
+impl BuddyAllocator {
+    // everything is allocated until it is freed, `bitmap` must be zeroed
+    pub fn new(nr_frames: usize, bitmap: &'static mut [u64]) -> Self {
+        let mut offset = [0; NUM_ORDERS];
+        let mut words = [0; NUM_ORDERS];
+        let mut next = 0;
+        for order in 0..NUM_ORDERS {
+            offset[order] = next;
+            words[order] = ((nr_frames >> order) + BITS_PER_WORD - 1) / BITS_PER_WORD;
+            next += words[order];
+        }
+        assert!(next <= bitmap.len());
+        Self {
+            bitmap,
+            nr_frames,
+            offset,
+            words,
+            hint: [0; NUM_ORDERS],
+            stats: FrameStats::default(),
+        }
+    }
+
+    // hand the frames in `range` to the allocator
+    pub fn add_range(&mut self, range: PaRange) -> Result<(), ErrorCode> {
+        self.free_range(range)?;
+        self.stats.total += range.count_4K().unwrap_or(0);
+        Ok(())
+    }
+
+    fn test(&self, order: usize, idx: usize) -> bool {
+        if idx >= self.nr_frames >> order {
+            return false;
+        }
+        let word = self.bitmap[self.offset[order] + idx / BITS_PER_WORD];
+        word & (1 << (idx % BITS_PER_WORD)) != 0
+    }
+
+    // whether any of the `count` blocks of `order` from `idx` is free. `count` is a power of two
+    // and `idx` a multiple of it, so from a word up whole words are tested
+    fn any_free(&self, order: usize, idx: usize, count: usize) -> bool {
+        let end = (idx + count).min(self.nr_frames >> order);
+        if count < BITS_PER_WORD {
+            return (idx..end).any(|i| self.test(order, i));
+        }
+        let base = self.offset[order];
+        (idx / BITS_PER_WORD..end.div_ceil(BITS_PER_WORD)).any(|w| self.bitmap[base + w] != 0)
+    }
+
+    // a block is allocated while neither it, a block containing it nor a part of it is free
+    fn is_allocated(&self, frame: usize, order: usize) -> bool {
+        let idx = frame >> order;
+        !(order..NUM_ORDERS).any(|k| self.test(k, idx >> (k - order)))
+            && !(0..order).any(|k| self.any_free(k, idx << (order - k), 1 << (order - k)))
+    }
+
+    fn set(&mut self, order: usize, idx: usize) {
+        self.bitmap[self.offset[order] + idx / BITS_PER_WORD] |= 1 << (idx % BITS_PER_WORD);
+        self.hint[order] = self.hint[order].min(idx / BITS_PER_WORD);
+        self.stats.free_blocks[order] += 1;
+        self.stats.free += 1 << order;
+    }
+
+    fn clear(&mut self, order: usize, idx: usize) {
+        self.bitmap[self.offset[order] + idx / BITS_PER_WORD] &= !(1 << (idx % BITS_PER_WORD));
+        self.stats.free_blocks[order] -= 1;
+        self.stats.free -= 1 << order;
+    }
+
+    fn find(&mut self, order: usize) -> Option<usize> {
+        let base = self.offset[order];
+        for w in self.hint[order]..self.words[order] {
+            let word = self.bitmap[base + w];
+            if word != 0 {
+                self.hint[order] = w;
+                return Some(w * BITS_PER_WORD + word.trailing_zeros() as usize);
+            }
+        }
+        self.hint[order] = self.words[order];
+        None
+    }
+
+    fn block(order: usize, idx: usize) -> PaRange {
+        let start = (idx << order) << config::SHIFT_4K;
+        PaRange::new(start, start + ((1 << order) << config::SHIFT_4K))
+    }
+
+    // 2^order contiguous frames aligned to their size
+    pub fn allocate(&mut self, order: usize) -> Result<PaRange, ErrorCode> {
+        if order > MAX_ORDER {
+            return Err(EPARAM);
+        }
+        let mut found = None;
+        for k in order..NUM_ORDERS {
+            if let Some(idx) = self.find(k) {
+                found = Some((k, idx));
+                break;
+            }
+        }
+        let (mut k, mut idx) = found.ok_or(EFRAME)?;
+        self.clear(k, idx);
+        // keep the lower half, the upper one is free
+        while k > order {
+            k -= 1;
+            idx <<= 1;
+            self.set(k, idx + 1);
+        }
+        Ok(Self::block(order, idx))
+    }
+
+    // `npage` contiguous frames, the rest of the block is given back
+    pub fn allocate_n(&mut self, npage: usize) -> Result<PaRange, ErrorCode> {
+        if npage == 0 {
+            return Err(EPARAM);
+        }
+        let block = self.allocate(order_for(npage))?;
+        let end = block.start() + PhysicalAddress::from(npage << config::SHIFT_4K);
+        if end < block.end() {
+            self.free_range(PaRange::new(end, block.end()))?;
+        }
+        Ok(PaRange::new(block.start(), end))
+    }
+
+    // a block of `order` at frame `frame`, merged with its buddies. EPARAM unless all of it is
+    // allocated, e.g. on a double free
+    pub fn free(&mut self, frame: usize, order: usize) -> Result<(), ErrorCode> {
+        if order > MAX_ORDER
+            || frame % (1 << order) != 0
+            || frame + (1 << order) > self.nr_frames
+            || !self.is_allocated(frame, order)
+        {
+            return Err(EPARAM);
+        }
+        self.merge(frame, order);
+        Ok(())
+    }
+
+    fn merge(&mut self, frame: usize, order: usize) {
+        let mut idx = frame >> order;
+        let mut k = order;
+        while k < MAX_ORDER && self.test(k, idx ^ 1) {
+            self.clear(k, idx ^ 1);
+            idx >>= 1;
+            k += 1;
+        }
+        self.set(k, idx);
+    }
+
+    // any 4K aligned range, split into the largest blocks that fit. nothing is freed unless all
+    // of it is allocated
+    pub fn free_range(&mut self, range: PaRange) -> Result<(), ErrorCode> {
+        let start = range.start().value() >> config::SHIFT_4K;
+        let end = (range.end().value() >> config::SHIFT_4K).min(self.nr_frames);
+        if Self::blocks(start, end).any(|(frame, order)| !self.is_allocated(frame, order)) {
+            return Err(EPARAM);
+        }
+        for (frame, order) in Self::blocks(start, end) {
+            self.merge(frame, order);
+        }
+        Ok(())
+    }
+
+    // frames start..end as (frame, order) of the largest aligned blocks
+    fn blocks(start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> {
+        let mut frame = start;
+        core::iter::from_fn(move || {
+            if frame >= end {
+                return None;
+            }
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            let block = (frame, order);
+            frame += 1 << order;
+            Some(block)
+        })
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    extern crate alloc;
+    use super::*;
+    use alloc::{boxed::Box, vec};
+    use test_macros::kernel_test;
+
+    const NR_FRAMES: usize = 1 << 12;
+
+    fn buddy() -> BuddyAllocator {
+        let bitmap = vec![0u64; bitmap_words(NR_FRAMES)].leak();
+        BuddyAllocator::new(NR_FRAMES, bitmap)
+    }
+
+    // give the bitmap back so the test does not leak it
+    fn release(b: BuddyAllocator) {
+        drop(unsafe { Box::from_raw(b.bitmap as *mut [u64]) });
+    }
+
+    fn frames(n: usize) -> usize {
+        n << config::SHIFT_4K
+    }
+
+    #[kernel_test]
+    fn test_buddy() {
+        let mut b = buddy();
+        // frame 1 is not aligned to anything larger than itself
+        b.add_range(PaRange::new(frames(1), frames(1 + 512)))
+            .unwrap();
+        assert_eq!(b.stats().free, 512);
+        assert_eq!(b.stats().free_blocks[0], 2);
+
+        let a = b.allocate(0).unwrap();
+        assert_eq!(a.start().value(), frames(1));
+        let c = b.allocate(8).unwrap();
+        assert!(c.start().is_aligned_to(frames(256)));
+        assert!(b.allocate(9).is_err());
+
+        // three frames out of an order 2 block, the fourth is free again
+        let n = b.allocate_n(3).unwrap();
+        assert_eq!(n.count_4K().unwrap(), 3);
+        assert_eq!(b.stats().free, 512 - 1 - 256 - 3);
+
+        b.free_range(n).unwrap();
+        b.free_range(c).unwrap();
+        b.free_range(a).unwrap();
+        assert_eq!(b.stats().free, 512);
+        assert_eq!(b.stats().free_blocks[0], 2);
+        // everything merged back into the blocks it started as
+        assert_eq!(b.stats().free_blocks.iter().sum::<usize>(), 10);
+        release(b);
+    }
+
+    #[kernel_test]
+    fn test_buddy_double_free() {
+        let mut b = buddy();
+        b.add_range(PaRange::new(0, frames(64))).unwrap();
+
+        let a = b.allocate(2).unwrap();
+        b.free_range(a).unwrap();
+        assert!(b.free_range(a).is_err());
+        assert_eq!(b.stats().free, 64);
+
+        // one free frame in the range and none of it is freed
+        let c = b.allocate(0).unwrap();
+        let two = PaRange::new(c.start().value(), c.start().value() + frames(2));
+        assert!(b.free_range(two).is_err());
+        assert_eq!(b.stats().free, 63);
+        b.free_range(c).unwrap();
+
+        // a block with a part of it freed already
+        let d = b.allocate(1).unwrap();
+        let frame = d.start().value() >> config::SHIFT_4K;
+        b.free(frame, 0).unwrap();
+        assert!(b.free(frame, 1).is_err());
+        assert!(b.free(frame + 1, 0).is_ok());
+        assert_eq!(b.stats().free, 64);
+        release(b);
+    }
+}
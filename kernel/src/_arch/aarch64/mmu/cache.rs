@@ -257,7 +257,7 @@ impl A64CacheSet {
     #[inline(always)]
     pub fn ic_inalidate_va_pou(&self, va: VirtualAddress) {
         unsafe {
-            asm!("IC IVAU {}", "DSB ISH", "ISB", in(reg) va.value() as u64);
+            asm!("IC IVAU, {}", "DSB ISH", "ISB", in(reg) va.value() as u64);
         }
     }
     #[inline(always)]
@@ -272,7 +272,7 @@ impl A64CacheSet {
     #[inline(always)]
     pub fn dc_invalidate_va_poc(&self, va: VirtualAddress) {
         unsafe {
-            asm!("DC IVAC {}", "DSB ISH", in(reg) va.value() as u64);
+            asm!("DC IVAC, {}", "DSB ISH", in(reg) va.value() as u64);
         }
     }
     #[inline(always)]
@@ -284,7 +284,7 @@ impl A64CacheSet {
     #[inline(always)]
     pub fn dc_clean_va_poc(&self, va: VirtualAddress) {
         unsafe {
-            asm!("DC CVAC {}", "DSB ISH", in(reg) va.value() as u64);
+            asm!("DC CVAC, {}", "DSB ISH", in(reg) va.value() as u64);
         }
     }
     #[inline(always)]
@@ -296,7 +296,7 @@ impl A64CacheSet {
     #[inline(always)]
     pub fn dc_clean_va_pou(&self, va: VirtualAddress) {
         unsafe {
-            asm!("DC CVAU {}", "DSB ISH", in(reg) va.value() as u64);
+            asm!("DC CVAU, {}", "DSB ISH", in(reg) va.value() as u64);
         }
     }
     #[inline(always)]
@@ -309,7 +309,7 @@ impl A64CacheSet {
     #[inline(always)]
     pub fn dc_clean_invalidate_va_poc(&self, va: VirtualAddress) {
         unsafe {
-            asm!("DC CIVAC {}", "DSB ISH", in(reg) va.value() as u64);
+            asm!("DC CIVAC, {}", "DSB ISH", in(reg) va.value() as u64);
         }
     }
     #[inline(always)]
@@ -348,31 +348,6 @@ impl A64CacheSet {
 // Because a TLB never holds any entry that generates a fault, therefore, a change from an entry
 // that causes a fault to one that does not fault, does not require any TLB invalidation.
 
-pub struct A64TLB;
-
-impl A64TLB {
-    #[inline(always)]
-    fn concat_asid_va(va: VirtualAddress, asid: u8) -> u64 {
-        ((asid as u64) << 48) | ((va.value() as u64) >> 12)
-    }
-    pub fn invalidate_all() {
-        unsafe {
-            asm!("DSB ISHST", "TLBI VMALLE1", "DSB ISH", "ISB");
-        }
-    }
-    pub fn invalidate_asid(asid: u8) {
-        unsafe {
-            asm!("DSB ISHST", "TLBI ASIDE1, {}", "DSB ISH", "ISB", in(reg) ((asid as u64) << 48));
-        }
-    }
-    pub fn invalidate_va_asid(_va: VirtualAddress, _asid: u8) {
-        core::todo!();
-    }
-    pub fn invalidate_va(_va: VirtualAddress) {
-        core::todo!();
-    }
-}
-
 #[cfg(test)]
 #[allow(unused_imports, unused_variables, dead_code)]
 mod tests {
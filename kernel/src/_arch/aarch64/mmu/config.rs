@@ -62,11 +62,9 @@ pub const STACK_MMIO_L1_INDEX: usize = RECURSIVE_L1_INDEX - 1;
 
 pub const PHYSICAL_PERIPHERAL_START: usize = mmio::PHYSICAL_PERIPHERAL_START;
 
-pub const PHYSICAL_MEMORY_END_INCLUSIVE: usize = mmio::PHYSICAL_MEMORY_END_INCLUSIVE;
+pub const PHYSICAL_MEMORY_LIMIT: usize = mmio::PHYSICAL_MEMORY_LIMIT;
 
-pub const PHYSICAL_MEMORY_END_EXCLUSIVE: usize = mmio::PHYSICAL_MEMORY_END_EXCLUSIVE;
-
-pub const NUMBER_OF_FRAMES: usize = (PHYSICAL_MEMORY_END_INCLUSIVE >> SHIFT_4K) + 1;
+pub const NUMBER_OF_FRAMES: usize = PHYSICAL_MEMORY_LIMIT >> SHIFT_4K;
 pub const NUMBER_OF_PAGES: usize = (0xFFFF_FFFF_FFFF >> SHIFT_4K) + 1;
 
 const fn get_level2_index(va: usize) -> usize {
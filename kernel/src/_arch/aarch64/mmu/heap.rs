@@ -1,17 +1,21 @@
 use super::*;
 use crate::{
+    cpu::smp::{core_id, NUM_OF_CORES},
     errno::*,
+    exception,
     generics::{DoublyLink, DoublyLinkable, DoublyLinkedList, Link},
     print, println, static_vector, type_enum, type_enum_with_error,
     utils::bitfields::Bitfields,
 };
 use core::{
     alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
     fmt,
     iter::Iterator,
     marker::PhantomData,
     num,
     ops::{Deref, Drop},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use spin::once::Once;
@@ -21,9 +25,20 @@ const BACKEND_FREE_4K: usize = 16;
 const BACKEND_FREE_2M: usize = 8;
 const OBJECT_PAGE_PER_SIZE_CLASS: usize = 8;
 const SLABS_LENGTH_LIMIT: usize = 4; // the maximum number of object page a szallocator can keep for allocation
+const MAGAZINE_SIZE: usize = 16;
+const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2; // objects moved per refill or flush
+const MAGAZINE_MAX_ALIGN: usize = 8; // every object of every size class is aligned to this
 
 type AllocationMap = [u64; 8];
 
+// wipe memory that goes back to the heap, with the sanitizer on it is poisoned instead
+unsafe fn scrub(ptr: *mut u8, len: usize) {
+    #[cfg(not(feature = "kasan"))]
+    ptr.write_bytes(0, len);
+    #[cfg(feature = "kasan")]
+    kasan::poison(ptr, len);
+}
+
 #[impl_doubly_linkable]
 #[repr(C)]
 struct ObjectPage<const SIZE: usize>
@@ -109,10 +124,7 @@ where
         } else if diff + layout.size() > self.data.len() {
             Err(EOVERFLOW)
         } else {
-            unsafe {
-                ptr.write_bytes(0, layout.size());
-            }
-
+            // the frontend scrubbed the object when it was freed
             let offset = diff / layout.size();
             self.allocated.set_bit(offset, 0);
             self.count = self.count - 1;
@@ -127,6 +139,7 @@ type ObjectPage2M = ObjectPage<0x200000>;
 static_vector!(Free4KVec, VaRange, BACKEND_FREE_4K);
 static_vector!(Free2MVec, VaRange, BACKEND_FREE_2M);
 static_vector!(ObjectPageVec, VaRange, OBJECT_PAGE_PER_SIZE_CLASS);
+static_vector!(Magazine, usize, MAGAZINE_SIZE);
 
 #[impl_doubly_linkable]
 #[derive(Copy, Clone)]
@@ -165,8 +178,12 @@ struct HeapBackend {
 }
 struct HeapFrontend {
     sc_allocator: [Spinlock<SizeClassAllocator>; 9],
+    cpu_cache: [UnsafeCell<CpuCache>; NUM_OF_CORES],
 }
 
+// a core only touches its own cache and only with irqs masked
+unsafe impl Sync for HeapFrontend {}
+
 type_enum!(
     enum Log2SizeClass {
         SZ_8 = 3,
@@ -206,6 +223,11 @@ impl SizeClassAllocator {
     }
 
     pub fn refill(&mut self, start: usize) -> Result<(), ErrorCode> {
+        #[cfg(feature = "kasan")]
+        {
+            let obj: &mut ObjectPage4K = Link::some(start).resolve_mut();
+            unsafe { kasan::poison(obj.data.as_mut_ptr(), obj.data.len()) };
+        }
         self.slabs.push_front(Link::some(start));
         Ok(())
     }
@@ -258,9 +280,181 @@ impl SizeClassAllocator {
     }
 }
 
+// free objects of every size class owned by one core
+//
+// the common path pops or pushes a magazine without taking any lock. an empty magazine is
+// refilled and a full one is flushed by MAGAZINE_BATCH objects under the size class lock.
+struct CpuCache {
+    magazines: [Magazine; 9],
+}
+
+impl CpuCache {
+    const MAGAZINE: Magazine = Magazine::new();
+    const fn new() -> Self {
+        Self {
+            magazines: [Self::MAGAZINE; 9],
+        }
+    }
+}
+
 struct UnsafeHeapAllocator {
     backend: Spinlock<HeapBackend>,
     frontend: HeapFrontend,
+    counters: HeapCounters,
+}
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+// updated on every alloc and dealloc without taking a lock
+struct HeapCounters {
+    live: [AtomicUsize; 9],     // objects of every size class handed out
+    big_live: [AtomicUsize; 4], // spans of every big object size class handed out
+    live_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    internal_bytes: AtomicUsize, // allocated by the heap for its own bookkeeping
+    bump_buffers: AtomicUsize,
+    bump_pages: AtomicUsize,
+    peak_bump_pages: AtomicUsize,
+}
+
+impl HeapCounters {
+    const fn new() -> Self {
+        Self {
+            live: [ZERO; 9],
+            big_live: [ZERO; 4],
+            live_bytes: ZERO,
+            peak_bytes: ZERO,
+            internal_bytes: ZERO,
+            bump_buffers: ZERO,
+            bump_pages: ZERO,
+            peak_bump_pages: ZERO,
+        }
+    }
+
+    // bytes actually taken by an allocation of `layout`
+    fn footprint(layout: Layout) -> usize {
+        if layout.size() >= HeapFrontend::HEAP_FRONTEND_MAX_SIZE_EXCLUSIVE {
+            HeapBackend::pick_big_object_size(layout.size()).to_npage() * 4096
+        } else {
+            HeapFrontend::pick_size_class(layout.size()).to_bytes()
+        }
+    }
+
+    fn class_counter(&self, layout: Layout) -> &AtomicUsize {
+        if layout.size() >= HeapFrontend::HEAP_FRONTEND_MAX_SIZE_EXCLUSIVE {
+            &self.big_live[HeapBackend::pick_big_object_size(layout.size()) as usize]
+        } else {
+            &self.live[HeapFrontend::pick_size_class(layout.size()) as usize - 3]
+        }
+    }
+
+    fn on_alloc(&self, layout: Layout) {
+        self.class_counter(layout).fetch_add(1, Ordering::Relaxed);
+        let bytes = self
+            .live_bytes
+            .fetch_add(Self::footprint(layout), Ordering::Relaxed);
+        self.peak_bytes
+            .fetch_max(bytes + Self::footprint(layout), Ordering::Relaxed);
+    }
+
+    fn on_dealloc(&self, layout: Layout) {
+        self.class_counter(layout).fetch_sub(1, Ordering::Relaxed);
+        self.live_bytes
+            .fetch_sub(Self::footprint(layout), Ordering::Relaxed);
+    }
+
+    // an allocation the heap made for itself and keeps forever
+    fn mark_internal(&self, layout: Layout) {
+        self.live_bytes
+            .fetch_sub(Self::footprint(layout), Ordering::Relaxed);
+        self.internal_bytes
+            .fetch_add(Self::footprint(layout), Ordering::Relaxed);
+    }
+
+    fn on_bump_alloc(&self, npage: usize) {
+        self.bump_buffers.fetch_add(1, Ordering::Relaxed);
+        let pages = self.bump_pages.fetch_add(npage, Ordering::Relaxed);
+        self.peak_bump_pages
+            .fetch_max(pages + npage, Ordering::Relaxed);
+    }
+
+    fn on_bump_free(&self, npage: usize) {
+        self.bump_buffers.fetch_sub(1, Ordering::Relaxed);
+        self.bump_pages.fetch_sub(npage, Ordering::Relaxed);
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct SizeClassStats {
+    pub live: usize,       // objects handed out
+    pub pages: usize,      // object pages with room left
+    pub full_pages: usize, // object pages without
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct HeapStats {
+    pub size_classes: [SizeClassStats; 9],
+    pub big_live: [usize; 4],
+    pub big_free: [usize; 4],
+    pub backend_free_4K: usize,
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    pub internal_bytes: usize,
+    pub bump_buffers: usize,
+    pub bump_pages: usize,
+    pub peak_bump_pages: usize,
+}
+
+impl HeapStats {
+    // memory handed out that is not in `baseline`, in bytes and bump buffers
+    pub fn leaked_since(&self, baseline: &HeapStats) -> Option<(isize, isize)> {
+        let bytes = self.live_bytes as isize - baseline.live_bytes as isize;
+        let buffers = self.bump_buffers as isize - baseline.bump_buffers as isize;
+        if bytes == 0 && buffers == 0 {
+            None
+        } else {
+            Some((bytes, buffers))
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} bytes live, peak {}, {} bytes of metadata",
+            self.live_bytes, self.peak_bytes, self.internal_bytes
+        )?;
+        writeln!(
+            f,
+            "    {:>6} {:>8} {:>6} {:>6}",
+            "class", "live", "pages", "full"
+        )?;
+        for (i, sc) in self.size_classes.iter().enumerate() {
+            writeln!(
+                f,
+                "    {:>6} {:>8} {:>6} {:>6}",
+                1 << (i + 3),
+                sc.live,
+                sc.pages,
+                sc.full_pages
+            )?;
+        }
+        writeln!(f, "    {:>6} {:>8} {:>6}", "big", "live", "free")?;
+        for (i, name) in ["4K", "8K", "32K", "256K"].iter().enumerate() {
+            writeln!(
+                f,
+                "    {:>6} {:>8} {:>6}",
+                name, self.big_live[i], self.big_free[i]
+            )?;
+        }
+        writeln!(f, "backend: {} free 4K pages", self.backend_free_4K)?;
+        writeln!(
+            f,
+            "bump buffers: {} with {} pages, peak {} pages",
+            self.bump_buffers, self.bump_pages, self.peak_bump_pages
+        )
+    }
 }
 
 type_enum!(
@@ -358,6 +552,13 @@ impl HeapBackend {
         if sc >= self.free_big_objects.len() {
             Err(EINVAL)
         } else {
+            #[cfg(feature = "kasan")]
+            unsafe {
+                kasan::poison(
+                    mapped.va.start().value() as *mut u8,
+                    mapped.va.size_in_bytes(),
+                );
+            }
             let span = Box::new(Span::new(
                 mapped.va.start(),
                 mapped.va.size_in_bytes() / 4096,
@@ -403,10 +604,13 @@ impl HeapBackend {
             } else {
                 let span = link.resolve();
                 unsafe {
+                    #[cfg(not(feature = "kasan"))]
                     clear_memory_range(
                         span.start.value(),
                         span.start.value() + sc.to_npage() * 4096,
                     );
+                    #[cfg(feature = "kasan")]
+                    kasan::poison(span.start.value() as *mut u8, sc.to_npage() * 4096);
                 }
                 self.allocated_big_objects[sc as usize].remove(link);
                 if self.free_big_objects[sc as usize].len() > FREE_BIG_OBJECT_LIST_LENGTH_LIMIT {
@@ -437,6 +641,7 @@ impl HeapFrontend {
             _ => Log2SizeClass::Undefined,
         }
     }
+    const CPU_CACHE: UnsafeCell<CpuCache> = UnsafeCell::new(CpuCache::new());
     pub const fn new() -> Self {
         Self {
             sc_allocator: [
@@ -450,9 +655,16 @@ impl HeapFrontend {
                 Spinlock::new(SizeClassAllocator::new(Log2SizeClass::SZ_1024)),
                 Spinlock::new(SizeClassAllocator::new(Log2SizeClass::SZ_2048)),
             ],
+            cpu_cache: [Self::CPU_CACHE; NUM_OF_CORES],
         }
     }
 
+    // irqs must stay masked while the magazine is in use
+    fn magazine(&self, idx: usize) -> &mut Magazine {
+        let cache = unsafe { &mut *self.cpu_cache[core_id()].get() };
+        &mut cache.magazines[idx]
+    }
+
     pub fn refill(&self, start: usize, layout: Layout) -> Result<(), ErrorCode> {
         let sc = Self::pick_size_class(layout.size());
         if sc == Log2SizeClass::Undefined {
@@ -463,17 +675,59 @@ impl HeapFrontend {
 
     pub fn alloc(&self, layout: Layout) -> Option<*mut u8> {
         let sc = Self::pick_size_class(layout.size());
-        if (sc as usize - 3) >= self.sc_allocator.len() {
+        let idx = sc as usize - 3;
+        if idx >= self.sc_allocator.len() {
             return None;
         }
-        self.sc_allocator[sc as usize - 3].lock().alloc(layout)
+        if layout.align() > MAGAZINE_MAX_ALIGN {
+            return self.sc_allocator[idx].lock().alloc(layout);
+        }
+
+        let daif = exception::local_irq_mask_save();
+        let magazine = self.magazine(idx);
+        if magazine.empty() {
+            let mut sc_allocator = self.sc_allocator[idx].lock();
+            while magazine.size() < MAGAZINE_BATCH {
+                match sc_allocator.alloc(layout) {
+                    Some(p) => magazine.push(p as usize).unwrap(),
+                    None => break,
+                }
+            }
+        }
+        let p = magazine.pop().map(|p| p as *mut u8);
+        exception::local_irq_restore(daif);
+        p
     }
-    fn dealloc(&self, ptr: *mut u8, layout: Layout) -> Result<Option<VirtualAddress>, ErrorCode> {
-        let sc = Self::pick_size_class(layout.size()) as usize;
-        if (sc - 3) >= self.sc_allocator.len() {
+
+    // returns the object pages that became empty and should go back to the backend
+    fn dealloc(&self, ptr: *mut u8, layout: Layout) -> Result<ObjectPageVec, ErrorCode> {
+        let sc = Self::pick_size_class(layout.size());
+        let idx = sc as usize - 3;
+        if idx >= self.sc_allocator.len() {
             return Err(ESUPPORTED);
         }
-        Ok(self.sc_allocator[sc - 3].lock().dealloc(ptr, layout))
+        // objects are handed out zeroed, whether from a magazine or an object page
+        unsafe {
+            scrub(ptr, sc.to_bytes());
+        }
+
+        let mut empty = ObjectPageVec::new();
+        let daif = exception::local_irq_mask_save();
+        let magazine = self.magazine(idx);
+        if magazine.full() {
+            let mut sc_allocator = self.sc_allocator[idx].lock();
+            for _ in 0..MAGAZINE_BATCH {
+                let p = magazine.pop().unwrap() as *mut u8;
+                if let Some(va) = sc_allocator.dealloc(p, layout) {
+                    empty
+                        .push(VaRange::new(va, va + VirtualAddress::_4K))
+                        .unwrap();
+                }
+            }
+        }
+        magazine.push(ptr as usize).unwrap();
+        exception::local_irq_restore(daif);
+        Ok(empty)
     }
 }
 
@@ -482,6 +736,7 @@ impl UnsafeHeapAllocator {
         Self {
             backend: Spinlock::new(HeapBackend::new()),
             frontend: HeapFrontend::new(),
+            counters: HeapCounters::new(),
         }
     }
 
@@ -505,6 +760,7 @@ impl UnsafeHeapAllocator {
                 .unwrap();
 
             self.backend.lock().refill_big(mapped).unwrap();
+            self.counters.mark_internal(Layout::new::<Span>());
             self.backend.lock().alloc_big(layout)
         } else {
             if let Some(p) = self.frontend.alloc(layout) {
@@ -541,19 +797,46 @@ impl UnsafeHeapAllocator {
             return self.backend.lock().dealloc_big(ptr, layout);
         }
 
-        let v = self.frontend.dealloc(ptr, layout)?;
+        let mut empty = self.frontend.dealloc(ptr, layout)?;
 
-        if let Some(va) = v {
+        while let Some(page) = empty.pop() {
             // first try to return to the backend
             // it its full, let MMU unmap it.
             self.backend
                 .lock()
-                .insert_4K(VaRange::new(va, va + VirtualAddress::_4K))
-                .or_else(|_| MMU.get().unwrap().unmap(va))
+                .insert_4K(page)
+                .or_else(|_| MMU.get().unwrap().unmap_range(page))
                 .unwrap();
         }
         Ok(())
     }
+
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+        for (i, sc) in self.frontend.sc_allocator.iter().enumerate() {
+            let sc = sc.lock();
+            stats.size_classes[i] = SizeClassStats {
+                live: self.counters.live[i].load(Ordering::Relaxed),
+                pages: sc.slabs.len(),
+                full_pages: sc.full_slabs.len(),
+            };
+        }
+        {
+            let backend = self.backend.lock();
+            for i in 0..backend.free_big_objects.len() {
+                stats.big_live[i] = self.counters.big_live[i].load(Ordering::Relaxed);
+                stats.big_free[i] = backend.free_big_objects[i].len();
+            }
+            stats.backend_free_4K = backend.free_4K.len();
+        }
+        stats.live_bytes = self.counters.live_bytes.load(Ordering::Relaxed);
+        stats.peak_bytes = self.counters.peak_bytes.load(Ordering::Relaxed);
+        stats.internal_bytes = self.counters.internal_bytes.load(Ordering::Relaxed);
+        stats.bump_buffers = self.counters.bump_buffers.load(Ordering::Relaxed);
+        stats.bump_pages = self.counters.bump_pages.load(Ordering::Relaxed);
+        stats.peak_bump_pages = self.counters.peak_bump_pages.load(Ordering::Relaxed);
+        stats
+    }
 }
 
 pub struct BumpBuffer {
@@ -614,6 +897,12 @@ impl BumpBuffer {
 
 impl BumpBuffer {
     pub fn new(range: VaRange) -> Self {
+        HEAP_ALLOCATOR
+            .get()
+            .unwrap()
+            .allocator
+            .counters
+            .on_bump_alloc(range.size_in_bytes() / 4096);
         Self {
             start: range.start().value(),
             next: range.start().value(),
@@ -624,9 +913,18 @@ impl BumpBuffer {
 
 impl Drop for BumpBuffer {
     fn drop(&mut self) {
+        HEAP_ALLOCATOR
+            .get()
+            .unwrap()
+            .allocator
+            .counters
+            .on_bump_free((self.end - self.start) / 4096);
         MMU.get()
             .unwrap()
-            .unmap(VirtualAddress::from(self.start))
+            .unmap_range(VaRange::new(
+                VirtualAddress::from(self.start),
+                VirtualAddress::from(self.end),
+            ))
             .unwrap();
     }
 }
@@ -647,7 +945,12 @@ impl HeapAllocator {
     }
 
     fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "kasan")]
+        let (object, layout) = (layout, kasan::padded(layout));
         if let Some(p) = self.allocator.alloc(layout) {
+            self.allocator.counters.on_alloc(layout);
+            #[cfg(feature = "kasan")]
+            let p = kasan::on_alloc(p, HeapCounters::footprint(layout), object);
             p
         } else {
             core::ptr::null::<u8>() as *mut u8
@@ -655,13 +958,29 @@ impl HeapAllocator {
     }
 
     fn dealloc(&self, ptr: *mut u8, layout: Layout) -> Result<(), ErrorCode> {
-        self.allocator.dealloc(ptr, layout)
+        #[cfg(feature = "kasan")]
+        let (ptr, layout) = {
+            let padded = kasan::padded(layout);
+            let footprint = HeapCounters::footprint(padded);
+            (kasan::on_dealloc(ptr, footprint, layout), padded)
+        };
+        self.allocator.dealloc(ptr, layout)?;
+        self.allocator.counters.on_dealloc(layout);
+        Ok(())
     }
 
     pub fn alloc_bump_buffer(&self, npage: usize) -> Result<BumpBuffer, ErrorCode> {
         let mapped = MMU.get().unwrap().kzalloc(npage, RWNORMAL, HIGHER_PAGE)?;
         Ok(BumpBuffer::new(mapped.va))
     }
+
+    pub fn stats(&self) -> HeapStats {
+        self.allocator.stats()
+    }
+}
+
+pub fn dump_heap_stats() {
+    print!("{}", HEAP_ALLOCATOR.get().unwrap().stats());
 }
 
 #[no_mangle]
@@ -694,6 +1013,7 @@ unsafe impl GlobalAlloc for Heap {
 
 #[cfg(test)]
 mod tests {
+    extern crate alloc;
     use super::*;
     use test_macros::kernel_test;
     #[derive(Debug)]
@@ -708,6 +1028,18 @@ mod tests {
         }
     }
 
+    // the layout the heap allocates for a `U`
+    fn heap_layout<U>() -> Layout {
+        #[cfg(feature = "kasan")]
+        return kasan::padded(Layout::new::<U>());
+        #[cfg(not(feature = "kasan"))]
+        Layout::new::<U>()
+    }
+
+    fn size_class_idx<U>() -> usize {
+        HeapFrontend::pick_size_class(heap_layout::<U>().size()) as usize - 3
+    }
+
     #[kernel_test]
     fn test_heap() {
         let span_size = core::mem::size_of::<Span>();
@@ -729,4 +1061,61 @@ mod tests {
             assert!(buffer.construct::<T>().is_err());
         }
     }
+
+    #[kernel_test]
+    fn test_magazine() {
+        let frontend = &HEAP_ALLOCATOR.get().unwrap().allocator.frontend;
+        let idx = size_class_idx::<[u8; 128]>();
+        let cached = || {
+            let daif = exception::local_irq_mask_save();
+            let n = frontend.magazine(idx).size();
+            exception::local_irq_restore(daif);
+            n
+        };
+
+        // the last freed object is the first one handed out again
+        let a = Box::new([7u8; 128]);
+        let p = &*a as *const _ as usize;
+        drop(a);
+        let n = cached();
+        assert!(n > 0);
+        let b = Box::new([0u8; 128]);
+        assert_eq!(&*b as *const _ as usize, p);
+        assert_eq!(cached(), n - 1);
+
+        // a full magazine flushes a batch and never grows past its size
+        let objs: alloc::vec::Vec<_> = (0..MAGAZINE_SIZE * 2)
+            .map(|_| Box::new([1u8; 128]))
+            .collect();
+        drop(objs);
+        assert!(cached() <= MAGAZINE_SIZE);
+        assert!(cached() > MAGAZINE_SIZE - MAGAZINE_BATCH);
+        // freed objects come back zeroed
+        let c = Box::new([0u8; 128]);
+        assert!(c.iter().all(|x| *x == 0));
+    }
+
+    #[kernel_test]
+    fn test_heap_stats() {
+        let heap = HEAP_ALLOCATOR.get().unwrap();
+        let before = heap.stats();
+
+        let small = Box::new([0u8; 100]);
+        let big = Box::new([0u8; 5000]);
+        let idx = size_class_idx::<[u8; 100]>();
+        let bytes = HeapCounters::footprint(heap_layout::<[u8; 100]>())
+            + HeapCounters::footprint(heap_layout::<[u8; 5000]>());
+        let stats = heap.stats();
+        assert_eq!(
+            stats.size_classes[idx].live,
+            before.size_classes[idx].live + 1
+        );
+        assert_eq!(stats.big_live[1], before.big_live[1] + 1);
+        assert_eq!(stats.leaked_since(&before), Some((bytes as isize, 0)));
+        assert!(stats.peak_bytes >= stats.live_bytes);
+
+        drop(small);
+        drop(big);
+        assert_eq!(heap.stats().leaked_since(&before), None);
+    }
 }
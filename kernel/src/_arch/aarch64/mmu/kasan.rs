@@ -0,0 +1,133 @@
+//! Heap redzones and poisoning, enabled by the `kasan` feature
+//!
+//! Every object is carved out of a larger block: a header in front records the layout it was
+//! allocated with and ends in a redzone, and the slack behind the object up to the end of the size
+//! class is a redzone as well. A freed block is filled with POISON_FREE and must still be intact
+//! when it is handed out again, so late writes through a dangling pointer are caught on the next
+//! allocation. Violations panic with the address and the size class of the block.
+
+use core::alloc::Layout;
+
+pub const POISON_FREE: u8 = 0x6b;
+pub const REDZONE: u8 = 0xfc;
+
+const REDZONE_SIZE: usize = 16;
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    redzone: [u8; REDZONE_SIZE],
+}
+
+// the header sits right in front of the object, which keeps its alignment
+fn offset(layout: Layout) -> usize {
+    layout.align().max(HEADER_SIZE)
+}
+
+// the layout the heap allocates for an object of `layout`
+pub fn padded(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        offset(layout) + layout.size() + REDZONE_SIZE,
+        layout.align(),
+    )
+    .unwrap()
+}
+
+fn report(what: &str, addr: usize, footprint: usize) -> ! {
+    panic!("kasan: {} at {:#x}, size class {}", what, addr, footprint)
+}
+
+// the first byte in `start..start + len` that is not `pattern`
+fn find_mismatch(start: *const u8, len: usize, pattern: u8) -> Option<usize> {
+    (0..len)
+        .map(|i| start as usize + i)
+        .find(|p| unsafe { *(*p as *const u8) } != pattern)
+}
+
+// fill memory that goes back to the heap
+pub unsafe fn poison(start: *mut u8, len: usize) {
+    start.write_bytes(POISON_FREE, len);
+}
+
+// check a fresh block of `footprint` bytes and lay out the object inside, which is zeroed
+pub fn on_alloc(block: *mut u8, footprint: usize, layout: Layout) -> *mut u8 {
+    if let Some(addr) = find_mismatch(block, footprint, POISON_FREE) {
+        report("write after free", addr, footprint);
+    }
+
+    let offset = offset(layout);
+    unsafe {
+        block.write_bytes(REDZONE, offset);
+        let header = &mut *(block.add(offset - HEADER_SIZE) as *mut Header);
+        header.size = layout.size();
+        header.align = layout.align();
+
+        let obj = block.add(offset);
+        obj.write_bytes(0, layout.size());
+        obj.add(layout.size())
+            .write_bytes(REDZONE, footprint - offset - layout.size());
+        obj
+    }
+}
+
+// check the redzones and the layout of `obj`, poison its block and return it
+pub fn on_dealloc(obj: *mut u8, footprint: usize, layout: Layout) -> *mut u8 {
+    let header = unsafe { &*(obj.sub(HEADER_SIZE) as *const Header) };
+    if header.size == usize::from_ne_bytes([POISON_FREE; 8]) {
+        report("double free", obj as usize, footprint);
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "kasan: dealloc at {:#x} with size {} align {}, allocated with size {} align {}",
+            obj as usize,
+            layout.size(),
+            layout.align(),
+            header.size,
+            header.align
+        );
+    }
+
+    let offset = offset(layout);
+    let block = unsafe { obj.sub(offset) };
+    let front = unsafe { obj.sub(REDZONE_SIZE) };
+    if let Some(addr) = find_mismatch(block, offset - HEADER_SIZE, REDZONE)
+        .or_else(|| find_mismatch(front, REDZONE_SIZE, REDZONE))
+    {
+        report("underflow", addr, footprint);
+    }
+    let back = unsafe { obj.add(layout.size()) };
+    if let Some(addr) = find_mismatch(back, footprint - offset - layout.size(), REDZONE) {
+        report("overflow", addr, footprint);
+    }
+
+    unsafe { poison(block, footprint) };
+    block
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    extern crate alloc;
+    use super::*;
+    use alloc::boxed::Box;
+    use test_macros::kernel_test;
+
+    #[kernel_test]
+    fn test_kasan() {
+        let b = Box::new([1u8; 24]);
+        let p = &*b as *const u8;
+        let header = unsafe { &*(p.sub(HEADER_SIZE) as *const Header) };
+        assert_eq!(header.size, 24);
+        assert_eq!(header.align, 1);
+        unsafe {
+            assert_eq!(*p.sub(1), REDZONE);
+            assert_eq!(*p.add(24), REDZONE);
+        }
+
+        drop(b);
+        // the object now sits poisoned in a magazine
+        unsafe { assert_eq!(*p, POISON_FREE) };
+    }
+}
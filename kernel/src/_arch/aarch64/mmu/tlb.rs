@@ -0,0 +1,112 @@
+//! TLB maintenance
+//!
+//! Every function returns once the invalidation is complete. The `_is` variants broadcast it to
+//! the inner shareable domain, i.e. to every core, the others only act on the calling core. Kernel
+//! pages are global, so the by-VA functions without an ASID drop the translation for every ASID.
+
+use super::{address::*, config};
+use core::arch::asm;
+
+// beyond this many pages a range is cheaper to drop with a full flush
+const RANGE_MAX_PAGES: usize = 64;
+
+// VA[55:12] in bits 43:0 of a TLBI operand, the top bits of a higher half address would end up
+// in the ASID field otherwise
+#[inline(always)]
+fn page(va: VirtualAddress) -> u64 {
+    ((va.value() >> config::SHIFT_4K) as u64) & ((1 << 44) - 1)
+}
+
+#[inline(always)]
+fn with_asid(va: VirtualAddress, asid: u8) -> u64 {
+    ((asid as u64) << 48) | page(va)
+}
+
+pub fn invalidate_all() {
+    unsafe {
+        asm!("DSB ISHST", "TLBI VMALLE1", "DSB ISH", "ISB");
+    }
+}
+pub fn invalidate_all_is() {
+    unsafe {
+        asm!("DSB ISHST", "TLBI VMALLE1IS", "DSB ISH", "ISB");
+    }
+}
+
+pub fn invalidate_asid(asid: u8) {
+    unsafe {
+        asm!("DSB ISHST", "TLBI ASIDE1, {}", "DSB ISH", "ISB", in(reg) ((asid as u64) << 48));
+    }
+}
+pub fn invalidate_asid_is(asid: u8) {
+    unsafe {
+        asm!("DSB ISHST", "TLBI ASIDE1IS, {}", "DSB ISH", "ISB", in(reg) ((asid as u64) << 48));
+    }
+}
+
+pub fn invalidate_va(va: VirtualAddress) {
+    unsafe {
+        asm!("DSB ISHST", "TLBI VAAE1, {}", "DSB ISH", "ISB", in(reg) page(va));
+    }
+}
+pub fn invalidate_va_is(va: VirtualAddress) {
+    unsafe {
+        asm!("DSB ISHST", "TLBI VAAE1IS, {}", "DSB ISH", "ISB", in(reg) page(va));
+    }
+}
+
+pub fn invalidate_va_asid(va: VirtualAddress, asid: u8) {
+    unsafe {
+        asm!("DSB ISHST", "TLBI VAE1, {}", "DSB ISH", "ISB", in(reg) with_asid(va, asid));
+    }
+}
+pub fn invalidate_va_asid_is(va: VirtualAddress, asid: u8) {
+    unsafe {
+        asm!("DSB ISHST", "TLBI VAE1IS, {}", "DSB ISH", "ISB", in(reg) with_asid(va, asid));
+    }
+}
+
+// every 4K page in `range`, one barrier for the whole range
+pub fn invalidate_range(range: VaRange) {
+    let npage = range.size_in_bytes() >> config::SHIFT_4K;
+    if npage > RANGE_MAX_PAGES {
+        return invalidate_all();
+    }
+    unsafe {
+        asm!("DSB ISHST");
+        for i in 0..npage {
+            let va = range.start() + VirtualAddress::from(i << config::SHIFT_4K);
+            asm!("TLBI VAAE1, {}", in(reg) page(va));
+        }
+        asm!("DSB ISH", "ISB");
+    }
+}
+pub fn invalidate_range_is(range: VaRange) {
+    let npage = range.size_in_bytes() >> config::SHIFT_4K;
+    if npage > RANGE_MAX_PAGES {
+        return invalidate_all_is();
+    }
+    unsafe {
+        asm!("DSB ISHST");
+        for i in 0..npage {
+            let va = range.start() + VirtualAddress::from(i << config::SHIFT_4K);
+            asm!("TLBI VAAE1IS, {}", in(reg) page(va));
+        }
+        asm!("DSB ISH", "ISB");
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    #[kernel_test]
+    fn test_tlb_operand() {
+        let higher = VirtualAddress::from(config::KERNEL_BASE | 0x1234_5000);
+        assert_eq!(page(higher) >> 44, 0);
+        assert_eq!(page(higher) & 0xfffff, 0x12345);
+        assert_eq!(with_asid(higher, 3) >> 48, 3);
+    }
+}
@@ -63,7 +63,7 @@ pub enum Descriptor {
     INVALID,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Eq, PartialEq)]
 pub enum MemoryType {
     RwNormal,
     RoNormal,
@@ -146,7 +146,8 @@ impl Descriptor {
         match *self {
             Self::INVALID => INVALID_PAGE,
             Self::L1BlockEntry(e) | Self::L2BlockEntry(e) | Self::PageEntry(e) => {
-                let attr = e & Self::BLOCK_PAGE_ATTR_MASK;
+                // user pages are tagged with an ASID on top of their type
+                let attr = e & Self::BLOCK_PAGE_ATTR_MASK & !(0b1 << Self::nG);
                 if attr == Self::RW_NORMAL {
                     RWNORMAL
                 } else if attr == Self::RO_NORMAL {
@@ -264,6 +265,29 @@ impl Descriptor {
             _ => None,
         }
     }
+    // tag the entry with the ASID of the active address space
+    pub fn set_nG(&mut self) -> Result<(), ErrorCode> {
+        match *self {
+            Self::L1BlockEntry(e) => {
+                *self = Self::L1BlockEntry(e | (0b1 << Self::nG));
+                Ok(())
+            }
+            Self::L2BlockEntry(e) => {
+                *self = Self::L2BlockEntry(e | (0b1 << Self::nG));
+                Ok(())
+            }
+            // only matters when the table is reached through the recursive entry
+            Self::TableEntry(e) => {
+                *self = Self::TableEntry(e | (0b1 << Self::nG));
+                Ok(())
+            }
+            Self::PageEntry(e) => {
+                *self = Self::PageEntry(e | (0b1 << Self::nG));
+                Ok(())
+            }
+            Self::INVALID => Err(EINVAL),
+        }
+    }
     pub fn get_AF(&self) -> Option<u8> {
         match *self {
             Self::L1BlockEntry(e) => Some(e.get_bit(Self::AF) as u8),
@@ -1,14 +1,19 @@
 use super::{
-    address::*, allocator::*, cache::*, config, translation_entry::*, BlockSize, BLOCK_1G,
+    address::*, allocator::*, config, probe, tlb, translation_entry::*, BlockSize, BLOCK_1G,
     BLOCK_2M, BLOCK_4K,
 };
-use crate::{errno::*, println};
+use crate::{cpu::smp::core_id, errno::*, exception, println, synchronization::Spinlock};
 use aarch64_cpu::{
     asm::barrier,
     registers::{TTBR0_EL1, TTBR1_EL1},
 };
-use core::{arch::asm, ops::Index};
-use tock_registers::interfaces::ReadWriteable;
+use core::{
+    arch::asm,
+    ops::Index,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::once::Once;
+use tock_registers::interfaces::{ReadWriteable, Readable};
 
 extern "C" {
     static __code_start: u8;
@@ -49,33 +54,79 @@ impl<L: TranslationTableLevel> UnsafeTranslationTable<L> {
         self.base.addr()
     }
 
+    // whatever the entry mapped before is gone from every TLB when this returns
     pub fn set_entry(&self, idx: usize, entry: TranslationTableEntry<L>) -> Result<(), ErrorCode> {
         if idx >= config::ENTRIES_PER_TABLE {
             Err(EBOUND)
         } else {
-            unsafe {
-                let target: usize =
-                    self.base as usize + idx * core::mem::size_of::<TranslationTableEntry<L>>();
-                core::ptr::write_volatile(target as *mut u64, entry.value());
-
-                asm!("DSB SY", "ISB SY",);
-            }
+            let old = self.write(idx, entry.value());
+            self.invalidate(idx, old);
             Ok(())
         }
     }
     pub fn set_invalid(&self, idx: usize) -> Result<(), ErrorCode> {
-        if idx >= config::ENTRIES_PER_TABLE {
-            Err(EBOUND)
-        } else {
-            unsafe {
-                let target: usize =
-                    self.base as usize + idx * core::mem::size_of::<TranslationTableEntry<L>>();
-                core::ptr::write_volatile(target as *mut u64, 0);
+        self.set_entry(idx, TranslationTableEntry::from(Descriptor::INVALID))
+    }
 
-                asm!("DSB SY", "ISB SY", "TLBI VMALLE1", "DSB SY", "ISB SY");
-            }
-            Ok(())
+    // store `value` in entry `idx` without any TLB maintenance and return the old value
+    fn write(&self, idx: usize, value: u64) -> u64 {
+        unsafe {
+            let target = self.base.add(idx) as *mut u64;
+            let old = core::ptr::read_volatile(target);
+            core::ptr::write_volatile(target, value);
+
+            asm!("DSB SY", "ISB SY",);
+            old
+        }
+    }
+
+    // a 4K page or 2M block seen through the recursive window goes by address. anything else
+    // flushes everything: a table entry was part of the walks of many addresses, a 1G block is
+    // visible as a 2M block in the recursive window as well, and a table outside the window
+    // gives no address at all
+    fn invalidate(&self, idx: usize, old: u64) {
+        const VALID: u64 = 0b01;
+        const TYPE: u64 = 0b11;
+        if old & VALID == 0 {
+            return;
+        }
+        match self.recursive_va(idx) {
+            Some((va, 2)) if old & TYPE == 0b01 => tlb::invalidate_va_is(va),
+            Some((va, 3)) if old & TYPE == 0b11 => tlb::invalidate_va_is(va),
+            _ => tlb::invalidate_all_is(),
+        }
+    }
+
+    // the first address mapped by entry `idx` and the level of the table, if the table is
+    // accessed through the recursive window. the number of leading recursive indices in the
+    // table's own address tells the level
+    fn recursive_va(&self, idx: usize) -> Option<(VirtualAddress, usize)> {
+        let base = self.base as usize;
+        let index = |shift: usize| (base >> shift) & config::INDEX_MASK;
+        let r = config::RECURSIVE_L1_INDEX;
+        if index(config::L1_INDEX_SHIFT) != r {
+            return None;
         }
+        let (va, level) = if index(config::L2_INDEX_SHIFT) != r {
+            (
+                (index(config::L2_INDEX_SHIFT) << config::L1_INDEX_SHIFT)
+                    | (index(config::L3_INDEX_SHIFT) << config::L2_INDEX_SHIFT)
+                    | (idx << config::L3_INDEX_SHIFT),
+                3,
+            )
+        } else if index(config::L3_INDEX_SHIFT) != r {
+            (
+                (index(config::L3_INDEX_SHIFT) << config::L1_INDEX_SHIFT)
+                    | (idx << config::L2_INDEX_SHIFT),
+                2,
+            )
+        } else {
+            (idx << config::L1_INDEX_SHIFT, 1)
+        };
+        Some((
+            VirtualAddress::from((base & config::KERNEL_BASE) | va),
+            level,
+        ))
     }
 }
 
@@ -168,12 +219,359 @@ impl UnsafeTranslationTable<Level1> {
         sz: &BlockSize,
     ) -> Result<Mapped, ErrorCode> {
         match *sz {
-            BlockSize::_4K => self.map_4K(va, pa, mt),
+            BlockSize::_4K => self.map_4K(va, pa, mt, false),
             BlockSize::_2M => self.map_2M(va, pa, mt),
             BlockSize::_1G => self.map_1G(va, pa, mt),
         }
     }
 
+    // a 4K page of the active user address space, tagged with its ASID. the tables created on the
+    // way are tagged as well since they are reachable through the recursive entry
+    pub fn map_user(
+        &self,
+        va: VirtualAddress,
+        pa: PhysicalAddress,
+        mt: &MemoryType,
+    ) -> Result<Mapped, ErrorCode> {
+        if !va.is_lower() {
+            return Err(EINVAL);
+        }
+        self.map_4K(va, pa, mt, true)
+    }
+
+    // free every frame mapped in the lower half of the active address space, the level 2 and 3
+    // tables included. entries equal to those of `kernel` are shared with it and left alone. the
+    // entries themselves are not cleared, the caller is about to throw the level 1 table away
+    pub fn release_lower(&self, kernel: &UnsafeTranslationTable<Level1>) {
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        for l1 in 0..config::RECURSIVE_L1_INDEX {
+            if self[l1].value() == kernel[l1].value() {
+                continue;
+            }
+            let l1_va = VirtualAddress::from(l1 << config::L1_INDEX_SHIFT);
+            let l1_entry = self[l1].get();
+            match l1_entry {
+                Descriptor::TableEntry(_) => {
+                    let l2_base = Self::l2_table_address(l1_va) as *mut L2Entry;
+                    let l2_table = UnsafeTranslationTable::<Level2>::new(l2_base);
+                    for l2 in 0..config::ENTRIES_PER_TABLE {
+                        let l2_va =
+                            VirtualAddress::from(l1_va.value() | (l2 << config::L2_INDEX_SHIFT));
+                        let l2_entry = l2_table[l2].get();
+                        match l2_entry {
+                            Descriptor::TableEntry(_) => {
+                                let l3_table = UnsafeTranslationTable::<Level3>::new(
+                                    Self::l3_table_address(l2_va) as *mut L3Entry,
+                                );
+                                for l3 in 0..config::ENTRIES_PER_TABLE {
+                                    let l3_entry = l3_table[l3].get();
+                                    // the frame may still be shared copy-on-write
+                                    if let Descriptor::PageEntry(_) = l3_entry {
+                                        frames
+                                            .release(l3_entry.get_address().unwrap().to_4K_range())
+                                            .unwrap();
+                                    }
+                                }
+                                frames
+                                    .free_range(l2_entry.get_address().unwrap().to_4K_range())
+                                    .unwrap();
+                            }
+                            Descriptor::L2BlockEntry(_) => {
+                                frames
+                                    .free_range(l2_entry.get_address().unwrap().to_2M_range())
+                                    .unwrap();
+                            }
+                            _ => {}
+                        }
+                    }
+                    frames
+                        .free_range(l1_entry.get_address().unwrap().to_4K_range())
+                        .unwrap();
+                }
+                Descriptor::L1BlockEntry(_) => {
+                    frames
+                        .free_range(l1_entry.get_address().unwrap().to_1G_range())
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // call `f` with every 4K page mapped in the lower half of the active address space, except
+    // for the level 1 entries shared with `kernel`
+    pub fn for_each_user_page(
+        &self,
+        kernel: &UnsafeTranslationTable<Level1>,
+        mut f: impl FnMut(VirtualAddress, &Descriptor),
+    ) {
+        for l1 in 0..config::RECURSIVE_L1_INDEX {
+            if self[l1].value() == kernel[l1].value() {
+                continue;
+            }
+            let Descriptor::TableEntry(_) = self[l1].get() else {
+                continue;
+            };
+            let l1_va = VirtualAddress::from(l1 << config::L1_INDEX_SHIFT);
+            let l2_base = Self::l2_table_address(l1_va) as *mut L2Entry;
+            let l2_table = UnsafeTranslationTable::<Level2>::new(l2_base);
+            for l2 in 0..config::ENTRIES_PER_TABLE {
+                let Descriptor::TableEntry(_) = l2_table[l2].get() else {
+                    continue;
+                };
+                let l2_va = VirtualAddress::from(l1_va.value() | (l2 << config::L2_INDEX_SHIFT));
+                let l3_table = UnsafeTranslationTable::<Level3>::new(
+                    Self::l3_table_address(l2_va) as *mut L3Entry
+                );
+                for l3 in 0..config::ENTRIES_PER_TABLE {
+                    let l3_entry = l3_table[l3].get();
+                    if let Descriptor::PageEntry(_) = l3_entry {
+                        f(
+                            VirtualAddress::from(l2_va.value() | (l3 << config::L3_INDEX_SHIFT)),
+                            &l3_entry,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // unmap the 4K page at `va` of the active user address space on every core, the frame is
+    // left to the caller
+    pub fn unmap_user(&self, va: VirtualAddress) -> Result<PaRange, ErrorCode> {
+        if !va.is_lower() {
+            return Err(EINVAL);
+        }
+        self.unmap(va)
+    }
+
+    // replace the 4K page at `va` of the active user address space with `pa`, returns the frame
+    // mapped before. break-before-make, the old entry is gone from every TLB before the new one
+    // is written
+    pub fn remap_user(
+        &self,
+        va: VirtualAddress,
+        pa: PhysicalAddress,
+        mt: &MemoryType,
+    ) -> Result<PaRange, ErrorCode> {
+        if !va.is_lower() {
+            return Err(EINVAL);
+        }
+        if !va.is_4K_aligned() || !pa.is_4K_aligned() {
+            return Err(EALIGN);
+        }
+        let Descriptor::TableEntry(_) = self[va.level1()].get() else {
+            return Err(EUNMAP);
+        };
+        let l2_table =
+            UnsafeTranslationTable::<Level2>::new(Self::l2_table_address(va) as *mut L2Entry);
+        let Descriptor::TableEntry(_) = l2_table[va.level2()].get() else {
+            return Err(EUNMAP);
+        };
+        let l3_table =
+            UnsafeTranslationTable::<Level3>::new(Self::l3_table_address(va) as *mut L3Entry);
+        let old = l3_table[va.level3()].get();
+        let Descriptor::PageEntry(_) = old else {
+            return Err(EUNMAP);
+        };
+
+        let mut l3_entry = Descriptor::INVALID.set_page()?;
+        l3_entry.set_attributes(mt)?;
+        l3_entry.set_nG()?;
+        l3_entry.set_address(pa)?;
+
+        l3_table.set_invalid(va.level3())?;
+        l3_table.set_entry(va.level3(), TranslationTableEntry::from(l3_entry))?;
+        Ok(old.get_address().ok_or(EUNMAP)?.to_4K_range())
+    }
+
+    // map `va` to `pa` with the largest descriptors their alignment and length allow, 1G and 2M
+    // blocks are only used where nothing else is mapped yet
+    pub fn map_range(&self, va: VaRange, pa: PaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        let len = va.size_in_bytes();
+        if len != pa.size_in_bytes() {
+            return Err(EPARAM);
+        }
+        if !va.start().is_4K_aligned() || !pa.start().is_4K_aligned() || !va.is_4K_multiple() {
+            return Err(EALIGN);
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let v = va.start() + VirtualAddress::from(offset);
+            let p = pa.start() + PhysicalAddress::from(offset);
+            let sz = self.block_size_for(v, p, len - offset);
+            if let Err(e) = self.map(v, p, mt, sz) {
+                if offset != 0 {
+                    self.unmap_range(VaRange::new(va.start(), v)).unwrap();
+                }
+                return Err(e);
+            }
+            offset += block_bytes(sz);
+        }
+        Ok(())
+    }
+
+    // unmap everything in `range`, splitting the blocks it only covers in part. the frames are
+    // left to the caller
+    pub fn unmap_range(&self, range: VaRange) -> Result<(), ErrorCode> {
+        let mut va = range.start();
+        while va < range.end() {
+            let size = self.leaf_size(va).ok_or(EUNMAP)?;
+            if va.is_aligned_to(size) && va.value() + size <= range.end().value() {
+                self.unmap(va)?;
+                va += VirtualAddress::from(size);
+            } else {
+                self.split(va)?;
+            }
+        }
+        Ok(())
+    }
+
+    // change the type of everything mapped in `range`, splitting the blocks it only covers in part
+    pub fn protect_range(&self, range: VaRange, mt: &MemoryType) -> Result<(), ErrorCode> {
+        let mut va = range.start();
+        while va < range.end() {
+            let size = self.leaf_size(va).ok_or(EUNMAP)?;
+            if !va.is_aligned_to(size) || va.value() + size > range.end().value() {
+                self.split(va)?;
+                continue;
+            }
+            // break-before-make
+            if size == config::FRAME_SIZE {
+                let l3_table = UnsafeTranslationTable::<Level3>::new(
+                    Self::l3_table_address(va) as *mut L3Entry
+                );
+                let new = reprotect(&l3_table[va.level3()].get(), mt)?;
+                l3_table.set_invalid(va.level3())?;
+                l3_table.set_entry(va.level3(), TranslationTableEntry::from(new))?;
+            } else if size == block_bytes(BLOCK_2M) {
+                let l2_table = UnsafeTranslationTable::<Level2>::new(
+                    Self::l2_table_address(va) as *mut L2Entry
+                );
+                let new = reprotect(&l2_table[va.level2()].get(), mt)?;
+                l2_table.set_invalid(va.level2())?;
+                l2_table.set_entry(va.level2(), TranslationTableEntry::from(new))?;
+            } else {
+                let new = reprotect(&self[va.level1()].get(), mt)?;
+                self.set_invalid(va.level1())?;
+                self.set_entry(va.level1(), TranslationTableEntry::from(new))?;
+            }
+            va += VirtualAddress::from(size);
+        }
+        Ok(())
+    }
+
+    // the largest block that fits at `va` and `pa` and is not in the way of a table
+    fn block_size_for(
+        &self,
+        va: VirtualAddress,
+        pa: PhysicalAddress,
+        len: usize,
+    ) -> &'static BlockSize {
+        let l1_entry = self[va.level1()].get();
+        if va.is_1G_aligned() && pa.is_1G_aligned() && len >= block_bytes(BLOCK_1G) {
+            if let Descriptor::INVALID = l1_entry {
+                return BLOCK_1G;
+            }
+        }
+        if va.is_2M_aligned() && pa.is_2M_aligned() && len >= block_bytes(BLOCK_2M) {
+            match l1_entry {
+                Descriptor::INVALID => return BLOCK_2M,
+                Descriptor::TableEntry(_) => {
+                    let l2_table = UnsafeTranslationTable::<Level2>::new(
+                        Self::l2_table_address(va) as *mut L2Entry,
+                    );
+                    if let Descriptor::INVALID = l2_table[va.level2()].get() {
+                        return BLOCK_2M;
+                    }
+                }
+                _ => {}
+            }
+        }
+        BLOCK_4K
+    }
+
+    // the number of bytes mapped by the descriptor that maps `va`
+    fn leaf_size(&self, va: VirtualAddress) -> Option<usize> {
+        match self[va.level1()].get() {
+            Descriptor::L1BlockEntry(_) => Some(block_bytes(BLOCK_1G)),
+            Descriptor::TableEntry(_) => {
+                let l2_table = UnsafeTranslationTable::<Level2>::new(
+                    Self::l2_table_address(va) as *mut L2Entry
+                );
+                match l2_table[va.level2()].get() {
+                    Descriptor::L2BlockEntry(_) => Some(block_bytes(BLOCK_2M)),
+                    Descriptor::TableEntry(_) => {
+                        let l3_table = UnsafeTranslationTable::<Level3>::new(
+                            Self::l3_table_address(va) as *mut L3Entry,
+                        );
+                        match l3_table[va.level3()].get() {
+                            Descriptor::PageEntry(_) => Some(block_bytes(BLOCK_4K)),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // replace the block that maps `va` with a table of the next level mapping the same memory.
+    // the table is filled in before it takes the place of the block. between the two writes of
+    // the break-before-make the block is unmapped, a core touching it then waits in its fault
+    // handler until the table is in place, see `wait_for_split`
+    fn split(&self, va: VirtualAddress) -> Result<(), ErrorCode> {
+        // one split at a time, the window below has room for one block
+        let split_page = SPLIT_PAGE.get().ok_or(EINIT)?.lock();
+        let l1_entry = self[va.level1()].get();
+        match l1_entry {
+            Descriptor::L1BlockEntry(_) => {
+                let base = l1_entry.get_address().ok_or(EUNMAP)?;
+                let table = new_table::<Level2>(&split_page, |i| {
+                    let pa = base + PhysicalAddress::from(i << config::SHIFT_2M);
+                    leaf(
+                        Descriptor::INVALID.set_l2_block()?,
+                        &l1_entry,
+                        pa,
+                        l1_entry.get_attributes(),
+                    )
+                })?;
+                let start = va.value() & config::ALIGN_1G;
+                with_split_window(start, start + (1 << config::SHIFT_1G), || {
+                    self.set_invalid(va.level1())?;
+                    self.set_entry(va.level1(), table_entry(&l1_entry, table)?)
+                })
+            }
+            Descriptor::TableEntry(_) => {
+                let l2_table = UnsafeTranslationTable::<Level2>::new(
+                    Self::l2_table_address(va) as *mut L2Entry
+                );
+                let l2_entry = l2_table[va.level2()].get();
+                let Descriptor::L2BlockEntry(_) = l2_entry else {
+                    return Err(ETYPE);
+                };
+                let base = l2_entry.get_address().ok_or(EUNMAP)?;
+                let table = new_table::<Level3>(&split_page, |i| {
+                    let pa = base + PhysicalAddress::from(i << config::SHIFT_4K);
+                    leaf(
+                        Descriptor::INVALID.set_page()?,
+                        &l2_entry,
+                        pa,
+                        l2_entry.get_attributes(),
+                    )
+                })?;
+                let start = va.value() & config::ALIGN_2M;
+                with_split_window(start, start + (1 << config::SHIFT_2M), || {
+                    l2_table.set_invalid(va.level2())?;
+                    l2_table.set_entry(va.level2(), table_entry(&l2_entry, table)?)
+                })
+            }
+            _ => Err(ETYPE),
+        }
+    }
+
     fn l2_table_address(va: VirtualAddress) -> usize {
         let mut res: usize = 0;
         if va.is_higher() {
@@ -200,6 +598,7 @@ impl UnsafeTranslationTable<Level1> {
         va: VirtualAddress,
         pa: PhysicalAddress,
         mt: &MemoryType,
+        ng: bool,
     ) -> Result<Mapped, ErrorCode> {
         if !va.is_4K_aligned() || !pa.is_4K_aligned() {
             return Err(EALIGN);
@@ -210,6 +609,9 @@ impl UnsafeTranslationTable<Level1> {
             Descriptor::INVALID => {
                 l1_entry = l1_entry.set_table()?;
                 l1_entry.set_attributes(TABLE_PAGE)?;
+                if ng {
+                    l1_entry.set_nG()?;
+                }
                 let allocated_frame_addr: PhysicalAddress =
                     FRAME_ALLOCATOR.get().unwrap().allocate(BLOCK_4K)?.start();
                 l1_entry.set_address(allocated_frame_addr)?;
@@ -231,6 +633,9 @@ impl UnsafeTranslationTable<Level1> {
             Descriptor::INVALID => {
                 l2_entry = l2_entry.set_table()?;
                 l2_entry.set_attributes(TABLE_PAGE)?;
+                if ng {
+                    l2_entry.set_nG()?;
+                }
 
                 let allocated_frame_addr: PhysicalAddress =
                     FRAME_ALLOCATOR.get().unwrap().allocate(BLOCK_4K)?.start();
@@ -253,6 +658,9 @@ impl UnsafeTranslationTable<Level1> {
             Descriptor::INVALID => {
                 l3_entry = l3_entry.set_page()?;
                 l3_entry.set_attributes(mt)?;
+                if ng {
+                    l3_entry.set_nG()?;
+                }
                 l3_entry.set_address(pa)?;
                 l3_table.set_entry(va.level3(), TranslationTableEntry::from(l3_entry))?;
             }
@@ -337,6 +745,148 @@ impl UnsafeTranslationTable<Level1> {
     }
 }
 
+// a higher half 4K page whose tables are in place from the start. pointing it at a frame only
+// writes its L3 entry, so it allocates nothing and takes no table lock, e.g. while the lock is
+// held already. one user at a time
+pub struct FixedPage {
+    va: VirtualAddress,
+}
+
+impl FixedPage {
+    pub(super) fn new(va: VirtualAddress) -> Self {
+        Self { va }
+    }
+
+    fn l3_table(&self) -> UnsafeTranslationTable<Level3> {
+        let address = UnsafeTranslationTable::<Level1>::l3_table_address(self.va);
+        UnsafeTranslationTable::<Level3>::new(address as *mut L3Entry)
+    }
+
+    // break-before-make, the frame it showed before is gone from every TLB first
+    pub fn map(&self, pa: PhysicalAddress, mt: &MemoryType) -> Result<VirtualAddress, ErrorCode> {
+        if !pa.is_4K_aligned() {
+            return Err(EALIGN);
+        }
+        let mut entry = Descriptor::INVALID.set_page()?;
+        entry.set_attributes(mt)?;
+        entry.set_address(pa)?;
+        let l3_table = self.l3_table();
+        l3_table.set_invalid(self.va.level3())?;
+        l3_table.set_entry(self.va.level3(), TranslationTableEntry::from(entry))?;
+        Ok(self.va)
+    }
+
+    pub fn unmap(&self) -> Result<(), ErrorCode> {
+        self.l3_table().set_invalid(self.va.level3())
+    }
+}
+
+// new tables of split are filled in through this page
+pub(super) static SPLIT_PAGE: Once<Spinlock<FixedPage>> = Once::new();
+
+// the block split has unmapped for the moment, [start, end) on `SPLIT_CORE`. `SPLIT_END` is 0
+// while no block is
+static SPLIT_START: AtomicUsize = AtomicUsize::new(0);
+static SPLIT_END: AtomicUsize = AtomicUsize::new(0);
+static SPLIT_CORE: AtomicUsize = AtomicUsize::new(0);
+
+// irqs are masked, so nothing else on this core touches the block while it is unmapped
+fn with_split_window<R>(start: usize, end: usize, f: impl FnOnce() -> R) -> R {
+    let daif = exception::local_irq_mask_save();
+    SPLIT_CORE.store(core_id(), Ordering::Relaxed);
+    SPLIT_START.store(start, Ordering::Relaxed);
+    SPLIT_END.store(end, Ordering::Release);
+    let result = f();
+    SPLIT_END.store(0, Ordering::Release);
+    exception::local_irq_restore(daif);
+    result
+}
+
+// called on a kernel fault at `va`. if another core is splitting the block of `va` this waits
+// for the table to take its place. true if `va` is mapped now, i.e. the access can be retried
+pub fn wait_for_split(va: VirtualAddress, write: bool) -> bool {
+    let in_window = || {
+        let end = SPLIT_END.load(Ordering::Acquire);
+        (SPLIT_START.load(Ordering::Relaxed)..end).contains(&va.value())
+            && SPLIT_CORE.load(Ordering::Relaxed) != core_id()
+    };
+    while in_window() {
+        core::hint::spin_loop();
+    }
+    probe(va, write).is_some()
+}
+
+fn block_bytes(sz: &BlockSize) -> usize {
+    match *sz {
+        BlockSize::_4K => 1 << config::SHIFT_4K,
+        BlockSize::_2M => 1 << config::SHIFT_2M,
+        BlockSize::_1G => 1 << config::SHIFT_1G,
+    }
+}
+
+// `empty` filled in to map `pa` as `mt`, tagged with the ASID if `like` is
+fn leaf(
+    mut empty: Descriptor,
+    like: &Descriptor,
+    pa: PhysicalAddress,
+    mt: &MemoryType,
+) -> Result<Descriptor, ErrorCode> {
+    empty.set_attributes(mt)?;
+    if like.get_nG() == Some(1) {
+        empty.set_nG()?;
+    }
+    empty.set_address(pa)?;
+    Ok(empty)
+}
+
+// the same mapping as `old` with the type `mt`
+fn reprotect(old: &Descriptor, mt: &MemoryType) -> Result<Descriptor, ErrorCode> {
+    let empty = match *old {
+        Descriptor::L1BlockEntry(_) => Descriptor::INVALID.set_l1_block()?,
+        Descriptor::L2BlockEntry(_) => Descriptor::INVALID.set_l2_block()?,
+        Descriptor::PageEntry(_) => Descriptor::INVALID.set_page()?,
+        _ => return Err(ETYPE),
+    };
+    leaf(empty, old, old.get_address().ok_or(EUNMAP)?, mt)
+}
+
+// a table of level `L` in a new frame with `entry(i)` in entry i. it is written through the split
+// page, no other table points at it yet
+fn new_table<L: TranslationTableLevel>(
+    split_page: &FixedPage,
+    entry: impl Fn(usize) -> Result<Descriptor, ErrorCode>,
+) -> Result<PhysicalAddress, ErrorCode> {
+    let frames = FRAME_ALLOCATOR.get().unwrap();
+    let frame = frames.allocate(BLOCK_4K)?;
+    let filled = split_page.map(frame.start(), RWNORMAL).and_then(|va| {
+        let table = UnsafeTranslationTable::<L>::new(va.value() as *mut TranslationTableEntry<L>);
+        for i in 0..config::ENTRIES_PER_TABLE {
+            table.write(i, TranslationTableEntry::<L>::from(entry(i)?).value());
+        }
+        Ok(())
+    });
+    split_page.unmap()?;
+    if let Err(e) = filled {
+        frames.free_range(frame)?;
+        return Err(e);
+    }
+    Ok(frame.start())
+}
+
+// a table entry for the table at `table` replacing the block `block`
+fn table_entry<L>(
+    block: &Descriptor,
+    table: PhysicalAddress,
+) -> Result<TranslationTableEntry<L>, ErrorCode> {
+    let mut entry = Descriptor::INVALID.set_table()?;
+    entry.set_attributes(TABLE_PAGE)?;
+    if block.get_nG() == Some(1) {
+        entry.set_nG()?;
+    }
+    entry.set_address(table)?;
+    Ok(TranslationTableEntry::from(entry))
+}
+
 fn get_ttbr0() -> usize {
     TTBR0_EL1.get_baddr() as usize
 }
@@ -345,22 +895,142 @@ pub fn set_ttbr0(pa: PhysicalAddress, asid: u8) {
     TTBR0_EL1.modify(TTBR0_EL1::ASID.val(asid as u64));
     TTBR0_EL1.set_baddr(pa.value() as u64);
     barrier::isb(barrier::SY);
-    A64TLB::invalidate_all();
+    tlb::invalidate_all();
 }
 pub fn set_ttbr1(pa: PhysicalAddress, asid: u8) {
     println!("Set up TTBR1_EL1 with pa {}, ASID = {}", pa, asid);
     TTBR1_EL1.modify(TTBR1_EL1::ASID.val(asid as u64));
     TTBR1_EL1.set_baddr(pa.value() as u64);
     barrier::isb(barrier::SY);
-    A64TLB::invalidate_all();
+    tlb::invalidate_all();
 }
 
 #[cfg(test)]
 #[allow(dead_code, unused_variables, unused_imports)]
 mod tests {
-    use super::*;
+    use super::{
+        super::{HIGHER_PAGE, MMU},
+        *,
+    };
+    use crate::{
+        cpu::smp::{core_id, NUM_OF_CORES},
+        kthread,
+        scheduler::{Task, SCHEDULER},
+    };
     use test_macros::kernel_test;
 
     //    #[kernel_test]
     fn test_translation_table() {}
+
+    // the address `read_block` keeps reading, usize::MAX stops it
+    static TARGET: AtomicUsize = AtomicUsize::new(0);
+    static READS: AtomicUsize = AtomicUsize::new(0);
+
+    fn read_block(_: usize) -> i32 {
+        loop {
+            match TARGET.load(Ordering::Acquire) {
+                0 => core::hint::spin_loop(),
+                usize::MAX => return 0,
+                address => {
+                    unsafe { core::ptr::read_volatile(address as *const u64) };
+                    READS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    #[kernel_test]
+    fn test_map_range() {
+        let mmu = MMU.get().unwrap();
+        let pages = PAGE_ALLOCATOR.get().unwrap();
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        const _2M: usize = 1 << config::SHIFT_2M;
+
+        // enough room for a 2M aligned start
+        let area = pages
+            .allocate_n(3 * config::ENTRIES_PER_TABLE, HIGHER_PAGE)
+            .unwrap();
+        let start =
+            VirtualAddress::from((area.start().value() + config::MASK_2M) & config::ALIGN_2M);
+        let block = frames.allocate_order(10).unwrap();
+        let len = _2M + 2 * config::FRAME_SIZE;
+        let va = VaRange::new(start, start + VirtualAddress::from(len));
+        let pa = PaRange::new(block.start(), block.start() + PhysicalAddress::from(len));
+
+        mmu.map_range(va, pa, RWNORMAL).unwrap();
+        {
+            let l1 = mmu.higher_l1.lock();
+            assert_eq!(l1.leaf_size(start), Some(_2M));
+            assert_eq!(
+                l1.leaf_size(start + VirtualAddress::_2M),
+                Some(config::FRAME_SIZE)
+            );
+        }
+
+        // a page inside the block splits it, the rest keeps its frames
+        let page = start + VirtualAddress::_4K;
+        mmu.protect_range(page.to_4K_range(), RONORMAL).unwrap();
+        {
+            let l1 = mmu.higher_l1.lock();
+            assert_eq!(l1.leaf_size(start), Some(config::FRAME_SIZE));
+            assert!(l1.translate(page) == Some(pa.start() + PhysicalAddress::_4K));
+            let last = start + VirtualAddress::from(_2M - config::FRAME_SIZE);
+            assert!(
+                l1.translate(last)
+                    == Some(pa.start() + PhysicalAddress::from(_2M - config::FRAME_SIZE))
+            );
+        }
+
+        mmu.higher_l1.lock().unmap_range(va).unwrap();
+        assert!(mmu.translate(start).is_none());
+        assert!(mmu.translate(va.end() - VirtualAddress::_4K).is_none());
+        frames.free_range(block).unwrap();
+        pages.free_range(area);
+    }
+
+    #[kernel_test]
+    fn test_split_while_read() {
+        let mmu = MMU.get().unwrap();
+        let pages = PAGE_ALLOCATOR.get().unwrap();
+        let frames = FRAME_ALLOCATOR.get().unwrap();
+        let sched = SCHEDULER.get().unwrap();
+        const _2M: usize = 1 << config::SHIFT_2M;
+
+        let area = pages
+            .allocate_n(2 * config::ENTRIES_PER_TABLE, HIGHER_PAGE)
+            .unwrap();
+        let start =
+            VirtualAddress::from((area.start().value() + config::MASK_2M) & config::ALIGN_2M);
+        let block = frames.allocate_order(9).unwrap();
+        let va = VaRange::new(start, start + VirtualAddress::from(_2M));
+        let pa = PaRange::new(block.start(), block.start() + PhysicalAddress::from(_2M));
+        mmu.map_range(va, pa, RWNORMAL).unwrap();
+        assert_eq!(mmu.higher_l1.lock().leaf_size(start), Some(_2M));
+
+        // another core reads the end of the block, the split of its first page must not fault it
+        READS.store(0, Ordering::Relaxed);
+        TARGET.store(va.end().value() - config::FRAME_SIZE, Ordering::Release);
+        let core = (core_id() + 1) % NUM_OF_CORES;
+        let reader = sched.spawn_on(core, Task::new(read_block, 0).unwrap());
+        while READS.load(Ordering::Relaxed) == 0 {
+            core::hint::spin_loop();
+        }
+
+        mmu.protect_range(start.to_4K_range(), RONORMAL).unwrap();
+        assert_eq!(
+            mmu.higher_l1.lock().leaf_size(start),
+            Some(config::FRAME_SIZE)
+        );
+        let after = READS.load(Ordering::Relaxed);
+        while READS.load(Ordering::Relaxed) == after {
+            core::hint::spin_loop();
+        }
+
+        TARGET.store(usize::MAX, Ordering::Release);
+        assert_eq!(kthread::join(reader).unwrap(), 0);
+        TARGET.store(0, Ordering::Relaxed);
+        mmu.higher_l1.lock().unmap_range(va).unwrap();
+        frames.free_range(block).unwrap();
+        pages.free_range(area);
+    }
 }
@@ -0,0 +1,160 @@
+//! Secondary core bring-up through the spin table
+//!
+//! Both the Pi 4 armstub and QEMU's raspi firmware emulation park the secondary cores polling
+//! 0xd8 + 8 * core. Writing the physical address of __secondary_start there and issuing a SEV
+//! releases the core at EL2 with its MMU off. boot.s then brings it to EL1, enables the MMU on the
+//! shared kernel tables and enters secondary_main on the stack we left in SECONDARY_STACK_TOP.
+
+use crate::{
+    cpu::timer::TIMER,
+    errno::{ErrorCode, EINIT},
+    interrupt::IRQ_CONTROLLER,
+    memory::{address::*, config, *},
+    scheduler::SCHEDULER,
+};
+use aarch64_cpu::registers::*;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tock_registers::interfaces::Readable;
+
+pub const NUM_OF_CORES: usize = 4;
+pub const BOOT_CORE_ID: usize = 0;
+
+const CORE_ID_MASK: u64 = 0b11;
+const SPIN_TABLE_PA: usize = 0;
+const SPIN_TABLE_OFFSET: usize = 0xd8;
+const SECONDARY_STACK_PAGES: usize = 4;
+const BRING_UP_TIMEOUT: Duration = Duration::from_millis(100);
+
+extern "C" {
+    fn __secondary_start();
+}
+
+const NO_STACK: AtomicUsize = AtomicUsize::new(0);
+// read by __secondary_start
+#[no_mangle]
+static SECONDARY_STACK_TOP: [AtomicUsize; NUM_OF_CORES] = [NO_STACK; NUM_OF_CORES];
+
+// bit i is set once core i runs the scheduler
+static ONLINE: AtomicUsize = AtomicUsize::new(1 << BOOT_CORE_ID);
+
+#[inline(always)]
+pub fn core_id() -> usize {
+    (MPIDR_EL1.get() & CORE_ID_MASK) as usize
+}
+
+pub fn is_online(core: usize) -> bool {
+    ONLINE.load(Ordering::Acquire) & (1 << core) != 0
+}
+
+pub fn online_cores() -> usize {
+    ONLINE.load(Ordering::Acquire).count_ones() as usize
+}
+
+// the scheduler must be initialized, every secondary core starts in its idle loop
+pub fn start_secondary_cores() -> Result<(), ErrorCode> {
+    let mmu = MMU.get().unwrap();
+    let spin_table = mmu.map_physical(
+        PaRange::new(SPIN_TABLE_PA, SPIN_TABLE_PA + config::FRAME_SIZE),
+        RWNORMAL,
+    )?;
+    let entry = __secondary_start as usize - config::KERNEL_BASE;
+
+    for core in (0..NUM_OF_CORES).filter(|c| *c != BOOT_CORE_ID) {
+        let stack = mmu.allocate_stack(SECONDARY_STACK_PAGES)?;
+        SECONDARY_STACK_TOP[core].store(stack.end().value(), Ordering::Release);
+
+        let release = spin_table.va.start().value() + SPIN_TABLE_OFFSET + core * 8;
+        unsafe {
+            core::ptr::write_volatile(release as *mut u64, entry as u64);
+            // the secondary polls with its caches off
+            asm!("dc civac, {0}", "dsb sy", "sev", in(reg) release);
+        }
+
+        let timer = TIMER.get().unwrap();
+        let deadline = timer.now() + BRING_UP_TIMEOUT;
+        while !is_online(core) {
+            if timer.now() > deadline {
+                return Err(EINIT);
+            }
+            core::hint::spin_loop();
+        }
+    }
+    Ok(())
+}
+
+#[no_mangle]
+extern "C" fn secondary_main(core: usize) -> ! {
+    let sched = SCHEDULER.get().unwrap();
+    sched.init_secondary();
+    // the tick preempts the tasks of this core
+    IRQ_CONTROLLER.get().unwrap().init_secondary().unwrap();
+    TIMER.get().unwrap().enable();
+    ONLINE.fetch_or(1 << core, Ordering::Release);
+    sched.idle()
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::{kthread, scheduler::Task};
+    use test_macros::kernel_test;
+
+    fn report_core(expected: usize) -> i32 {
+        (core_id() == expected) as i32
+    }
+
+    #[kernel_test]
+    fn test_task_per_core() {
+        assert_eq!(online_cores(), NUM_OF_CORES);
+
+        let sched = SCHEDULER.get().unwrap();
+        let mut ids = [None; NUM_OF_CORES];
+        for core in 0..NUM_OF_CORES {
+            let t = Task::new(report_core, core).unwrap();
+            ids[core] = Some(sched.spawn_on(core, t));
+        }
+        for id in ids {
+            assert_eq!(kthread::join(id.unwrap()).unwrap(), 1);
+        }
+    }
+
+    static COUNTERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+    static STOP: AtomicUsize = AtomicUsize::new(0);
+
+    fn busy(i: usize) -> i32 {
+        while STOP.load(Ordering::Relaxed) == 0 {
+            COUNTERS[i].fetch_add(1, Ordering::Relaxed);
+        }
+        0
+    }
+
+    #[kernel_test]
+    fn test_secondary_preemption() {
+        let sched = SCHEDULER.get().unwrap();
+        let core = (BOOT_CORE_ID + 1) % NUM_OF_CORES;
+        let a = sched.spawn_on(core, Task::new(busy, 0).unwrap());
+        let b = sched.spawn_on(core, Task::new(busy, 1).unwrap());
+
+        // neither yields, so both only keep moving if the tick of that core preempts them
+        for _ in 0..4 {
+            let before = [
+                COUNTERS[0].load(Ordering::Relaxed),
+                COUNTERS[1].load(Ordering::Relaxed),
+            ];
+            while COUNTERS[0].load(Ordering::Relaxed) == before[0]
+                || COUNTERS[1].load(Ordering::Relaxed) == before[1]
+            {
+                core::hint::spin_loop();
+            }
+        }
+
+        STOP.store(1, Ordering::Relaxed);
+        kthread::join(a).unwrap();
+        kthread::join(b).unwrap();
+    }
+}
@@ -0,0 +1,21 @@
+use core::arch::asm;
+
+// issue `svc #0` with the number in x8 and the arguments in x0 - x5, the result comes back in x0
+#[inline(always)]
+pub fn syscall(nr: usize, args: [usize; 6]) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "svc #0",
+            inlateout("x0") args[0] => ret,
+            in("x1") args[1],
+            in("x2") args[2],
+            in("x3") args[3],
+            in("x4") args[4],
+            in("x5") args[5],
+            in("x8") nr,
+            options(nostack),
+        );
+    }
+    ret
+}
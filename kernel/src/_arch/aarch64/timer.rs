@@ -1,6 +1,20 @@
-use crate::{errno::ErrorCode, println, scheduler::*, synchronization::Spinlock};
+use crate::{
+    bsp::device_driver::interrupt_controller::CORE_PS_TIMER_IRQ,
+    cpu::smp::{core_id, BOOT_CORE_ID},
+    errno::*,
+    interrupt::IRQ_CONTROLLER,
+    println,
+    scheduler::*,
+    synchronization::{Spinlock, WaitQueue},
+};
 use aarch64_cpu::{asm::barrier, registers::*};
-use core::{arch::asm, num::NonZeroU64, ops::Div, time::Duration};
+use core::{
+    arch::asm,
+    num::NonZeroU64,
+    ops::Div,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 use spin::once::Once;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
@@ -11,6 +25,12 @@ static SYSTEM_COUNTER_FREQUENCY: NonZeroU64 = NonZeroU64::MIN;
 
 const NANOSEC_PER_SEC: NonZeroU64 = NonZeroU64::new(1_000_000_000).unwrap();
 
+// the period of the timer interrupt, which is also the scheduler tick
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+// the highest, the ticks drive the scheduler
+const TIMER_IRQ_PRIORITY: u8 = 0;
+
 pub fn system_counter_frequency() -> NonZeroU64 {
     unsafe { core::ptr::read_volatile(&SYSTEM_COUNTER_FREQUENCY) }
 }
@@ -55,27 +75,63 @@ impl From<GenericPhysicalCounter> for Duration {
 
 pub struct Timer {
     frequency: u64,
+    interval: AtomicU64, // in counter cycles
+    ticks: AtomicU64,
+    sleepers: WaitQueue,
 }
 
 impl Timer {
     fn new() -> Self {
-        Self {
+        let timer = Self {
             frequency: system_counter_frequency().get(),
-        }
+            interval: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            sleepers: WaitQueue::new(),
+        };
+        timer.set_interval(DEFAULT_TICK_INTERVAL);
+        timer
     }
 
     pub fn now(&self) -> Duration {
         Duration::from(GenericPhysicalCounter::read())
     }
 
+    // takes effect from the next time the timer is armed
+    pub fn set_interval(&self, interval: Duration) {
+        let cycles = (interval.as_nanos() * self.frequency as u128 / NANOSEC_PER_SEC.get() as u128)
+            .max(1) as u64;
+        self.interval.store(cycles, Ordering::Relaxed);
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from(GenericPhysicalCounter(
+            self.interval.load(Ordering::Relaxed),
+        ))
+    }
+
+    // number of timer interrupts handled so far
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    // block the current task for at least `duration`, rounded up to whole ticks
+    pub fn sleep(&self, duration: Duration) {
+        let n = duration
+            .as_nanos()
+            .div_ceil(self.interval().as_nanos().max(1))
+            .max(1) as u64;
+        let target = self.ticks() + n;
+        self.sleepers.wait_until(|| self.ticks() >= target);
+    }
+
     pub fn enable(&self) {
-        CNTP_TVAL_EL0.set(self.frequency);
+        CNTP_TVAL_EL0.set(self.interval.load(Ordering::Relaxed));
         CNTP_CTL_EL0.modify(CNTP_CTL_EL0::IMASK.val(0) + CNTP_CTL_EL0::ENABLE.val(1));
         barrier::isb(barrier::SY);
     }
 
     pub fn reset(&self) {
-        CNTP_TVAL_EL0.set(2 * self.frequency);
+        CNTP_TVAL_EL0.set(self.interval.load(Ordering::Relaxed));
         barrier::isb(barrier::SY);
     }
 
@@ -91,10 +147,28 @@ pub fn init() -> Result<(), ErrorCode> {
     Ok(())
 }
 
+// hooks the timer of the boot core, after the interrupt controller is initialized
+pub fn request_irq() -> Result<(), ErrorCode> {
+    IRQ_CONTROLLER.get().ok_or(EINIT)?.request_irq(
+        CORE_PS_TIMER_IRQ,
+        handle_interrupt,
+        TIMER_IRQ_PRIORITY,
+        0,
+    )
+}
+
 pub fn handle_interrupt() -> Result<(), ErrorCode> {
-    println!("handle timer");
+    let timer = TIMER.get().unwrap();
+    timer.reset();
+    // every core ticks its scheduler, only the boot core keeps the time
+    if core_id() == BOOT_CORE_ID {
+        timer.ticks.fetch_add(1, Ordering::Relaxed);
+        timer.sleepers.wake_all();
+    }
 
-    TIMER.get().unwrap().reset();
+    if let Some(sched) = SCHEDULER.get() {
+        sched.tick();
+    }
 
     Ok(())
 }
@@ -0,0 +1,108 @@
+//! Frame pointer backtraces
+//!
+//! The kernel is built with frame pointers, every frame holds a record of the caller's frame
+//! pointer and the return address. The walk follows these records up the stack and stops at the
+//! first one that is null, misaligned, not mapped or not above the previous one.
+
+#[cfg(target_arch = "aarch64")]
+#[path = "_arch/aarch64/backtrace.rs"]
+mod arch_backtrace;
+
+use crate::symbols;
+use core::fmt;
+
+const MAX_FRAMES: usize = 32;
+const RECORD_ALIGN: usize = 16;
+
+pub struct Backtrace {
+    pc: Option<usize>,
+    fp: usize,
+}
+
+impl Backtrace {
+    // from the frame of the caller
+    #[inline(always)]
+    pub fn here() -> Self {
+        Self {
+            pc: None,
+            fp: arch_backtrace::frame_pointer(),
+        }
+    }
+
+    // from `pc` in the frame `fp`, e.g. the instruction that took an exception
+    pub fn new(pc: usize, fp: usize) -> Self {
+        Self { pc: Some(pc), fp }
+    }
+
+    // the address of every call on the stack, innermost first
+    pub fn frames(&self) -> Frames {
+        Frames {
+            pc: self.pc,
+            fp: self.fp,
+            depth: 0,
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, pc) in self.frames().enumerate() {
+            match symbols::lookup(pc) {
+                Some(symbol) => writeln!(f, "  {:>2}: {:#018x} - {}", i, pc, symbol)?,
+                None => writeln!(f, "  {:>2}: {:#018x} - <unknown>", i, pc)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Frames {
+    pc: Option<usize>,
+    fp: usize,
+    depth: usize,
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if let Some(pc) = self.pc.take() {
+            return Some(pc);
+        }
+        if self.depth == MAX_FRAMES
+            || self.fp == 0
+            || self.fp % RECORD_ALIGN != 0
+            || !arch_backtrace::is_readable(self.fp)
+        {
+            return None;
+        }
+        let record = self.fp as *const usize;
+        let (fp, lr) = unsafe { (record.read(), record.add(1).read()) };
+        if lr < arch_backtrace::CALL_SIZE {
+            return None;
+        }
+        // the stack grows down, a record at or below this one means the chain is broken
+        self.fp = if fp > self.fp { fp } else { 0 };
+        self.depth += 1;
+        // the call, not the instruction after it, which may already belong to the next function
+        Some(lr - arch_backtrace::CALL_SIZE)
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    #[kernel_test]
+    fn test_backtrace() {
+        // at least the test runner is above this frame
+        assert!(Backtrace::here().frames().count() > 0);
+
+        let mut frames = Backtrace::new(0x1234, 0).frames();
+        assert_eq!(frames.next(), Some(0x1234));
+        assert_eq!(frames.next(), None);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod gpio;
 pub mod mini_uart;
+pub mod pl011_uart;
 mod utils;
 
 #[cfg(feature = "build_qemu")]
@@ -1,10 +1,18 @@
 //! PI3 BCM Interrupt controller
+//!
+//! The legacy controller collects the ARM and GPU peripheral interrupts and raises them as the GPU
+//! interrupt of one core. The per core local interrupt controller of the BCM2836 in front of it
+//! also takes the core timers, so the generic timer reaches the kernel through it.
 
 use crate::{
     bsp::mmio,
-    cpu::timer::TIMER,
+    cpu::{
+        smp::{core_id, BOOT_CORE_ID, NUM_OF_CORES},
+        timer::TIMER,
+    },
+    exception,
     interrupt::IRQController,
-    memory::{config, MMIOWrapper},
+    memory::{address::PaRange, config, MMIOWrapper, MMU, RWDEVICE},
 };
 
 use tock_registers::{
@@ -13,10 +21,10 @@ use tock_registers::{
     registers::{ReadOnly, ReadWrite, WriteOnly},
 };
 
-use crate::{errno::ErrorCode, synchronization::IRQSafeSpinlock};
+use crate::errno::*;
 use aarch64_cpu::registers::*;
 
-const IC_VIRTUAL_START: usize = config::VIRTUAL_PERIPHERAL_START + mmio::IC_OFFSET;
+const IC_PHYSICAL_START: usize = config::PHYSICAL_PERIPHERAL_START + mmio::IC_OFFSET;
 
 register_bitfields!(u32,
     FIQControl[
@@ -86,6 +94,30 @@ register_bitfields!(u32,
         ],
     ],
 
+    CoreTimerIRQ [
+        CNTPSIRQ OFFSET(0) NUMBITS(1) [],
+        CNTPNSIRQ OFFSET(1) NUMBITS(1) [],
+        CNTHPIRQ OFFSET(2) NUMBITS(1) [],
+        CNTVIRQ OFFSET(3) NUMBITS(1) [],
+    ],
+
+    CoreIRQSource [
+        CNTPSIRQ OFFSET(0) NUMBITS(1) [],
+        CNTPNSIRQ OFFSET(1) NUMBITS(1) [],
+        CNTHPIRQ OFFSET(2) NUMBITS(1) [],
+        CNTVIRQ OFFSET(3) NUMBITS(1) [],
+        Mailbox OFFSET(4) NUMBITS(4) [],
+        GPU OFFSET(8) NUMBITS(1) [],
+        PMU OFFSET(9) NUMBITS(1) [],
+        AXI OFFSET(10) NUMBITS(1) [],
+        LocalTimer OFFSET(11) NUMBITS(1) [],
+    ],
+
+    GPURouting [
+        IRQ OFFSET(0) NUMBITS(2) [],
+        FIQ OFFSET(2) NUMBITS(2) [],
+    ],
+
     BasicIRQ [
          ARMTimer OFFSET(0) NUMBITS(1) [],
          ARMMailBox OFFSET(1) NUMBITS(1) [],
@@ -123,22 +155,91 @@ register_structs!(
     }
 );
 
+register_structs!(
+    #[allow(non_snake_case)]
+    LocalRegisterBlock {
+        (0x000 => _reserved1),
+        (0x00C => GPURouting: ReadWrite<u32, GPURouting::Register>),
+        (0x010 => _reserved2),
+        (0x040 => CoreTimerIRQ: [ReadWrite<u32, CoreTimerIRQ::Register>; NUM_OF_CORES]),
+        (0x050 => _reserved3),
+        (0x060 => CoreIRQSource: [ReadOnly<u32, CoreIRQSource::Register>; NUM_OF_CORES]),
+        (0x070 => @END),
+    }
+);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum IRQNum {
+    // the bits of the basic registers
     ARM(u8),
+    // 0-31 are in the 1 registers, 32-63 in the 2 registers
     GPU(u8),
+    // the core timers of the local interrupt controller
+    Local(u8),
+}
+
+impl IRQNum {
+    // the core timers have one route per core
+    pub fn is_per_core(&self) -> bool {
+        matches!(self, IRQNum::Local(_))
+    }
 }
 
+// CNTPNSIRQ of the local interrupt controller
+pub const CORE_PS_TIMER_IRQ: IRQNum = IRQNum::Local(1);
+
+const NUM_ARM_IRQS: u8 = 8;
+const NUM_GPU_IRQS: u8 = 64;
+const NUM_LOCAL_IRQS: u8 = 4;
+const ARM_IRQ_MASK: u32 = (1 << NUM_ARM_IRQS) - 1;
+const LOCAL_IRQ_MASK: u32 = (1 << NUM_LOCAL_IRQS) - 1;
+
 pub struct BCMIC {
     ro_reg: MMIOWrapper<RORegisterBlock>,
-    rw_reg: IRQSafeSpinlock<MMIOWrapper<RWRegisterBlock>>,
+    // only touched with the lock of the generic controller held
+    rw_reg: MMIOWrapper<RWRegisterBlock>,
+    local: MMIOWrapper<LocalRegisterBlock>,
+}
+
+// under build_qemu the virtual peripheral addresses are the physical ones, which are unmapped once
+// the kernel runs in the higher half
+fn map_registers(pa: usize) -> usize {
+    let frame = pa & config::ALIGN_4K;
+    let mapped = MMU
+        .get()
+        .unwrap()
+        .map_physical(PaRange::new(frame, frame + config::PAGE_SIZE), RWDEVICE)
+        .unwrap();
+    mapped.va.start().value() + (pa - frame)
+}
+
+fn lowest_bit(bits: u32) -> Option<u8> {
+    (bits != 0).then(|| bits.trailing_zeros() as u8)
 }
 
 impl BCMIC {
     fn new() -> Self {
+        let ic = map_registers(IC_PHYSICAL_START);
         Self {
-            ro_reg: MMIOWrapper::new(IC_VIRTUAL_START),
-            rw_reg: IRQSafeSpinlock::new(MMIOWrapper::new(IC_VIRTUAL_START)),
+            ro_reg: MMIOWrapper::new(ic),
+            rw_reg: MMIOWrapper::new(ic),
+            local: MMIOWrapper::new(map_registers(mmio::LOCAL_IC_START)),
+        }
+    }
+
+    fn set_enabled(&mut self, irq: IRQNum, enabled: bool) -> Result<(), ErrorCode> {
+        let rw_reg = &self.rw_reg;
+        // the enable and disable registers only act on the bits written as 1
+        match irq {
+            IRQNum::ARM(n) if n < NUM_ARM_IRQS && enabled => rw_reg.EnableBasic.set(1 << n),
+            IRQNum::ARM(n) if n < NUM_ARM_IRQS => rw_reg.DisableBasic.set(1 << n),
+            IRQNum::GPU(n) if n < 32 && enabled => rw_reg.Enable1.set(1 << n),
+            IRQNum::GPU(n) if n < 32 => rw_reg.Disable1.set(1 << n),
+            IRQNum::GPU(n) if n < NUM_GPU_IRQS && enabled => rw_reg.Enable2.set(1 << (n - 32)),
+            IRQNum::GPU(n) if n < NUM_GPU_IRQS => rw_reg.Disable2.set(1 << (n - 32)),
+            _ => return Err(EPARAM),
         }
+        Ok(())
     }
 }
 
@@ -148,11 +249,102 @@ pub fn create() -> BCMIC {
 
 impl IRQController for BCMIC {
     fn init(&mut self) -> Result<(), ErrorCode> {
+        exception::local_irq_mask();
+
+        // nothing is enabled until request_irq
+        self.rw_reg.FIQControl.set(0);
+        self.rw_reg.Disable1.set(u32::MAX);
+        self.rw_reg.Disable2.set(u32::MAX);
+        self.rw_reg.DisableBasic.set(u32::MAX);
+        for timer in self.local.CoreTimerIRQ.iter() {
+            timer.set(0);
+        }
+        // the legacy controller interrupts the boot core
+        self.local
+            .GPURouting
+            .write(GPURouting::IRQ.val(BOOT_CORE_ID as u32) + GPURouting::FIQ.val(0));
+
+        exception::local_irq_unmask();
         Ok(())
     }
-    fn handle(&self) -> Result<(), ErrorCode> {
+
+    // init already cleared the routes of every core
+    fn init_secondary(&mut self) -> Result<(), ErrorCode> {
         Ok(())
     }
+
+    // the local sources come first, then the basic, 1 and 2 pending registers. the GPU irqs
+    // repeated in the upper basic bits are found in the 1 and 2 registers
+    fn acknowledge(&self) -> Option<IRQNum> {
+        let source = &self.local.CoreIRQSource[core_id()];
+        if let Some(n) = lowest_bit(source.get() & LOCAL_IRQ_MASK) {
+            return Some(IRQNum::Local(n));
+        }
+        if !source.is_set(CoreIRQSource::GPU) {
+            return None;
+        }
+        let basic = self.ro_reg.BasicPending.get() & self.rw_reg.EnableBasic.get();
+        if let Some(n) = lowest_bit(basic & ARM_IRQ_MASK) {
+            return Some(IRQNum::ARM(n));
+        }
+        if let Some(n) = lowest_bit(self.ro_reg.Pending1.get() & self.rw_reg.Enable1.get()) {
+            return Some(IRQNum::GPU(n));
+        }
+        lowest_bit(self.ro_reg.Pending2.get() & self.rw_reg.Enable2.get())
+            .map(|n| IRQNum::GPU(n + 32))
+    }
+
+    // the sources are level triggered, the handler clears them at the device
+    fn end(&self, irq: IRQNum) {}
+
+    // there are no priorities, and only the core timers can go to other cores than the boot one
+    fn enable(&mut self, irq: IRQNum, priority: u8, target_cpu: u8) -> Result<(), ErrorCode> {
+        match irq {
+            IRQNum::Local(n) if n < NUM_LOCAL_IRQS && (target_cpu as usize) < NUM_OF_CORES => {
+                let timer = &self.local.CoreTimerIRQ[target_cpu as usize];
+                timer.set(timer.get() | 1 << n);
+                Ok(())
+            }
+            IRQNum::Local(_) => Err(EPARAM),
+            _ if target_cpu as usize != BOOT_CORE_ID => Err(ESUPPORTED),
+            _ => self.set_enabled(irq, true),
+        }
+    }
+
+    fn disable(&mut self, irq: IRQNum) -> Result<(), ErrorCode> {
+        match irq {
+            // on every core, the core it was enabled on is not known here
+            IRQNum::Local(n) if n < NUM_LOCAL_IRQS => {
+                for timer in self.local.CoreTimerIRQ.iter() {
+                    timer.set(timer.get() & !(1 << n));
+                }
+                Ok(())
+            }
+            IRQNum::Local(_) => Err(EPARAM),
+            _ => self.set_enabled(irq, false),
+        }
+    }
 }
 unsafe impl Send for BCMIC {}
 unsafe impl Sync for BCMIC {}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    #[kernel_test]
+    fn test_core_timer_irq() {
+        let timer = TIMER.get().unwrap();
+        let ticks = timer.ticks();
+
+        exception::local_irq_unmask();
+        timer.enable();
+        // only moves if the interrupt makes it through the local controller to the handler
+        while timer.ticks() < ticks + 2 {
+            core::hint::spin_loop();
+        }
+        timer.disable();
+    }
+}
@@ -2,12 +2,10 @@
 extern crate alloc;
 use crate::{
     bsp::mmio,
-    cpu::{timer, timer::TIMER},
-    errno::{ErrorCode, ESUPPORTED},
+    errno::{ErrorCode, EPARAM},
     exception,
     interrupt::IRQController,
     memory::{config, MMIOWrapper},
-    println,
     utils::bitfields::Bitfields,
 };
 use alloc::boxed::Box;
@@ -215,7 +213,7 @@ const GICC_OFFSET: usize = 0xFF842000 - config::PHYSICAL_PERIPHERAL_START;
 const GICD_VIRTUAL_START: usize = config::VIRTUAL_PERIPHERAL_START + GICD_OFFSET;
 const GICC_VIRTUAL_START: usize = config::VIRTUAL_PERIPHERAL_START + GICC_OFFSET;
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum IRQNum {
     PPI(u32),
     SPI(u32),
@@ -238,21 +236,17 @@ impl IRQNum {
             IRQNum::SPI(u) => u,
         }
     }
-}
-
-const CORE_PS_TIMER_IRQ: IRQNum = IRQNum::PPI(30);
 
-pub struct IRQDescriptor {
-    num: IRQNum,
-    name: &'static str,
-    handleFn: fn() -> Result<(), ErrorCode>,
+    // banked, every core enables its own
+    pub fn is_per_core(&self) -> bool {
+        matches!(self, IRQNum::PPI(_))
+    }
 }
 
-static GIC400_HANDLER: [IRQDescriptor; 1] = [IRQDescriptor {
-    num: CORE_PS_TIMER_IRQ,
-    name: "Core Physical Timer",
-    handleFn: timer::handle_interrupt,
-}];
+pub const CORE_PS_TIMER_IRQ: IRQNum = IRQNum::PPI(30);
+const SPURIOUS_IRQ: u32 = 1023;
+const NUM_IRQS: u32 = 256;
+
 pub struct GIC400 {
     gicd: MMIOWrapper<GICDRegisterBlock>,
     gicc: MMIOWrapper<GICCRegisterBlock>,
@@ -300,12 +294,10 @@ impl GIC400 {
         enabled.set_bit(offset, 1);
         self.gicd.ISEnable[idx].set(enabled);
     }
-
-    fn dispatch(&self, irq: IRQNum) -> Result<(), ErrorCode> {
-        match irq {
-            CORE_PS_TIMER_IRQ => (GIC400_HANDLER[0].handleFn)(),
-            _ => Err(ESUPPORTED),
-        }
+    fn disable(&mut self, irq: &IRQNum) {
+        let (idx, offset) = to_enable(irq.value());
+        // writing 0 has no effect, only the bit of irq is cleared
+        self.gicd.ICEnable[idx].set(1 << offset);
     }
 }
 
@@ -346,12 +338,8 @@ impl IRQController for GIC400 {
             iprio.set(0xF0_F0_F0_F0);
         }
 
-        self.set_priority(&CORE_PS_TIMER_IRQ, P0);
-
-        self.enable(&CORE_PS_TIMER_IRQ);
-
-        // mask all interrupts other than ones with the highest priority
-        self.gicc.Pmr.modify(GICC_PMR::Priority::P1);
+        // let through every priority above the lowest one, irqs are enabled by request_irq
+        self.gicc.Pmr.modify(GICC_PMR::Priority::P15);
 
         // enable GICD and GICC
         self.gicd.Ctlr.modify(GICD_CTLR::EnableGrp0::forwarded);
@@ -361,17 +349,54 @@ impl IRQController for GIC400 {
         Ok(())
     }
 
-    fn handle(&self) -> Result<(), ErrorCode> {
-        let iar = self.gicc.Iar.get();
-        let interrupt_id = iar & 0b11111111111;
-        let cpu_id = (iar >> 10) & 0b111;
-        println!("IRQ ID{}, CPU ID{}", interrupt_id, cpu_id);
-        let result = self.dispatch(IRQNum::from(interrupt_id));
+    fn init_secondary(&mut self) -> Result<(), ErrorCode> {
+        // the distributor registers of SGIs and PPIs are banked per core
+        self.gicd.Group[0].set(0);
+        self.gicd.ICEnable[0].set(0xFFFF_FFFF);
+        self.gicd.ICPend[0].set(0xFFFF_FFFF);
+        self.gicd.ICActive[0].set(0xFFFF_FFFF);
+        for iprio in self.gicd.Priority[..8].iter() {
+            iprio.set(0xF0_F0_F0_F0);
+        }
 
-        self.gicc
-            .Eoir
-            .write(GICC_EOIR::EOIINTID.val(interrupt_id) + GICC_EOIR::CPUID.val(cpu_id));
-        result
+        self.gicc.Pmr.modify(GICC_PMR::Priority::P15);
+        self.gicc.Ctlr.modify(GICC_CTLR::EnableGrp0::forwarded);
+        Ok(())
+    }
+
+    fn acknowledge(&self) -> Option<IRQNum> {
+        let interrupt_id = self.gicc.Iar.read(GICC_IAR::InterruptID);
+        if interrupt_id == SPURIOUS_IRQ {
+            return None;
+        }
+        Some(IRQNum::from(interrupt_id))
+    }
+
+    // SGIs are never enabled, so the CPUID of the source can stay 0
+    fn end(&self, irq: IRQNum) {
+        self.gicc.Eoir.write(GICC_EOIR::EOIINTID.val(irq.value()));
+    }
+
+    fn enable(&mut self, irq: IRQNum, priority: u8, target_cpu: u8) -> Result<(), ErrorCode> {
+        if irq.value() >= NUM_IRQS || target_cpu >= 8 {
+            return Err(EPARAM);
+        }
+        // priorities use the upper 4 bits, the lowest one is left for the mask
+        self.set_priority(&irq, priority.min(14) << 4);
+        // read only for PPIs, they always go to the core that owns them
+        if let IRQNum::SPI(_) = irq {
+            self.set_target_cpu(&irq, target_cpu);
+        }
+        self.enable(&irq);
+        Ok(())
+    }
+
+    fn disable(&mut self, irq: IRQNum) -> Result<(), ErrorCode> {
+        if irq.value() >= NUM_IRQS {
+            return Err(EPARAM);
+        }
+        self.disable(&irq);
+        Ok(())
     }
 }
 unsafe impl Send for GIC400 {}
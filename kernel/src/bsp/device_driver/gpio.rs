@@ -18,6 +18,18 @@ const GPIO_VIRTUAL_START: usize = config::VIRTUAL_PERIPHERAL_START + mmio::GPIO_
 register_bitfields! {
     u32,
 
+    GPFSEL0 [
+        FSEL5 OFFSET(15) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            AltFunc4 = 0b011
+        ],
+        FSEL4 OFFSET(12) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            AltFunc4 = 0b011
+        ],
+    ],
 
     GPFSEL1 [
         FSEL15 OFFSET(15) NUMBITS(3) [
@@ -48,6 +60,16 @@ register_bitfields! {
             PullUp = 0b01,
             PullDown = 0b10
         ],
+        GPIO_PUP_PDN_CNTRL5 OFFSET(10) NUMBITS(2)[
+            NoResistor = 0b00,
+            PullUp = 0b01,
+            PullDown = 0b10
+        ],
+        GPIO_PUP_PDN_CNTRL4 OFFSET(8) NUMBITS(2)[
+            NoResistor = 0b00,
+            PullUp = 0b01,
+            PullDown = 0b10
+        ],
 
     ],
 
@@ -57,7 +79,7 @@ register_structs! {
     #[allow(non_snake_case)]
     RegisterBlock{
 
-        (0x00 => _reserved1),
+        (0x00 => GPFSEL0: ReadWrite<u32, GPFSEL0::Register>),
         (0x04 => GPFSEL1: ReadWrite<u32, GPFSEL1::Register>),
         (0x08 => _reserved2),
         (0xE4 => GPIO_PUP_PDN_CNTRL_REG0: ReadWrite<u32, GPIO_PUP_PDN_CNTRL_REG0::Register>),
@@ -93,6 +115,17 @@ impl UnsafeGPIO {
             .GPFSEL1
             .modify(GPFSEL1::FSEL14::AltFunc5 + GPFSEL1::FSEL15::AltFunc5);
     }
+
+    // UART3 of the pi 4 for the GDB stub
+    fn init_debug_uart(&self) {
+        self.registers.GPIO_PUP_PDN_CNTRL_REG0.modify(
+            GPIO_PUP_PDN_CNTRL_REG0::GPIO_PUP_PDN_CNTRL4::PullUp
+                + GPIO_PUP_PDN_CNTRL_REG0::GPIO_PUP_PDN_CNTRL5::PullUp,
+        );
+        self.registers
+            .GPFSEL0
+            .modify(GPFSEL0::FSEL4::AltFunc4 + GPFSEL0::FSEL5::AltFunc4);
+    }
 }
 
 impl GPIOController {
@@ -105,6 +138,10 @@ impl GPIOController {
     fn init(&self) {
         self.inner.lock().init()
     }
+
+    pub fn init_debug_uart(&self) {
+        self.inner.lock().init_debug_uart()
+    }
 }
 
 pub static GPIO: Once<GPIOController> = Once::new();
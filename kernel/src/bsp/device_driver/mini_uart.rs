@@ -130,7 +130,7 @@ impl UnSafeMiniUart {
         }
     }
 
-    fn init(&mut self) {
+    pub fn init(&mut self) {
         self.reg.ENABLES.modify(AUX_ENABLES::MINI_UART.val(1)); // m-uart needs to be enabled for
                                                                 // accessing its reg. GPIO should be set up beforehand
         self.reg
@@ -169,14 +169,14 @@ impl UnSafeMiniUart {
         self.reg.STAT.is_set(AUX_MU_STAT_REG::TRANSMITTER_DONE)
     }
 
-    fn send_byte(&mut self, b: u8) {
+    pub fn send_byte(&mut self, b: u8) {
         while !self.is_writeable() {
             nop();
         }
         self.reg.IO.modify(AUX_MU_IO_REG::DATA.val(b as u32));
     }
 
-    fn read_byte(&mut self) -> u8 {
+    pub fn read_byte(&mut self) -> u8 {
         while !self.is_readable() {
             nop();
         }
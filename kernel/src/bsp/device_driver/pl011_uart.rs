@@ -0,0 +1,111 @@
+use crate::{
+    bsp::mmio,
+    cpu::nop,
+    memory::{config, MMIOWrapper},
+};
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+// a PL011 of its own for the GDB stub, the console keeps the mini UART
+pub const VIRTUAL_DEBUG_UART_START: usize =
+    config::VIRTUAL_PERIPHERAL_START + mmio::DEBUG_UART_OFFSET;
+
+register_bitfields!(u32,
+    DR[
+        DATA OFFSET(0) NUMBITS(8) [],
+    ],
+    FR[
+        BUSY OFFSET(3) NUMBITS(1) [],
+        RXFE OFFSET(4) NUMBITS(1) [],
+        TXFF OFFSET(5) NUMBITS(1) [],
+    ],
+    IBRD[
+        BAUD_DIVINT OFFSET(0) NUMBITS(16) [],
+    ],
+    FBRD[
+        BAUD_DIVFRAC OFFSET(0) NUMBITS(6) [],
+    ],
+    LCR_H[
+        FEN OFFSET(4) NUMBITS(1) [],
+        WLEN OFFSET(5) NUMBITS(2) [
+            EightBit = 0b11
+        ],
+    ],
+    CR[
+        UARTEN OFFSET(0) NUMBITS(1) [],
+        TXE OFFSET(8) NUMBITS(1) [],
+        RXE OFFSET(9) NUMBITS(1) [],
+    ],
+);
+
+register_structs! {
+    RegisterBlock{
+        (0x00 => DR: ReadWrite<u32, DR::Register>),
+        (0x04 => _reserved1),
+        (0x18 => FR: ReadOnly<u32, FR::Register>),
+        (0x1C => _reserved2),
+        (0x24 => IBRD: WriteOnly<u32, IBRD::Register>),
+        (0x28 => FBRD: WriteOnly<u32, FBRD::Register>),
+        (0x2C => LCR_H: ReadWrite<u32, LCR_H::Register>),
+        (0x30 => CR: ReadWrite<u32, CR::Register>),
+        (0x34 => _reserved3),
+        (0x38 => IMSC: ReadWrite<u32>),
+        (0x3C => _reserved4),
+        (0x44 => ICR: WriteOnly<u32>),
+        (0x48 => @END),
+    }
+}
+
+// the firmware's default UART clock
+const CLOCK: u32 = 48_000_000;
+const BAUD_RATE: u32 = 115200;
+
+pub struct UnSafePl011Uart {
+    reg: MMIOWrapper<RegisterBlock>,
+}
+
+impl UnSafePl011Uart {
+    pub fn new(mmio_start_addr: usize) -> Self {
+        Self {
+            reg: MMIOWrapper::new(mmio_start_addr),
+        }
+    }
+
+    // 8N1 without interrupts. the divisor is CLOCK / (16 * BAUD_RATE) with a fraction in 64ths
+    pub fn init(&mut self) {
+        self.reg.CR.set(0);
+        while self.reg.FR.is_set(FR::BUSY) {
+            nop();
+        }
+        self.reg.LCR_H.set(0); // flushes the FIFOs
+        self.reg.ICR.set(0x7FF);
+        self.reg.IMSC.set(0);
+
+        let divisor = (CLOCK * 4 + BAUD_RATE / 2) / BAUD_RATE;
+        self.reg.IBRD.write(IBRD::BAUD_DIVINT.val(divisor >> 6));
+        self.reg.FBRD.write(FBRD::BAUD_DIVFRAC.val(divisor & 0x3F));
+        self.reg
+            .LCR_H
+            .write(LCR_H::WLEN::EightBit + LCR_H::FEN.val(1));
+        self.reg
+            .CR
+            .write(CR::UARTEN.val(1) + CR::TXE.val(1) + CR::RXE.val(1));
+    }
+
+    pub fn send_byte(&mut self, b: u8) {
+        while self.reg.FR.is_set(FR::TXFF) {
+            nop();
+        }
+        self.reg.DR.write(DR::DATA.val(b as u32));
+    }
+
+    pub fn read_byte(&mut self) -> u8 {
+        while self.reg.FR.is_set(FR::RXFE) {
+            nop();
+        }
+        self.reg.DR.read(DR::DATA) as u8
+    }
+}
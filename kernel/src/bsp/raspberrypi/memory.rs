@@ -28,13 +28,17 @@ pub mod mmio {
     use super::*;
 
     pub const PHYSICAL_PERIPHERAL_START: usize = 0x3F00_0000;
-    pub const PHYSICAL_MEMORY_END_INCLUSIVE: usize = 0x3FFF_FFFF;
-    pub const PHYSICAL_MEMORY_END_EXCLUSIVE: usize = PHYSICAL_MEMORY_END_INCLUSIVE + 1;
+    // the frame bitmaps cover this much, the device tree tells what is there
+    pub const PHYSICAL_MEMORY_LIMIT: usize = 0x4000_0000;
 
     pub const GPIO_OFFSET: usize = 0x0020_0000;
     pub const UART_OFFSET: usize = 0x0020_1000;
     pub const MINI_UART_OFFSET: usize = 0x0021_5000;
+    // UART0, the first serial port of QEMU
+    pub const DEBUG_UART_OFFSET: usize = UART_OFFSET;
     pub const IC_OFFSET: usize = 0x3F00B200 - PHYSICAL_PERIPHERAL_START;
+    // the BCM2836 local interrupt controller, right after the peripherals
+    pub const LOCAL_IC_START: usize = 0x4000_0000;
 }
 
 #[cfg(any(feature = "bsp_rpi4", feature = "build_chainloader"))]
@@ -42,11 +46,13 @@ pub mod mmio {
     use super::*;
 
     pub const PHYSICAL_PERIPHERAL_START: usize = 0xFE00_0000;
-    pub const PHYSICAL_MEMORY_END_INCLUSIVE: usize = 0xFFFF_FFFF; // we assume pi4 has 4GB memory
-    pub const PHYSICAL_MEMORY_END_EXCLUSIVE: usize = PHYSICAL_MEMORY_END_INCLUSIVE + 1;
+    // the largest pi4, the device tree tells what is there
+    pub const PHYSICAL_MEMORY_LIMIT: usize = 0x2_0000_0000;
 
     pub const GPIO_OFFSET: usize = 0x0020_0000;
     pub const UART_OFFSET: usize = 0x0020_1000;
     pub const MINI_UART_OFFSET: usize = 0x0021_5000;
+    // UART3 on GPIO 4 and 5, GPIO 14 and 15 carry the mini UART
+    pub const DEBUG_UART_OFFSET: usize = 0x0020_1600;
     pub const IC_OFFSET: usize = 0xFF840000 - PHYSICAL_PERIPHERAL_START;
 }
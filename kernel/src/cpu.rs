@@ -15,6 +15,12 @@ pub mod arch_timer;
 
 pub use arch_timer as timer;
 
+#[cfg(target_arch = "aarch64")]
+#[path = "_arch/aarch64/smp.rs"]
+pub mod arch_smp;
+
+pub use arch_smp as smp;
+
 #[cfg(feature = "build_qemu")]
 pub use arch_cpu::spin_for_cycles;
 
@@ -0,0 +1,414 @@
+//! ELF64 executables for EL0
+//!
+//! Only statically linked AArch64 executables are supported. Every PT_LOAD segment is mapped into a
+//! fresh address space with the permissions of its flags, and the entry point starts on a user
+//! stack laid out as the SysV ABI expects: argc, argv, envp and the auxiliary vector, with the
+//! strings right above them.
+extern crate alloc;
+use crate::{
+    errno::*,
+    exception,
+    memory::{address::*, address_space::AddressSpace, config, *},
+    scheduler::{Task, TaskId, SCHEDULER},
+};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use nom::{
+    bytes::complete::take,
+    error::Error,
+    number::complete::{le_u16, le_u32, le_u64},
+    Finish, IResult,
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+const PHDR_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// auxiliary vector entries
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+// below the recursive window at the top of the lower half
+const USER_STACK_TOP: usize = 0x7F_0000_0000;
+const USER_STACK_PAGES: usize = 16;
+
+type ParserResult<'a, T> = IResult<&'a [u8], T, Error<&'a [u8]>>;
+
+struct ElfHeader {
+    e_type: u16,
+    machine: u16,
+    entry: usize,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct ProgramHeader {
+    p_type: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+impl ProgramHeader {
+    // writable and executable at the same time is refused
+    fn memory_type(&self) -> Result<&'static MemoryType, ErrorCode> {
+        match (self.flags & PF_W != 0, self.flags & PF_X != 0) {
+            (true, true) => Err(ESUPPORTED),
+            (true, false) => Ok(RWNORMAL),
+            (false, true) => Ok(XNORMAL),
+            (false, false) => Ok(RONORMAL),
+        }
+    }
+
+    // the pages covering the segment in memory, parse made sure the end can't overflow
+    fn pages(&self) -> VaRange {
+        let start = self.vaddr & config::ALIGN_4K;
+        let end = (self.vaddr + self.memsz + config::MASK_4K) & config::ALIGN_4K;
+        VaRange::new(start, end)
+    }
+}
+
+fn parse_ident(input: &[u8]) -> Result<&[u8], ErrorCode> {
+    let (input, ident) = take(16usize)(input)
+        .finish()
+        .map_err(|_: Error<&[u8]>| EINVAL)?;
+    if ident[0..4] != ELF_MAGIC {
+        return Err(EINVAL);
+    }
+    if ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB {
+        return Err(ESUPPORTED);
+    }
+    Ok(input)
+}
+
+fn parse_header(input: &[u8]) -> ParserResult<'_, ElfHeader> {
+    let (input, e_type) = le_u16(input)?;
+    let (input, machine) = le_u16(input)?;
+    let (input, _version) = le_u32(input)?;
+    let (input, entry) = le_u64(input)?;
+    let (input, phoff) = le_u64(input)?;
+    let (input, _shoff) = le_u64(input)?;
+    let (input, _flags) = le_u32(input)?;
+    let (input, _ehsize) = le_u16(input)?;
+    let (input, phentsize) = le_u16(input)?;
+    let (input, phnum) = le_u16(input)?;
+    Ok((
+        input,
+        ElfHeader {
+            e_type,
+            machine,
+            entry: entry as usize,
+            phoff: phoff as usize,
+            phentsize: phentsize as usize,
+            phnum: phnum as usize,
+        },
+    ))
+}
+
+fn parse_program_header(input: &[u8]) -> ParserResult<'_, ProgramHeader> {
+    let (input, p_type) = le_u32(input)?;
+    let (input, flags) = le_u32(input)?;
+    let (input, offset) = le_u64(input)?;
+    let (input, vaddr) = le_u64(input)?;
+    let (input, _paddr) = le_u64(input)?;
+    let (input, filesz) = le_u64(input)?;
+    let (input, memsz) = le_u64(input)?;
+    let (input, _align) = le_u64(input)?;
+    Ok((
+        input,
+        ProgramHeader {
+            p_type,
+            flags,
+            offset: offset as usize,
+            vaddr: vaddr as usize,
+            filesz: filesz as usize,
+            memsz: memsz as usize,
+        },
+    ))
+}
+
+pub struct Elf<'a> {
+    image: &'a [u8],
+    header: ElfHeader,
+    program_headers: Vec<ProgramHeader>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, ErrorCode> {
+        let input = parse_ident(image)?;
+        let (_, header) = parse_header(input)
+            .finish()
+            .map_err(|_: Error<&[u8]>| EINVAL)?;
+        if header.e_type != ET_EXEC || header.machine != EM_AARCH64 {
+            return Err(ESUPPORTED);
+        }
+        if header.phentsize < PHDR_SIZE {
+            return Err(EINVAL);
+        }
+
+        let mut program_headers = Vec::with_capacity(header.phnum);
+        for i in 0..header.phnum {
+            let offset = i
+                .checked_mul(header.phentsize)
+                .and_then(|offset| offset.checked_add(header.phoff))
+                .ok_or(EBOUND)?;
+            let ph = image.get(offset..).ok_or(EBOUND)?;
+            let (_, ph) = parse_program_header(ph)
+                .finish()
+                .map_err(|_: Error<&[u8]>| EBOUND)?;
+            if ph.p_type == PT_LOAD {
+                let file_end = ph.offset.checked_add(ph.filesz).ok_or(EOVERFLOW)?;
+                let mem_end = ph.vaddr.checked_add(ph.memsz).ok_or(EOVERFLOW)?;
+                if file_end > image.len() || ph.filesz > ph.memsz {
+                    return Err(EBOUND);
+                }
+                if !VirtualAddress::from(mem_end).is_lower() || mem_end > USER_STACK_TOP {
+                    return Err(EPARAM);
+                }
+            }
+            program_headers.push(ph);
+        }
+
+        Ok(Self {
+            image,
+            header,
+            program_headers,
+        })
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header.entry
+    }
+
+    pub fn loads(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+    }
+
+    // where the program headers end up in memory, if some segment loads them
+    fn phdr_address(&self) -> Option<usize> {
+        let phoff = self.header.phoff;
+        self.loads()
+            .find(|ph| ph.offset <= phoff && phoff < ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + phoff - ph.offset)
+    }
+
+    // map every PT_LOAD segment, `mm` must be active on this core
+    pub fn load(&self, mm: &AddressSpace) -> Result<(), ErrorCode> {
+        for ph in self.loads() {
+            let data = &self.image[ph.offset..ph.offset + ph.filesz];
+            mm.map_pages(ph.pages(), ph.memory_type()?, |page, frame| {
+                // the file data falling into this page, the rest stays zero
+                let page = page.value();
+                let start = page.max(ph.vaddr);
+                let end = (page + config::PAGE_SIZE).min(ph.vaddr + ph.filesz);
+                if start < end {
+                    frame[start - page..end - page]
+                        .copy_from_slice(&data[start - ph.vaddr..end - ph.vaddr]);
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    // map the user stack and return the initial sp. all of argc, argv, envp, auxv and the strings
+    // have to fit in its top page
+    pub fn setup_stack(
+        &self,
+        mm: &AddressSpace,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<usize, ErrorCode> {
+        let top_page = USER_STACK_TOP - config::PAGE_SIZE;
+        let mut page = vec![0u8; config::PAGE_SIZE];
+
+        // the strings go right below the top
+        let mut string_ptr = USER_STACK_TOP;
+        let mut push_strings = |strings: &[&str]| -> Result<Vec<usize>, ErrorCode> {
+            let mut ptrs = Vec::with_capacity(strings.len());
+            for s in strings {
+                let len = s.len() + 1; // NUL terminated
+                string_ptr = string_ptr.checked_sub(len).ok_or(EOVERFLOW)?;
+                if string_ptr < top_page {
+                    return Err(EOVERFLOW);
+                }
+                let offset = string_ptr - top_page;
+                page[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+                ptrs.push(string_ptr);
+            }
+            Ok(ptrs)
+        };
+        let argv_ptrs = push_strings(argv)?;
+        let envp_ptrs = push_strings(envp)?;
+
+        let mut auxv = vec![
+            (AT_PHENT, self.header.phentsize),
+            (AT_PHNUM, self.header.phnum),
+            (AT_PAGESZ, config::PAGE_SIZE),
+            (AT_ENTRY, self.entry()),
+        ];
+        if let Some(phdr) = self.phdr_address() {
+            auxv.push((AT_PHDR, phdr));
+        }
+        auxv.push((AT_NULL, 0));
+
+        let mut words = Vec::new();
+        words.push(argv.len());
+        words.extend(argv_ptrs);
+        words.push(0);
+        words.extend(envp_ptrs);
+        words.push(0);
+        for (key, value) in auxv {
+            words.push(key);
+            words.push(value);
+        }
+
+        // sp must be 16 byte aligned and point at argc
+        let sp = (string_ptr - words.len() * 8) & !0xf;
+        if sp < top_page {
+            return Err(EOVERFLOW);
+        }
+        for (i, word) in words.iter().enumerate() {
+            let offset = sp - top_page + i * 8;
+            page[offset..offset + 8].copy_from_slice(&word.to_le_bytes());
+        }
+
+        let stack = VaRange::new(
+            USER_STACK_TOP - USER_STACK_PAGES * config::PAGE_SIZE,
+            USER_STACK_TOP,
+        );
+        // only the top page is populated, the rest is mapped as the stack grows into it
+        mm.map_lazy(stack, RWNORMAL)?;
+        mm.map_pages(
+            VaRange::new(top_page, USER_STACK_TOP),
+            RWNORMAL,
+            |_, frame| frame.copy_from_slice(&page),
+        )?;
+        Ok(sp)
+    }
+}
+
+struct UserStart {
+    pc: usize,
+    sp: usize,
+}
+
+fn start_user(arg: usize) -> i32 {
+    let start = unsafe { Box::from_raw(arg as *mut UserStart) };
+    let (pc, sp) = (start.pc, start.sp);
+    drop(start);
+    exception::enter_el0(pc, sp)
+}
+
+// load the executable into a new address space and run it as a new task
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<TaskId, ErrorCode> {
+    let elf = Elf::parse(image)?;
+    let mm = Arc::new(AddressSpace::new()?);
+    let sp = mm.with_active(|| -> Result<usize, ErrorCode> {
+        elf.load(&mm)?;
+        elf.setup_stack(&mm, argv, envp)
+    })?;
+
+    let start = Box::into_raw(Box::new(UserStart {
+        pc: elf.entry(),
+        sp,
+    }));
+    let mut t = match Task::new(start_user, start as usize) {
+        Ok(t) => t,
+        Err(e) => {
+            drop(unsafe { Box::from_raw(start) });
+            return Err(e);
+        }
+    };
+    t.set_address_space(mm);
+    Ok(SCHEDULER.get().unwrap().spawn(t))
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::kthread;
+    use test_macros::kernel_test;
+
+    // see elf/hello.s
+    const HELLO_ELF: &[u8; 4104] = include_bytes!("elf/hello.elf");
+
+    #[kernel_test]
+    fn test_parse_elf() {
+        let elf = Elf::parse(HELLO_ELF).unwrap();
+        assert_eq!(elf.entry(), 0x40_0000_00b0);
+        assert_eq!(elf.loads().count(), 2);
+        assert_eq!(elf.phdr_address(), Some(0x40_0000_0040));
+
+        assert!(Elf::parse(&HELLO_ELF[..32]).is_err());
+        assert!(Elf::parse(b"not an elf file at all").is_err());
+    }
+
+    fn load(vaddr: usize, memsz: usize) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_LOAD,
+            flags: 0,
+            offset: 0,
+            vaddr,
+            filesz: 0,
+            memsz,
+        }
+    }
+
+    #[kernel_test]
+    fn test_segment_pages() {
+        let pages = |vaddr, memsz| {
+            let pages = load(vaddr, memsz).pages();
+            (pages.start().value(), pages.end().value())
+        };
+        // the .text and .data of hello.elf
+        assert_eq!(
+            pages(0x40_0000_0000, 0xfb),
+            (0x40_0000_0000, 0x40_0000_1000)
+        );
+        assert_eq!(
+            pages(0x40_0001_0000, 0x10),
+            (0x40_0001_0000, 0x40_0001_1000)
+        );
+        // unaligned on both ends
+        assert_eq!(
+            pages(0x40_0000_0800, 0x1000),
+            (0x40_0000_0000, 0x40_0000_2000)
+        );
+        assert_eq!(
+            pages(0x40_0000_1000, 0x1000),
+            (0x40_0000_1000, 0x40_0000_2000)
+        );
+    }
+
+    #[kernel_test]
+    fn test_phoff_overflow() {
+        let mut image = HELLO_ELF.to_vec();
+        // e_phoff
+        image[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Elf::parse(&image).is_err());
+    }
+
+    #[kernel_test]
+    fn test_spawn_elf() {
+        // exits with .data + .bss + argc
+        let id = spawn(HELLO_ELF, &["hello", "world"], &["TERM=dumb"]).unwrap();
+        assert_eq!(kthread::join(id).unwrap(), 42);
+    }
+}
@@ -0,0 +1,31 @@
+// hello.elf, a static user program for the loader tests
+//
+// The image has no linker behind it: the text below is placed right after the ELF and program
+// headers in a read-execute segment at 0x40_0000_0000, and a read-write segment at 0x40_0001_0000
+// holds a u64 = 40 in .data followed by a u64 of .bss.
+//
+// It writes a greeting and exits with .data + .bss + argc.
+
+    .text
+    .global _start
+_start:
+    ldr     x19, [sp]               // argc
+    mov     x0, #1                  // stdout
+    adr     x1, msg
+    mov     x2, #(msg_end - msg)
+    mov     x8, #0                  // SYS_WRITE
+    svc     #0
+
+    mov     x9, #0x10000
+    movk    x9, #0x40, lsl #32
+    ldr     x10, [x9]               // .data
+    ldr     x11, [x9, #8]           // .bss
+    add     x0, x10, x11
+    add     x0, x0, x19
+    mov     x8, #2                  // SYS_EXIT
+    svc     #0
+1:  b       1b
+
+msg:
+    .ascii  "hello from ELF\n"
+msg_end:
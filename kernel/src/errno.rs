@@ -22,6 +22,15 @@ macro_rules! errno_decl {
 
         impl core::error::Error for SysError{}
 
+        impl SysError{
+            // 0 is left for success
+            pub fn errno(&self) -> usize{
+               match self{
+                   $(SysError::$ident(c,_)=>{*c as usize + 1},)*
+               }
+            }
+        }
+
 
         pub type ErrorCode = &'static SysError;
 
@@ -43,4 +52,5 @@ errno_decl!(
     ESCHED => "Scheduler error",
     EUNKNOWN => "Unknown reason",
     EUNMAP => "Address is not mapped",
+    EFAULT => "Bad address",
 );
@@ -4,7 +4,7 @@ mod arch_exception;
 
 pub use arch_exception::*;
 
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum PrivilegeLevel {
     User,
     Kernel,
@@ -0,0 +1,431 @@
+//! Flattened device tree blobs
+//!
+//! The firmware passes the physical address of the blob in x0. Only what the kernel needs to seed
+//! the frame allocator is parsed: the `/memory` nodes, the memory reservation block and the
+//! children of `/reserved-memory`. A `reg` property is read with the `#address-cells` and
+//! `#size-cells` of the parent node, which default to 2 and 1.
+//!
+//! Nothing is allocated, the blob is parsed before the heap can hand out more than a few pages.
+use crate::{errno::*, memory::address::*, static_vector};
+use core::ops::Deref;
+use nom::{
+    bytes::complete::tag,
+    error::Error,
+    number::complete::{be_u32, be_u64},
+    sequence::tuple,
+    Finish, IResult,
+};
+
+const FDT_MAGIC: [u8; 4] = [0xd0, 0x0d, 0xfe, 0xed];
+const FDT_LAST_COMP_VERSION: u32 = 16;
+pub const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const MAX_DEPTH: usize = 16;
+pub const MAX_RANGES: usize = 16;
+
+type ParserResult<'a, T> = IResult<&'a [u8], T, Error<&'a [u8]>>;
+
+static_vector!(pub Ranges, PaRange, MAX_RANGES);
+
+struct FdtHeader {
+    total_size: usize,
+    off_dt_struct: usize,
+    off_dt_strings: usize,
+    off_mem_rsvmap: usize,
+    last_comp_version: u32,
+    size_dt_strings: usize,
+    size_dt_struct: usize,
+}
+
+fn parse_header(input: &[u8]) -> ParserResult<FdtHeader> {
+    let (
+        rest,
+        (
+            _,
+            total_size,
+            off_dt_struct,
+            off_dt_strings,
+            off_mem_rsvmap,
+            _version,
+            last_comp_version,
+            _boot_cpuid,
+            size_dt_strings,
+            size_dt_struct,
+        ),
+    ) = tuple((
+        tag(FDT_MAGIC),
+        be_u32,
+        be_u32,
+        be_u32,
+        be_u32,
+        be_u32,
+        be_u32,
+        be_u32,
+        be_u32,
+        be_u32,
+    ))(input)?;
+    Ok((
+        rest,
+        FdtHeader {
+            total_size: total_size as usize,
+            off_dt_struct: off_dt_struct as usize,
+            off_dt_strings: off_dt_strings as usize,
+            off_mem_rsvmap: off_mem_rsvmap as usize,
+            last_comp_version,
+            size_dt_strings: size_dt_strings as usize,
+            size_dt_struct: size_dt_struct as usize,
+        },
+    ))
+}
+
+// the size of the whole blob, so the caller knows how much to map behind the header
+pub fn total_size(header: &[u8]) -> Result<usize, ErrorCode> {
+    let (_, header) = parse_header(header).finish().map_err(|_| EINVAL)?;
+    Ok(header.total_size)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Node {
+    Other,
+    Memory,
+    ReservedMemory,
+    Reserved, // a child of /reserved-memory
+}
+
+pub struct MemoryMap {
+    pub memory: Ranges,
+    pub reserved: Ranges,
+}
+
+impl MemoryMap {
+    // the 4K frames of `memory` inside `within` that are not `reserved`, in the order of the
+    // memory nodes
+    pub fn for_each_usable(&self, within: PaRange, mut f: impl FnMut(PaRange)) {
+        for memory in self.memory.iter().flatten() {
+            let start = memory.start().value().max(within.start().value());
+            let end = memory.end().value().min(within.end().value());
+            let mut cur = align_up(start);
+            let end = align_down(end);
+            while cur < end {
+                // the reservation closest above `cur` that is still in the way
+                let next = self
+                    .reserved
+                    .iter()
+                    .flatten()
+                    .filter(|r| r.end().value() > cur && r.start().value() < end)
+                    .min_by_key(|r| r.start().value());
+                match next {
+                    None => {
+                        f(PaRange::new(cur, end));
+                        break;
+                    }
+                    Some(r) => {
+                        let hole = align_down(r.start().value());
+                        if hole > cur {
+                            f(PaRange::new(cur, hole));
+                        }
+                        cur = cur.max(align_up(r.end().value()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn align_up(addr: usize) -> usize {
+    (addr + 0xfff) & !0xfff
+}
+fn align_down(addr: usize) -> usize {
+    addr & !0xfff
+}
+
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    rsvmap: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(blob: &'a [u8]) -> Result<Self, ErrorCode> {
+        let (_, header) = parse_header(blob).finish().map_err(|_| EINVAL)?;
+        if header.last_comp_version > FDT_LAST_COMP_VERSION {
+            return Err(ESUPPORTED);
+        }
+        if header.total_size > blob.len() {
+            return Err(EBOUND);
+        }
+        let blob = &blob[..header.total_size];
+        let slice = |off: usize, len: usize| {
+            off.checked_add(len)
+                .and_then(|end| blob.get(off..end))
+                .ok_or(EBOUND)
+        };
+        Ok(Self {
+            blob,
+            structs: slice(header.off_dt_struct, header.size_dt_struct)?,
+            strings: slice(header.off_dt_strings, header.size_dt_strings)?,
+            rsvmap: blob.get(header.off_mem_rsvmap..).ok_or(EBOUND)?,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.blob.len()
+    }
+
+    // the memory there is and the parts of it that are not ours to use
+    pub fn memory_map(&self) -> Result<MemoryMap, ErrorCode> {
+        let mut map = MemoryMap {
+            memory: Ranges::new(),
+            reserved: Ranges::new(),
+        };
+        self.reservations(&mut map.reserved)?;
+        self.walk(&mut map)?;
+        Ok(map)
+    }
+
+    // the memory reservation block, pairs of address and size up to one of all zeros
+    fn reservations(&self, reserved: &mut Ranges) -> Result<(), ErrorCode> {
+        let mut input = self.rsvmap;
+        loop {
+            let (rest, (address, size)) = tuple((be_u64, be_u64))(input)
+                .finish()
+                .map_err(|_: Error<&[u8]>| EBOUND)?;
+            if address == 0 && size == 0 {
+                return Ok(());
+            }
+            reserved.push(range(address, size)?)?;
+            input = rest;
+        }
+    }
+
+    fn walk(&self, map: &mut MemoryMap) -> Result<(), ErrorCode> {
+        // the cells of every open node, used by the `reg` of its children
+        let mut cells = [(2, 1); MAX_DEPTH];
+        let mut kind = [Node::Other; MAX_DEPTH];
+        let mut depth = 0;
+        let mut offset = 0;
+        loop {
+            let token = self.u32_at(offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = self.str_at(self.structs, offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    depth += 1;
+                    if depth >= MAX_DEPTH {
+                        return Err(EOVERFLOW);
+                    }
+                    cells[depth] = (2, 1);
+                    kind[depth] = match (depth, kind[depth - 1]) {
+                        // the root is at depth 1
+                        (2, _) if name == "memory" || name.starts_with("memory@") => Node::Memory,
+                        (2, _) if name == "reserved-memory" => Node::ReservedMemory,
+                        (3, Node::ReservedMemory) => Node::Reserved,
+                        _ => Node::Other,
+                    };
+                }
+                FDT_END_NODE => {
+                    depth = depth.checked_sub(1).ok_or(EINVAL)?;
+                }
+                FDT_PROP => {
+                    let len = self.u32_at(offset)? as usize;
+                    let name_offset = self.u32_at(offset + 4)? as usize;
+                    let value = self
+                        .structs
+                        .get(offset + 8..offset + 8 + len)
+                        .ok_or(EBOUND)?;
+                    offset = align4(offset + 8 + len);
+
+                    match self.str_at(self.strings, name_offset)? {
+                        "#address-cells" => cells[depth].0 = be32(value)?,
+                        "#size-cells" => cells[depth].1 = be32(value)?,
+                        "reg" if kind[depth] == Node::Memory => {
+                            reg(value, cells[depth - 1], &mut map.memory)?
+                        }
+                        "reg" if kind[depth] == Node::Reserved => {
+                            reg(value, cells[depth - 1], &mut map.reserved)?
+                        }
+                        _ => {}
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return Ok(()),
+                _ => return Err(EINVAL),
+            }
+        }
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, ErrorCode> {
+        be32(self.structs.get(offset..).ok_or(EBOUND)?)
+    }
+
+    fn str_at(&self, block: &'a [u8], offset: usize) -> Result<&'a str, ErrorCode> {
+        let bytes = block.get(offset..).ok_or(EBOUND)?;
+        let len = bytes.iter().position(|b| *b == 0).ok_or(EBOUND)?;
+        core::str::from_utf8(&bytes[..len]).map_err(|_| EINVAL)
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn be32(bytes: &[u8]) -> Result<u32, ErrorCode> {
+    let (_, v) = be_u32(bytes).finish().map_err(|_: Error<&[u8]>| EBOUND)?;
+    Ok(v)
+}
+
+// a number of 1 or 2 cells
+fn cells_value(bytes: &[u8]) -> Result<u64, ErrorCode> {
+    match bytes.len() {
+        4 => Ok(be32(bytes)? as u64),
+        8 => Ok(((be32(bytes)? as u64) << 32) | be32(&bytes[4..])? as u64),
+        _ => Err(ESUPPORTED),
+    }
+}
+
+fn range(address: u64, size: u64) -> Result<PaRange, ErrorCode> {
+    let end = address.checked_add(size).ok_or(EOVERFLOW)?;
+    Ok(PaRange::new(address as usize, end as usize))
+}
+
+// every address and size pair of a `reg` property
+fn reg(
+    value: &[u8],
+    (address_cells, size_cells): (u32, u32),
+    ranges: &mut Ranges,
+) -> Result<(), ErrorCode> {
+    let address_len = address_cells as usize * 4;
+    let entry_len = address_len + size_cells as usize * 4;
+    if entry_len == 0 || value.len() % entry_len != 0 {
+        return Err(EINVAL);
+    }
+    for entry in value.chunks(entry_len) {
+        let address = cells_value(&entry[..address_len])?;
+        let size = cells_value(&entry[address_len..])?;
+        if size != 0 {
+            ranges.push(range(address, size)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    extern crate alloc;
+    use super::*;
+    use alloc::vec::Vec;
+    use test_macros::kernel_test;
+
+    // a blob as dtc would lay it out: header, reservations, structure block and strings
+    fn blob() -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut string = |s: &str| {
+            let off = strings.len() as u32;
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+            off
+        };
+        let address_cells = string("#address-cells");
+        let size_cells = string("#size-cells");
+        let reg_name = string("reg");
+
+        let mut structs = Vec::new();
+        let word = |v: &mut Vec<u8>, x: u32| v.extend_from_slice(&x.to_be_bytes());
+        let node = |v: &mut Vec<u8>, name: &str| {
+            word(v, FDT_BEGIN_NODE);
+            v.extend_from_slice(name.as_bytes());
+            v.push(0);
+            v.resize(align4(v.len()), 0);
+        };
+        let prop = |v: &mut Vec<u8>, name: u32, cells: &[u32]| {
+            word(v, FDT_PROP);
+            word(v, cells.len() as u32 * 4);
+            word(v, name);
+            cells.iter().for_each(|c| word(v, *c));
+        };
+
+        node(&mut structs, "");
+        prop(&mut structs, address_cells, &[2]);
+        prop(&mut structs, size_cells, &[1]);
+        node(&mut structs, "memory@0");
+        // 0..1G and 4G..4G+16M
+        prop(
+            &mut structs,
+            reg_name,
+            &[0, 0, 0x4000_0000, 1, 0, 0x100_0000],
+        );
+        word(&mut structs, FDT_END_NODE);
+        node(&mut structs, "reserved-memory");
+        prop(&mut structs, address_cells, &[1]);
+        prop(&mut structs, size_cells, &[1]);
+        node(&mut structs, "linux,cma");
+        prop(&mut structs, reg_name, &[0x3000_0000, 0x20_0000]);
+        word(&mut structs, FDT_END_NODE);
+        word(&mut structs, FDT_END_NODE);
+        word(&mut structs, FDT_NOP);
+        word(&mut structs, FDT_END_NODE);
+        word(&mut structs, FDT_END);
+
+        let rsvmap: [u64; 4] = [0x1000, 0x800, 0, 0];
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + rsvmap.len() * 8;
+        let off_strings = off_struct + structs.len();
+        let total = off_strings + strings.len();
+
+        let mut blob = Vec::new();
+        for v in [
+            u32::from_be_bytes(FDT_MAGIC),
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structs.len() as u32,
+        ] {
+            word(&mut blob, v);
+        }
+        rsvmap
+            .iter()
+            .for_each(|r| blob.extend_from_slice(&r.to_be_bytes()));
+        blob.extend_from_slice(&structs);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    #[kernel_test]
+    fn test_fdt_memory_map() {
+        let blob = blob();
+        assert_eq!(total_size(&blob).unwrap(), blob.len());
+        let fdt = Fdt::new(&blob).unwrap();
+        let map = fdt.memory_map().unwrap();
+        assert_eq!(map.memory.size(), 2);
+        assert_eq!(map.reserved.size(), 2);
+        assert!(map.memory[1].unwrap().start().value() == 0x1_0000_0000);
+
+        // the reservation block entry is not 4K aligned and takes its whole frame
+        let mut usable = [(0, 0); 4];
+        let mut n = 0;
+        map.for_each_usable(PaRange::new(0x800usize, 0x1_0080_0000), |r| {
+            usable[n] = (r.start().value(), r.end().value());
+            n += 1;
+        });
+        assert_eq!(n, 3);
+        assert_eq!(usable[0], (0x2000, 0x3000_0000));
+        assert_eq!(usable[1], (0x3020_0000, 0x4000_0000));
+        assert_eq!(usable[2], (0x1_0000_0000, 0x1_0080_0000));
+
+        assert!(Fdt::new(&blob[..HEADER_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+//! GDB remote serial protocol stub
+//!
+//! The kernel stops in the stub on a breakpoint or after a single step and serves GDB until it is
+//! told to continue, step or detach. Only the calling core stops, the others keep running.
+//!
+//! Supported packets: `?`, `g`, `G`, `p`, `P`, `m`, `M`, `c`, `s`, `D`, `k`, `Z0` and `z0`, plus
+//! the queries GDB needs to attach. Everything else gets the empty reply, which GDB takes as
+//! unsupported. The stub has a PL011 of its own, so the console stays on the mini UART: UART3 on
+//! GPIO 4 and 5 of the Pi 4, UART0 under QEMU, which is the first serial port there:
+//!
+//! ```text
+//! qemu-system-aarch64 -M raspi3b ... -serial tcp::1234,server -serial stdio
+//! gdb-multiarch -ex 'target remote :1234' kernel
+//! ```
+use crate::errno::*;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_arch = "aarch64")]
+#[path = "_arch/aarch64/gdb.rs"]
+mod arch_gdb;
+
+pub use arch_gdb::{breakpoint, init};
+
+// the largest packet in either direction, without framing
+const PACKET_SIZE: usize = 1024;
+
+pub const SIGTRAP: u8 = 5;
+
+// GDB is waiting for a stop reply, the kernel was resumed by a `c` or `s`
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+pub trait Connection {
+    fn read_byte(&mut self) -> u8;
+    fn write_byte(&mut self, b: u8);
+}
+
+pub trait Target {
+    // all registers in the layout of the `g` packet, the number of bytes written to `buf`
+    fn registers(&self, buf: &mut [u8]) -> usize;
+    fn set_registers(&mut self, buf: &[u8]) -> Result<(), ErrorCode>;
+    fn register(&self, n: usize, buf: &mut [u8]) -> Result<usize, ErrorCode>;
+    fn set_register(&mut self, n: usize, value: &[u8]) -> Result<(), ErrorCode>;
+    fn read_memory(&self, address: usize, buf: &mut [u8]) -> Result<(), ErrorCode>;
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), ErrorCode>;
+    fn insert_breakpoint(&mut self, address: usize) -> Result<(), ErrorCode>;
+    fn remove_breakpoint(&mut self, address: usize) -> Result<(), ErrorCode>;
+    fn set_pc(&mut self, pc: usize);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    Step,
+    Detach,
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0usize, |n, c| Some((n << 4) | hex_digit(*c)? as usize))
+}
+
+// `hex` into `out`, the number of bytes decoded
+fn decode_hex(hex: &[u8], out: &mut [u8]) -> Option<usize> {
+    if hex.len() % 2 != 0 || hex.len() / 2 > out.len() {
+        return None;
+    }
+    for (i, pair) in hex.chunks(2).enumerate() {
+        out[i] = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+    }
+    Some(hex.len() / 2)
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+// "addr,len" and what follows
+fn parse_range(s: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let comma = s.iter().position(|c| *c == b',')?;
+    let end = s.iter().position(|c| *c == b':').unwrap_or(s.len());
+    let len = parse_hex(s.get(comma + 1..end)?)?;
+    Some((
+        parse_hex(&s[..comma])?,
+        len,
+        s.get(end + 1..).unwrap_or(&[]),
+    ))
+}
+
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, s: &[u8]) {
+        let n = s.len().min(PACKET_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s[..n]);
+        self.len += n;
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.push(&[HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+        }
+    }
+
+    fn ok(mut self) -> Self {
+        self.push(b"OK");
+        self
+    }
+
+    fn error(mut self, err: ErrorCode) -> Self {
+        self.push(b"E");
+        self.push_hex(&[err.errno() as u8]);
+        self
+    }
+}
+
+pub struct Stub<C: Connection> {
+    conn: C,
+}
+
+impl<C: Connection> Stub<C> {
+    pub fn new(conn: C) -> Self {
+        Self { conn }
+    }
+
+    // the payload of the next packet with a good checksum, acknowledged
+    fn receive<'a>(&mut self, buf: &'a mut [u8; PACKET_SIZE]) -> &'a [u8] {
+        loop {
+            while self.conn.read_byte() != b'$' {}
+            let mut len = 0;
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                let c = self.conn.read_byte();
+                if c == b'#' {
+                    break;
+                }
+                if len == PACKET_SIZE {
+                    overflow = true;
+                } else {
+                    buf[len] = c;
+                    len += 1;
+                }
+                sum = sum.wrapping_add(c);
+            }
+            let high = hex_digit(self.conn.read_byte());
+            let low = hex_digit(self.conn.read_byte());
+            match (high, low) {
+                (Some(h), Some(l)) if !overflow && ((h << 4) | l) == sum => {
+                    self.conn.write_byte(b'+');
+                    return &buf[..len];
+                }
+                _ => self.conn.write_byte(b'-'),
+            }
+        }
+    }
+
+    // sent until GDB acknowledges it
+    fn send(&mut self, reply: &Reply) {
+        let payload = &reply.buf[..reply.len];
+        let sum = payload.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+        loop {
+            self.conn.write_byte(b'$');
+            payload.iter().for_each(|c| self.conn.write_byte(*c));
+            self.conn.write_byte(b'#');
+            self.conn.write_byte(HEX[(sum >> 4) as usize]);
+            self.conn.write_byte(HEX[(sum & 0xf) as usize]);
+            if self.conn.read_byte() == b'+' {
+                return;
+            }
+        }
+    }
+
+    fn stop_reply(signal: u8) -> Reply {
+        let mut reply = Reply::new();
+        reply.push(b"S");
+        reply.push_hex(&[signal]);
+        reply
+    }
+
+    // talk to GDB until it resumes the kernel, `signal` is why it stopped
+    pub fn serve(&mut self, target: &mut impl Target, signal: u8) -> Resume {
+        if RESUMED.swap(false, Ordering::Relaxed) {
+            self.send(&Self::stop_reply(signal));
+        }
+        let mut buf = [0u8; PACKET_SIZE];
+        loop {
+            let packet = self.receive(&mut buf);
+            let (command, args) = match packet.split_first() {
+                Some((c, args)) => (*c, args),
+                None => continue,
+            };
+            let reply = match command {
+                b'?' => Self::stop_reply(signal),
+                b'g' => {
+                    let mut regs = [0u8; PACKET_SIZE / 2];
+                    let n = target.registers(&mut regs);
+                    let mut reply = Reply::new();
+                    reply.push_hex(&regs[..n]);
+                    reply
+                }
+                b'G' => {
+                    let mut regs = [0u8; PACKET_SIZE / 2];
+                    match decode_hex(args, &mut regs) {
+                        Some(n) => match target.set_registers(&regs[..n]) {
+                            Ok(()) => Reply::new().ok(),
+                            Err(err) => Reply::new().error(err),
+                        },
+                        None => Reply::new().error(EPARAM),
+                    }
+                }
+                b'p' => {
+                    let mut value = [0u8; 16];
+                    match parse_hex(args).map(|n| target.register(n, &mut value)) {
+                        Some(Ok(n)) => {
+                            let mut reply = Reply::new();
+                            reply.push_hex(&value[..n]);
+                            reply
+                        }
+                        Some(Err(err)) => Reply::new().error(err),
+                        None => Reply::new().error(EPARAM),
+                    }
+                }
+                b'P' => {
+                    let mut value = [0u8; 16];
+                    let eq = args.iter().position(|c| *c == b'=').unwrap_or(args.len());
+                    let n = parse_hex(&args[..eq]);
+                    let len = args.get(eq + 1..).and_then(|v| decode_hex(v, &mut value));
+                    match (n, len) {
+                        (Some(n), Some(len)) => match target.set_register(n, &value[..len]) {
+                            Ok(()) => Reply::new().ok(),
+                            Err(err) => Reply::new().error(err),
+                        },
+                        _ => Reply::new().error(EPARAM),
+                    }
+                }
+                b'm' => match parse_range(args) {
+                    Some((address, len, _)) if len <= PACKET_SIZE / 2 => {
+                        let mut data = [0u8; PACKET_SIZE / 2];
+                        match target.read_memory(address, &mut data[..len]) {
+                            Ok(()) => {
+                                let mut reply = Reply::new();
+                                reply.push_hex(&data[..len]);
+                                reply
+                            }
+                            Err(err) => Reply::new().error(err),
+                        }
+                    }
+                    _ => Reply::new().error(EPARAM),
+                },
+                b'M' => {
+                    let mut data = [0u8; PACKET_SIZE / 2];
+                    match parse_range(args) {
+                        Some((address, len, hex)) if decode_hex(hex, &mut data) == Some(len) => {
+                            match target.write_memory(address, &data[..len]) {
+                                Ok(()) => Reply::new().ok(),
+                                Err(err) => Reply::new().error(err),
+                            }
+                        }
+                        _ => Reply::new().error(EPARAM),
+                    }
+                }
+                b'c' | b's' => {
+                    if let Some(pc) = parse_hex(args) {
+                        target.set_pc(pc);
+                    }
+                    RESUMED.store(true, Ordering::Relaxed);
+                    return if command == b'c' {
+                        Resume::Continue
+                    } else {
+                        Resume::Step
+                    };
+                }
+                b'D' => {
+                    self.send(&Reply::new().ok());
+                    return Resume::Detach;
+                }
+                // the kernel cannot be killed, GDB just goes away
+                b'k' => return Resume::Detach,
+                b'Z' | b'z' if args.starts_with(b"0,") => {
+                    let result = match parse_range(&args[2..]) {
+                        Some((address, _, _)) if command == b'Z' => {
+                            target.insert_breakpoint(address)
+                        }
+                        Some((address, _, _)) => target.remove_breakpoint(address),
+                        None => Err(EPARAM),
+                    };
+                    match result {
+                        Ok(()) => Reply::new().ok(),
+                        Err(err) => Reply::new().error(err),
+                    }
+                }
+                // thread selection, there is only the stopped core
+                b'H' => Reply::new().ok(),
+                b'q' if args.starts_with(b"Supported") => {
+                    let mut reply = Reply::new();
+                    reply.push(b"PacketSize=");
+                    reply.push_hex(&(PACKET_SIZE as u16).to_be_bytes());
+                    reply
+                }
+                b'q' if args == b"Attached" => {
+                    let mut reply = Reply::new();
+                    reply.push(b"1");
+                    reply
+                }
+                _ => Reply::new(),
+            };
+            self.send(&reply);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    // GDB's side, every packet of ours is acknowledged
+    struct Script {
+        input: Vec<u8>,
+        next: usize,
+        output: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(packets: &[&[u8]]) -> Self {
+            let mut input = Vec::new();
+            for p in packets {
+                let sum = p.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+                input.push(b'$');
+                input.extend_from_slice(p);
+                input.push(b'#');
+                input.extend_from_slice(&[HEX[(sum >> 4) as usize], HEX[(sum & 0xf) as usize]]);
+                input.push(b'+');
+            }
+            Self {
+                input,
+                next: 0,
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Connection for &mut Script {
+        fn read_byte(&mut self) -> u8 {
+            self.next += 1;
+            self.input[self.next - 1]
+        }
+        fn write_byte(&mut self, b: u8) {
+            self.output.push(b)
+        }
+    }
+
+    struct Memory {
+        regs: [u64; 2],
+        mem: [u8; 8],
+        breakpoint: Option<usize>,
+    }
+
+    impl Target for Memory {
+        fn registers(&self, buf: &mut [u8]) -> usize {
+            buf[..8].copy_from_slice(&self.regs[0].to_le_bytes());
+            buf[8..16].copy_from_slice(&self.regs[1].to_le_bytes());
+            16
+        }
+        fn set_registers(&mut self, buf: &[u8]) -> Result<(), ErrorCode> {
+            Err(ESUPPORTED)
+        }
+        fn register(&self, n: usize, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+            let reg = self.regs.get(n).ok_or(EPARAM)?;
+            buf[..8].copy_from_slice(&reg.to_le_bytes());
+            Ok(8)
+        }
+        fn set_register(&mut self, n: usize, value: &[u8]) -> Result<(), ErrorCode> {
+            Err(ESUPPORTED)
+        }
+        fn read_memory(&self, address: usize, buf: &mut [u8]) -> Result<(), ErrorCode> {
+            let mem = self.mem.get(address..address + buf.len()).ok_or(EFAULT)?;
+            buf.copy_from_slice(mem);
+            Ok(())
+        }
+        fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), ErrorCode> {
+            let mem = self
+                .mem
+                .get_mut(address..address + data.len())
+                .ok_or(EFAULT)?;
+            mem.copy_from_slice(data);
+            Ok(())
+        }
+        fn insert_breakpoint(&mut self, address: usize) -> Result<(), ErrorCode> {
+            self.breakpoint = Some(address);
+            Ok(())
+        }
+        fn remove_breakpoint(&mut self, address: usize) -> Result<(), ErrorCode> {
+            self.breakpoint.take().map(|_| ()).ok_or(EPARAM)
+        }
+        fn set_pc(&mut self, pc: usize) {
+            self.regs[1] = pc as u64;
+        }
+    }
+
+    #[kernel_test]
+    fn test_gdb_session() {
+        let mut script = Script::new(&[
+            b"p1",
+            b"M2,2:abcd",
+            b"m1,3",
+            b"m6,4",
+            b"Z0,40,4",
+            b"vMustReplyEmpty",
+            b"c1000",
+        ]);
+        let mut target = Memory {
+            regs: [1, 0x10],
+            mem: [0; 8],
+            breakpoint: None,
+        };
+        let resume = Stub::new(&mut script).serve(&mut target, SIGTRAP);
+        assert_eq!(resume, Resume::Continue);
+        assert_eq!(target.regs[1], 0x1000);
+        assert_eq!(target.breakpoint, Some(0x40));
+        assert_eq!(&target.mem[2..4], &[0xab, 0xcd]);
+
+        // every packet acknowledged, then the replies
+        let replies: Vec<&[u8]> = script.output.split(|c| *c == b'+').collect();
+        assert_eq!(replies[1], b"$1000000000000000#01");
+        assert_eq!(replies[2], b"$OK#9a");
+        assert_eq!(replies[3], b"$00abcd#ea");
+        assert!(replies[4].starts_with(b"$E"));
+        assert_eq!(replies[5], b"$OK#9a");
+        assert_eq!(replies[6], b"$#00");
+        RESUMED.store(false, Ordering::Relaxed);
+    }
+}
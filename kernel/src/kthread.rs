@@ -0,0 +1,62 @@
+//! Kernel threads on top of the scheduler
+use crate::{
+    errno::ErrorCode,
+    exception,
+    scheduler::{Task, TaskId, SCHEDULER},
+};
+
+// __ret_from_fork lands here the first time a new task is switched in
+#[no_mangle]
+extern "C" fn __kthread_start(entry: fn(usize) -> i32, arg: usize) -> ! {
+    SCHEDULER.get().unwrap().finish_switch();
+    exception::local_irq_unmask();
+    exit(entry(arg))
+}
+
+// returning from `entry` is the same as calling exit with the returned value
+pub fn spawn(entry: fn(usize) -> i32, arg: usize) -> Result<TaskId, ErrorCode> {
+    let t = Task::new(entry, arg)?;
+    Ok(SCHEDULER.get().unwrap().spawn(t))
+}
+
+// the stack is released once we have switched away, the task itself when it is joined
+pub fn exit(code: i32) -> ! {
+    SCHEDULER.get().unwrap().exit(code)
+}
+
+pub fn join(id: TaskId) -> Result<i32, ErrorCode> {
+    SCHEDULER.get().unwrap().join(id)
+}
+
+pub fn current() -> TaskId {
+    SCHEDULER.get().unwrap().current_id()
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    fn double(arg: usize) -> i32 {
+        (arg * 2) as i32
+    }
+
+    fn exit_early(arg: usize) -> i32 {
+        exit(arg as i32);
+    }
+
+    #[kernel_test]
+    fn test_spawn_join() {
+        let a = spawn(double, 21).unwrap();
+        let b = spawn(exit_early, 7).unwrap();
+        assert_ne!(a, b);
+        assert_ne!(a, current());
+
+        assert_eq!(join(a).unwrap(), 42);
+        assert_eq!(join(b).unwrap(), 7);
+
+        // reaped
+        assert!(join(a).is_err());
+    }
+}
@@ -38,20 +38,27 @@
 
 extern crate alloc;
 
+mod backtrace;
 mod bsp;
 mod console;
 mod cpu;
 mod driver;
+mod elf;
 mod errno;
 mod exception;
+mod fdt;
+mod gdb;
 mod generics;
 mod interrupt;
+mod kthread;
 mod macros;
 mod memory;
 mod panic_wait;
 mod print;
 mod scheduler;
+mod symbols;
 mod synchronization;
+mod syscall;
 mod utils;
 mod wasm;
 
@@ -65,7 +72,7 @@ use tock_registers::interfaces::{Readable, Writeable};
 extern "C" {
     fn clear_memory_range(start: usize, end_exclusive: usize);
 }
-// 32 bytes * 4 + 16 + 16 + 16
+// 32 bytes * 4 + 16 + 16 + 16 + 8
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct BootInfo {
@@ -76,6 +83,7 @@ pub struct BootInfo {
     pub free_frame: PaRange,
     pub lower_free_page: VaRange,
     pub higher_free_page: VaRange,
+    pub dtb: PhysicalAddress, // 0 if the firmware passed none
 }
 
 impl fmt::Display for BootInfo {
@@ -86,7 +94,8 @@ impl fmt::Display for BootInfo {
         writeln!(f, "    peripheral:        {}", self.peripheral)?;
         writeln!(f, "    free frame:        {}", self.free_frame)?;
         writeln!(f, "    lower free page:   {}", self.lower_free_page)?;
-        write!(f, "    higher free page:  {}", self.higher_free_page)
+        writeln!(f, "    higher free page:  {}", self.higher_free_page)?;
+        write!(f, "    device tree:       {}", self.dtb)
     }
 }
 
@@ -98,7 +107,8 @@ impl fmt::Debug for BootInfo {
         writeln!(f, "    peripheral:        {:?}", self.peripheral)?;
         writeln!(f, "    free frame:        {:?}", self.free_frame)?;
         writeln!(f, "    lower free page:   {:?}", self.lower_free_page)?;
-        write!(f, "    higher free page:  {:?}", self.higher_free_page)
+        writeln!(f, "    higher free page:  {:?}", self.higher_free_page)?;
+        write!(f, "    device tree:       {:?}", self.dtb)
     }
 }
 
@@ -112,6 +122,12 @@ pub unsafe fn kernel_main(boot_info: &BootInfo) -> ! {
     println!("Boot info:");
     println!("{}", boot_info);
     memory::init(boot_info).unwrap();
+    #[cfg(feature = "gdb")]
+    {
+        gdb::init().unwrap();
+        println!("Waiting for GDB on the debug UART");
+        gdb::breakpoint();
+    }
     cpu::timer::init().unwrap();
     // cpu::timer::TIMER.get().unwrap().enable();
 
@@ -124,8 +140,10 @@ pub unsafe fn kernel_main(boot_info: &BootInfo) -> ! {
     println!("boot takes {} micros", boot_duration.as_micros());
 
     interrupt::init().unwrap();
+    cpu::timer::request_irq().unwrap();
     cpu::timer::TIMER.get().unwrap().enable();
-    scheduler::init();
+    scheduler::init().unwrap();
+    cpu::smp::start_secondary_cores().unwrap();
     scheduler::SCHEDULER.get().unwrap().init_task()
 }
 
@@ -134,8 +152,19 @@ fn test_runner(tests: &[&test_types::UnitTest]) {
     println!("Running {} tests", tests.len());
     for (i, test) in tests.iter().enumerate() {
         println!("{:>3}. {:.<58}", i + 1, test.name);
+        let baseline = memory::heap::HEAP_ALLOCATOR.get().unwrap().stats();
         (test.test_func)();
 
+        if test.leak_check {
+            let stats = memory::heap::HEAP_ALLOCATOR.get().unwrap().stats();
+            if let Some((bytes, buffers)) = stats.leaked_since(&baseline) {
+                memory::heap::dump_heap_stats();
+                panic!(
+                    "{} leaked {} bytes and {} bump buffers",
+                    test.name, bytes, buffers
+                );
+            }
+        }
         println!("[ok]");
     }
 }
@@ -155,6 +184,9 @@ pub unsafe fn kernel_main(boot_info: &BootInfo) -> ! {
     println!("boot takes {} micros", boot_duration.as_micros());
 
     interrupt::init().unwrap();
+    cpu::timer::request_irq().unwrap();
+    scheduler::init().unwrap();
+    cpu::smp::start_secondary_cores().unwrap();
 
     wasm::init().unwrap();
     test_main();
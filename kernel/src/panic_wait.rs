@@ -4,7 +4,7 @@
 
 //! A panic handler that infinitely waits.
 
-use crate::{cpu, println};
+use crate::{backtrace::Backtrace, cpu, println};
 use core::panic::PanicInfo;
 
 #[linkage = "weak"]
@@ -50,6 +50,7 @@ fn panic(info: &PanicInfo) -> ! {
         col,
         info.message().unwrap_or(&format_args!(""))
     );
+    println!("{}", Backtrace::here());
 
     _panic_exit()
 }
@@ -1,14 +1,20 @@
 extern crate alloc;
 use crate::{
+    cpu::smp::{self, core_id, NUM_OF_CORES},
     errno::*,
+    exception,
     generics::{DoublyLinkedList, Link},
     memory::*,
     println,
     synchronization::Spinlock,
+    syscall,
 };
 use aarch64_cpu::{asm::barrier, registers::*};
-use alloc::boxed::Box;
-use core::arch::asm;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use spin::{once::Once, Spin};
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
@@ -16,17 +22,24 @@ mod context_switch;
 mod task;
 #[cfg(not(feature = "build_qemu"))]
 use crate::bsp::device_driver::gic_400::IRQNum::SPI;
-use crate::memory::address::AddressRange;
+use crate::memory::address::{AddressRange, VaRange};
 pub use context_switch::*;
 pub use task::*;
 
-const NUM_OF_CORES: usize = 4;
-const CORE_ID: usize = 0;
+// number of timer ticks a task runs before it is preempted
+const DEFAULT_QUANTUM: usize = 5;
+
+// below the recursive window at the top of the lower half
+const INIT_STACK_TOP: usize = 0x7F_0000_0000;
 
-#[derive(Copy, Clone)]
 struct RunQueue {
     tasks: DoublyLinkedList<Task>,
     current: Option<*mut Task>,
+    idle: Option<*mut Task>,
+    // a task that exited but whose stack is still in use until we switch away from it
+    dead: Option<*mut Task>,
+    slice_left: usize,
+    need_resched: bool,
 }
 
 unsafe impl Sync for RunQueue {}
@@ -37,11 +50,16 @@ impl RunQueue {
         Self {
             tasks: DoublyLinkedList::new(),
             current: None,
+            idle: None,
+            dead: None,
+            slice_left: DEFAULT_QUANTUM,
+            need_resched: false,
         }
     }
 
-    fn add_task(&mut self, t: Box<Task>) {
-        self.tasks.push_front(Link::some(Box::into_raw(t) as usize));
+    fn add_task(&mut self, t: *mut Task) {
+        unsafe { (*t).set_state(TaskState::Ready) };
+        self.tasks.push_back(Link::some(t as usize));
     }
 
     fn get_task(&mut self) -> Option<*mut Task> {
@@ -51,34 +69,129 @@ impl RunQueue {
 
     fn replace_current(&mut self, t: *mut Task) {
         if let Some(c) = self.current {
-            self.tasks.push_front(Link::some(c as usize));
+            // blocked and exited tasks leave the run queue
+            if unsafe { (*c).state() } == TaskState::Running {
+                unsafe { (*c).set_state(TaskState::Ready) };
+                if Some(c) != self.idle {
+                    self.tasks.push_back(Link::some(c as usize));
+                }
+            }
         }
 
+        unsafe { (*t).set_state(TaskState::Running) };
         self.current = Some(t)
     }
 }
 
+// tasks never migrate, each one stays on the run queue of the core it was spawned on
 pub struct UnSafeScheduler {
     rq: [RunQueue; NUM_OF_CORES],
+    tasks: BTreeMap<TaskId, *mut Task>,
+    quantum: usize,
+    next_core: usize,
 }
 
 impl UnSafeScheduler {
     fn new() -> Self {
         Self {
-            rq: [RunQueue::new(); NUM_OF_CORES],
+            rq: core::array::from_fn(|_| RunQueue::new()),
+            tasks: BTreeMap::new(),
+            quantum: DEFAULT_QUANTUM,
+            next_core: 0,
         }
     }
 
-    fn add_task(&mut self, t: Box<Task>) {
-        self.rq[CORE_ID].add_task(t);
+    fn add_task(&mut self, mut t: Box<Task>) {
+        let core = core_id();
+        t.set_cpu(core);
+        self.rq[core].add_task(Box::into_raw(t));
+    }
+
+    fn spawn_on(&mut self, core: usize, mut t: Box<Task>) -> TaskId {
+        let id = t.id();
+        t.set_cpu(core);
+        let t = Box::into_raw(t);
+        self.tasks.insert(id, t);
+        self.rq[core].add_task(t);
+        id
+    }
+
+    // spread new tasks over the online cores
+    fn spawn(&mut self, t: Box<Task>) -> TaskId {
+        let core = loop {
+            let core = self.next_core;
+            self.next_core = (self.next_core + 1) % NUM_OF_CORES;
+            if smp::is_online(core) {
+                break core;
+            }
+        };
+        self.spawn_on(core, t)
     }
 
     fn schedule(&mut self) -> Option<*mut Task> {
-        let t = self.rq[CORE_ID].get_task()?;
+        let t = self.rq[core_id()].get_task()?;
         Some(t as *mut Task)
     }
     fn replace_current(&mut self, t: *mut Task) {
-        self.rq[CORE_ID].replace_current(t)
+        self.rq[core_id()].replace_current(t)
+    }
+
+    fn current(&self) -> *mut Task {
+        self.rq[core_id()].current.unwrap()
+    }
+
+    // requeue the current task and return (prev, next) if there is another task to run
+    fn pick_next(&mut self) -> Option<(*mut Task, *mut Task)> {
+        let rq = &mut self.rq[core_id()];
+        rq.slice_left = self.quantum;
+        let prev = rq.current?;
+        let next = match rq.get_task() {
+            Some(t) => t,
+            None if unsafe { (*prev).state() } == TaskState::Running => return None,
+            None => rq.idle?,
+        };
+        rq.replace_current(next);
+        Some((prev, next))
+    }
+
+    fn tick(&mut self) {
+        let rq = &mut self.rq[core_id()];
+        rq.slice_left = rq.slice_left.saturating_sub(1);
+        if rq.slice_left == 0 {
+            rq.need_resched = true;
+        }
+    }
+
+    fn take_need_resched(&mut self) -> bool {
+        let rq = &mut self.rq[core_id()];
+        let need_resched = rq.need_resched;
+        rq.need_resched = false;
+        need_resched
+    }
+
+    fn block_current(&mut self) {
+        unsafe { (*self.current()).set_state(TaskState::Blocked) };
+    }
+
+    fn wake(&mut self, t: *mut Task) {
+        if unsafe { (*t).state() } == TaskState::Blocked {
+            let core = unsafe { (*t).cpu() };
+            self.rq[core].add_task(t);
+        }
+    }
+
+    fn exit_current(&mut self, code: i32) {
+        let current = unsafe { &mut *self.current() };
+        current.set_exit_code(code);
+        current.set_state(TaskState::Zombie);
+        self.rq[core_id()].dead = Some(current as *mut Task);
+        if let Some(joiner) = current.take_joiner() {
+            self.wake(joiner);
+        }
+    }
+
+    fn take_dead(&mut self) -> Option<*mut Task> {
+        self.rq[core_id()].dead.take()
     }
 }
 
@@ -87,62 +200,310 @@ pub struct Scheduler {
 }
 
 impl Scheduler {
-    fn new() -> Self {
-        Self {
-            sched: Spinlock::new(UnSafeScheduler::new()),
-        }
+    fn new() -> Result<Self, ErrorCode> {
+        let mut sched = UnSafeScheduler::new();
+
+        // the boot context becomes the first task, its context is saved on the first switch
+        let boot = Box::into_raw(Box::new(Task::default()));
+        sched.tasks.insert(TaskId::default(), boot);
+        sched.replace_current(boot);
+
+        let mut idle = Task::new(idle_loop, 0)?;
+        idle.set_cpu(core_id());
+        sched.rq[core_id()].idle = Some(Box::into_raw(idle));
+
+        Ok(Self {
+            sched: Spinlock::new(sched),
+        })
     }
 
+    // called by a secondary core on its boot stack, which becomes the idle task of the core
+    pub fn init_secondary(&self) {
+        let mut idle = Box::new(Task::default());
+        idle.set_cpu(core_id());
+        let idle = Box::into_raw(idle);
+        self.with_sched(|s| {
+            let rq = &mut s.rq[core_id()];
+            rq.idle = Some(idle);
+            rq.replace_current(idle);
+        });
+    }
+
+    // the timer interrupt also takes the lock, so keep it masked while we hold it
+    fn with_sched<R>(&self, f: impl FnOnce(&mut UnSafeScheduler) -> R) -> R {
+        let daif = exception::local_irq_mask_save();
+        let r = f(&mut self.sched.lock());
+        exception::local_irq_restore(daif);
+        r
+    }
+
+    // the task is freed when it exits
     pub fn add_task(&self, t: Box<Task>) {
-        self.sched.lock().add_task(t)
+        self.with_sched(|s| s.add_task(t))
     }
 
-    pub fn schedule(&self) -> Option<*mut Task> {
-        self.sched.lock().schedule()
+    // unlike add_task, a spawned task can be joined
+    pub fn spawn(&self, t: Box<Task>) -> TaskId {
+        let id = self.with_sched(|s| s.spawn(t));
+        // kick the idle cores
+        aarch64_cpu::asm::sev();
+        id
     }
 
-    pub fn init_task(&self) -> ! {
-        let mut t = Box::new(Task::default());
-        let stack = MMU.get().unwrap().allocate_stack(1).unwrap();
-        t.set_sp(stack.va.start().value());
-        t.set_lr(sched_test as usize);
-        self.sched.lock().replace_current(Box::into_raw(t));
-
-        SPSR_EL1.write(
-            SPSR_EL1::M::EL0t
-                + SPSR_EL1::D::Unmasked
-                + SPSR_EL1::A::Unmasked
-                + SPSR_EL1::I::Unmasked
-                + SPSR_EL1::F::Unmasked,
-        );
-        SP_EL0.set(stack.va.start().value() as u64);
-        ELR_EL1.set(sched_test as u64);
-
-        barrier::isb(barrier::SY);
-        unsafe {
-            asm!("eret");
+    pub fn spawn_on(&self, core: usize, t: Box<Task>) -> TaskId {
+        let id = self.with_sched(|s| s.spawn_on(core, t));
+        aarch64_cpu::asm::sev();
+        id
+    }
+
+    pub fn current_id(&self) -> TaskId {
+        self.with_sched(|s| unsafe { (*s.current()).id() })
+    }
+    // for fault reporting, the faulting code may hold the lock
+    pub fn try_current_id(&self) -> Option<TaskId> {
+        let s = self.sched.try_lock()?;
+        Some(unsafe { (*s.current()).id() })
+    }
+
+    pub fn current_address_space(&self) -> Option<Arc<address_space::AddressSpace>> {
+        self.with_sched(|s| unsafe { (*s.current()).address_space_arc() })
+    }
+
+    // mark the current task blocked, it stays current until the next schedule()
+    pub fn block_current(&self) -> *mut Task {
+        self.with_sched(|s| {
+            s.block_current();
+            s.current()
+        })
+    }
+
+    // put a blocked task back on the run queue of its core
+    pub fn wake(&self, t: *mut Task) {
+        self.with_sched(|s| s.wake(t));
+        aarch64_cpu::asm::sev();
+    }
+
+    // in timer ticks
+    pub fn set_quantum(&self, quantum: usize) {
+        self.with_sched(|s| s.quantum = quantum.max(1))
+    }
+
+    // called from the timer interrupt
+    pub fn tick(&self) {
+        self.with_sched(|s| s.tick())
+    }
+
+    // called on the way out of an IRQ, after the interrupt has been acknowledged
+    pub fn preempt(&self) {
+        if self.with_sched(|s| s.take_need_resched()) {
+            self.schedule();
+        }
+    }
+
+    // give up the cpu to the next ready task, if any
+    pub fn schedule(&self) {
+        let daif = exception::local_irq_mask_save();
+        let switch = self.sched.lock().pick_next();
+        if let Some((prev, next)) = switch {
+            unsafe {
+                switch_address_space(&*prev, &*next);
+                __cpu_switch_to(prev, next);
+            }
+            self.finish_switch();
+        }
+        exception::local_irq_restore(daif);
+    }
+
+    // runs on the next task right after a switch, when the previous stack is no longer in use
+    pub(crate) fn finish_switch(&self) {
+        // keep the lock so a join on another core can't free the task under us
+        let mut s = self.sched.lock();
+        if let Some(dead) = s.take_dead() {
+            unsafe {
+                (*dead).release_stack().unwrap();
+                (*dead).release_address_space();
+            }
+            // a task from add_task can't be joined, so nothing else would free it
+            let id = unsafe { (*dead).id() };
+            if s.tasks.get(&id) != Some(&dead) {
+                drop(unsafe { Box::from_raw(dead) });
+            }
+        }
+    }
+
+    // run whatever becomes ready on this core, sleeping in between
+    pub fn idle(&self) -> ! {
+        exception::local_irq_unmask();
+        loop {
+            self.schedule();
+            aarch64_cpu::asm::wfe();
         }
+    }
 
-        loop {}
+    pub fn exit(&self, code: i32) -> ! {
+        exception::local_irq_mask();
+        self.sched.lock().exit_current(code);
+        self.schedule();
+        unreachable!("a zombie task was scheduled")
     }
+
+    // block until the task exits, then reap it and return its exit code
+    pub fn join(&self, id: TaskId) -> Result<i32, ErrorCode> {
+        let daif = exception::local_irq_mask_save();
+        let result = loop {
+            let mut s = self.sched.lock();
+            let Some(&t) = s.tasks.get(&id) else {
+                break Err(ESCHED);
+            };
+            let current = s.current();
+            if t == current {
+                break Err(EPARAM);
+            }
+
+            if unsafe { (*t).state() } == TaskState::Zombie {
+                // the exiting core may still be switching away from it
+                if s.rq.iter().any(|rq| rq.dead == Some(t)) {
+                    drop(s);
+                    core::hint::spin_loop();
+                    continue;
+                }
+                s.tasks.remove(&id);
+                let t = unsafe { Box::from_raw(t) };
+                break Ok(t.exit_code());
+            }
+
+            if let Err(e) = unsafe { (*t).set_joiner(current) } {
+                break Err(e);
+            }
+            s.block_current();
+            drop(s);
+            self.schedule();
+        };
+        exception::local_irq_restore(daif);
+        result
+    }
+
+    // the boot task continues as the first user task, with its stack in an address space of its
+    // own
+    pub fn init_task(&self) -> ! {
+        let mm = Arc::new(address_space::AddressSpace::new().unwrap());
+        mm.activate();
+        let stack = VaRange::new(INIT_STACK_TOP - config::PAGE_SIZE, INIT_STACK_TOP);
+        mm.map_zeroed(stack, RWNORMAL).unwrap();
+        self.with_sched(|s| unsafe { (*s.current()).set_address_space(mm) });
+        exception::enter_el0(sched_test as usize, INIT_STACK_TOP)
+    }
+}
+
+// kernel tasks run on the boot table
+fn switch_address_space(prev: &Task, next: &Task) {
+    match (prev.address_space(), next.address_space()) {
+        (Some(p), Some(n)) if core::ptr::eq(p, n) => {}
+        (_, Some(n)) => n.activate(),
+        (Some(_), None) => address_space::activate_kernel(),
+        (None, None) => {}
+    }
+}
+
+fn idle_loop(_: usize) -> i32 {
+    SCHEDULER.get().unwrap().idle()
 }
 
 pub fn sched_test() -> ! {
-    println!("Hello Scheduler");
-    let a = 5u32;
-    let b = 6u32;
-    let mut c = 0u32;
+    // a copy on the user stack, the kernel's rodata is not user memory
+    let msg = core::hint::black_box(*b"Hello Scheduler\n");
+    syscall::user::write(1, &msg);
     loop {
-        unsafe {
-            core::ptr::write_volatile(&mut c as *mut u32, a + b);
-        }
+        syscall::user::sleep(1000);
     }
 }
 
 pub fn init() -> Result<(), ErrorCode> {
-    SCHEDULER.call_once(|| Scheduler::new());
+    SCHEDULER.try_call_once(Scheduler::new)?;
 
     Ok(())
 }
 
 pub static SCHEDULER: Once<Scheduler> = Once::new();
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::cpu::timer::TIMER;
+    use test_macros::kernel_test;
+
+    static COUNTERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+    static STOP: AtomicUsize = AtomicUsize::new(0);
+
+    fn busy(i: usize) -> i32 {
+        while STOP.load(Ordering::Relaxed) == 0 {
+            COUNTERS[i].fetch_add(1, Ordering::Relaxed);
+        }
+        0
+    }
+
+    #[kernel_test]
+    fn test_round_robin() {
+        let sched = SCHEDULER.get().unwrap();
+        sched.set_quantum(1);
+        // both on this core, so they can only interleave with us through preemption
+        let a = sched.spawn_on(core_id(), Task::new(busy, 0).unwrap());
+        let b = sched.spawn_on(core_id(), Task::new(busy, 1).unwrap());
+
+        exception::local_irq_unmask();
+        TIMER.get().unwrap().enable();
+
+        // neither task yields, so both counters only keep moving if the tick preempts them
+        for _ in 0..4 {
+            let before = [
+                COUNTERS[0].load(Ordering::Relaxed),
+                COUNTERS[1].load(Ordering::Relaxed),
+            ];
+            while COUNTERS[0].load(Ordering::Relaxed) == before[0]
+                || COUNTERS[1].load(Ordering::Relaxed) == before[1]
+            {
+                core::hint::spin_loop();
+            }
+        }
+
+        STOP.store(1, Ordering::Relaxed);
+        sched.join(a).unwrap();
+        sched.join(b).unwrap();
+
+        TIMER.get().unwrap().disable();
+        sched.set_quantum(DEFAULT_QUANTUM);
+    }
+
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    fn run_once(_: usize) -> i32 {
+        RAN.fetch_add(1, Ordering::Relaxed);
+        0
+    }
+
+    // the leak check catches a task that is not freed on exit
+    #[kernel_test]
+    fn test_add_task_reaped() {
+        let sched = SCHEDULER.get().unwrap();
+        sched.add_task(Task::new(run_once, 0).unwrap());
+        // it runs to its exit once picked, and is reaped when we are switched back in
+        while RAN.load(Ordering::Relaxed) == 0 {
+            sched.schedule();
+        }
+    }
+
+    fn on_own_address_space(_: usize) -> i32 {
+        let sched = SCHEDULER.get().unwrap();
+        sched.with_sched(|s| unsafe { (*s.current()).address_space().unwrap().is_active() }) as i32
+    }
+
+    #[kernel_test]
+    fn test_address_space_switch() {
+        let sched = SCHEDULER.get().unwrap();
+        let mut t = Task::new(on_own_address_space, 0).unwrap();
+        t.set_address_space(Arc::new(address_space::AddressSpace::new().unwrap()));
+        let id = sched.spawn_on(core_id(), t);
+        assert_eq!(sched.join(id).unwrap(), 1);
+    }
+}
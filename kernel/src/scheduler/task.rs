@@ -1,19 +1,90 @@
+extern crate alloc;
 use crate::{
+    errno::{ErrorCode, EAGAIN},
     generics::{DoublyLink, DoublyLinkable, DoublyLinkedList, Link},
     memory,
-    memory::{address::AddressRange, *},
-    scheduler::context_switch::Context,
+    memory::{
+        address::{AddressRange, VaRange},
+        address_space::AddressSpace,
+        *,
+    },
+    scheduler::context_switch::{Context, __ret_from_fork},
+};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use test_macros::doubly_linkable;
 
+const TASK_STACK_PAGES: usize = 4;
+
+// 0 is the boot task
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    fn allocate() -> Self {
+        Self(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed))
+    }
+    pub fn value(&self) -> usize {
+        self.0
+    }
+}
+
+// e.g. an id handed out to EL0
+impl From<usize> for TaskId {
+    fn from(id: usize) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Default, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TaskState {
+    Running,
+    #[default]
+    Ready,
+    Blocked,
+    Zombie,
+}
+
+// owns its stack and a reference to its address space, so it is never copied
 #[doubly_linkable]
-#[derive(Default, Copy, Clone)]
+#[derive(Default)]
 #[repr(C)]
 pub struct Task {
-    ctx: Context,
+    ctx: Context, // must be the first field, see __cpu_switch_to
+    id: TaskId,
+    state: TaskState,
+    cpu: usize,
+    exit_code: i32,
+    stack: Option<VaRange>,
+    joiner: Option<*mut Task>,
+    mm: Option<Arc<AddressSpace>>, // None for kernel tasks
 }
 
 impl Task {
+    // A kernel task that starts running `entry(arg)` at EL1 the first time it is switched in
+    pub fn new(entry: fn(usize) -> i32, arg: usize) -> Result<Box<Self>, ErrorCode> {
+        let stack = MMU.get().unwrap().allocate_stack(TASK_STACK_PAGES)?;
+        let mut t = Box::new(Task::default());
+        t.id = TaskId::allocate();
+        t.stack = Some(stack);
+        t.set_sp(stack.end().value());
+        t.set_lr(__ret_from_fork as usize);
+        t.ctx.gpr[0] = entry as u64; // x19
+        t.ctx.gpr[1] = arg as u64; // x20
+        Ok(t)
+    }
+
     pub fn set_sp(&mut self, sp: usize) {
         self.ctx.sp = sp as u64;
     }
@@ -27,4 +98,68 @@ impl Task {
     pub fn get_lr(&self) -> usize {
         self.ctx.lr as usize
     }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+    pub fn set_state(&mut self, state: TaskState) {
+        self.state = state;
+    }
+
+    // the core whose run queue the task lives on
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+    pub(super) fn set_cpu(&mut self, cpu: usize) {
+        self.cpu = cpu;
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    pub(super) fn set_exit_code(&mut self, code: i32) {
+        self.exit_code = code;
+    }
+
+    pub(super) fn take_joiner(&mut self) -> Option<*mut Task> {
+        self.joiner.take()
+    }
+    pub(super) fn set_joiner(&mut self, t: *mut Task) -> Result<(), ErrorCode> {
+        if self.joiner.is_some() {
+            Err(EAGAIN)
+        } else {
+            self.joiner = Some(t);
+            Ok(())
+        }
+    }
+
+    // every task sharing an address space holds a reference to it
+    pub fn set_address_space(&mut self, mm: Arc<AddressSpace>) {
+        self.mm = Some(mm);
+    }
+    pub fn address_space(&self) -> Option<&AddressSpace> {
+        self.mm.as_deref()
+    }
+    // a reference that outlives the scheduler lock
+    pub fn address_space_arc(&self) -> Option<Arc<AddressSpace>> {
+        self.mm.clone()
+    }
+
+    // the last reference tears the lower half down, so the task must not be running on it
+    pub(super) fn release_address_space(&mut self) {
+        self.mm = None;
+    }
+
+    // the caller must not be running on this stack
+    pub(super) fn release_stack(&mut self) -> Result<(), ErrorCode> {
+        let Some(stack) = self.stack.take() else {
+            return Ok(());
+        };
+        MMU.get().unwrap().free_stack(stack)
+    }
 }
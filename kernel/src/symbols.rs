@@ -0,0 +1,188 @@
+//! Kernel symbol table
+//!
+//! `utils/kernel_symbols.rb` fills the `.kernel_symbols` section of the linked kernel ELF with the
+//! function symbols of that same ELF, sorted by address. All fields are little endian:
+//!
+//! ```text
+//! "KSYM" | count: u32 | count * (address: u64, size: u64, name: u32, len: u32) | names
+//! ```
+//!
+//! `name` is the offset of the demangled name from the start of the table. The section of a kernel
+//! that was never patched stays zeroed, lookups then find nothing.
+use core::fmt;
+use nom::{
+    bytes::complete::tag,
+    error::Error,
+    number::complete::{le_u32, le_u64},
+    sequence::tuple,
+    Finish, IResult,
+};
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 24;
+
+// the tool refuses to patch a kernel whose symbols do not fit
+const TABLE_SIZE: usize = 256 * 1024;
+
+type ParserResult<'a, T> = IResult<&'a [u8], T, Error<&'a [u8]>>;
+
+#[used]
+#[link_section = ".kernel_symbols"]
+static KERNEL_SYMBOLS: [u8; TABLE_SIZE] = [0; TABLE_SIZE];
+
+// read through the linker symbols, the compiler would fold reads of the zeroed static
+extern "C" {
+    static __kernel_symbols_start: u8;
+    static __kernel_symbols_end: u8;
+}
+
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub offset: usize,
+}
+
+impl fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+struct Entry {
+    address: usize,
+    size: usize,
+    name: usize,
+    len: usize,
+}
+
+fn parse_header(input: &[u8]) -> ParserResult<usize> {
+    let (rest, (_, count)) = tuple((tag(MAGIC), le_u32))(input)?;
+    Ok((rest, count as usize))
+}
+
+fn parse_entry(input: &[u8]) -> ParserResult<Entry> {
+    let (rest, (address, size, name, len)) = tuple((le_u64, le_u64, le_u32, le_u32))(input)?;
+    Ok((
+        rest,
+        Entry {
+            address: address as usize,
+            size: size as usize,
+            name: name as usize,
+            len: len as usize,
+        },
+    ))
+}
+
+pub struct SymbolTable<'a> {
+    table: &'a [u8],
+    count: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    // a table without the magic or with more entries than fit is taken as empty
+    pub fn new(table: &'a [u8]) -> Self {
+        let count = parse_header(table).finish().map_or(0, |(_, count)| count);
+        let count = if HEADER_SIZE + count * ENTRY_SIZE <= table.len() {
+            count
+        } else {
+            0
+        };
+        Self { table, count }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn entry(&self, i: usize) -> Entry {
+        let start = HEADER_SIZE + i * ENTRY_SIZE;
+        // in bounds, checked against count in new
+        let (_, entry) = parse_entry(&self.table[start..start + ENTRY_SIZE])
+            .finish()
+            .unwrap();
+        entry
+    }
+
+    // the function containing `address`, a symbol without a size extends to the next one
+    pub fn lookup(&self, address: usize) -> Option<Symbol<'a>> {
+        // the first entry above `address`
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid).address <= address {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let entry = self.entry(lo.checked_sub(1)?);
+        let offset = address - entry.address;
+        if entry.size != 0 && offset >= entry.size {
+            return None;
+        }
+        let name = self.table.get(entry.name..entry.name + entry.len)?;
+        Some(Symbol {
+            name: core::str::from_utf8(name).ok()?,
+            offset,
+        })
+    }
+}
+
+pub fn kernel() -> SymbolTable<'static> {
+    let table = unsafe {
+        let start = &__kernel_symbols_start as *const u8;
+        let end = &__kernel_symbols_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    SymbolTable::new(table)
+}
+
+pub fn lookup(address: usize) -> Option<Symbol<'static>> {
+    kernel().lookup(address)
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use test_macros::kernel_test;
+
+    #[kernel_test]
+    fn test_symbol_lookup() {
+        // two sized functions with a gap, then a label without a size
+        let symbols: [(u64, u64, &str); 3] = [
+            (0x1000, 0x20, "first"),
+            (0x1040, 0x10, "second"),
+            (0x2000, 0, "label"),
+        ];
+        let mut table = [0u8; HEADER_SIZE + 3 * ENTRY_SIZE + 32];
+        table[..4].copy_from_slice(&MAGIC);
+        table[4..8].copy_from_slice(&3u32.to_le_bytes());
+        let mut name = HEADER_SIZE + 3 * ENTRY_SIZE;
+        for (i, (address, size, s)) in symbols.iter().enumerate() {
+            let entry = &mut table[HEADER_SIZE + i * ENTRY_SIZE..];
+            entry[..8].copy_from_slice(&address.to_le_bytes());
+            entry[8..16].copy_from_slice(&size.to_le_bytes());
+            entry[16..20].copy_from_slice(&(name as u32).to_le_bytes());
+            entry[20..24].copy_from_slice(&(s.len() as u32).to_le_bytes());
+            table[name..name + s.len()].copy_from_slice(s.as_bytes());
+            name += s.len();
+        }
+
+        let table = SymbolTable::new(&table);
+        assert_eq!(table.len(), 3);
+        assert!(table.lookup(0xfff).is_none());
+        let symbol = table.lookup(0x1004).unwrap();
+        assert_eq!((symbol.name, symbol.offset), ("first", 4));
+        assert!(table.lookup(0x1020).is_none());
+        assert_eq!(table.lookup(0x104f).unwrap().name, "second");
+        assert_eq!(table.lookup(0x3000).unwrap().offset, 0x1000);
+
+        // an unpatched section
+        assert!(SymbolTable::new(&[0; 64]).is_empty());
+    }
+}
@@ -1,4 +1,11 @@
+mod condvar;
 pub mod primitive;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
 
 pub type Spinlock<T> = lock_api::Mutex<primitive::RawSpinlock, T>;
 pub type SpinlockGuard<'a, T> = lock_api::MutexGuard<'a, primitive::RawSpinlock, T>;
@@ -7,6 +14,10 @@ pub type MappedSpinlockGuard<'a, T> = lock_api::MappedMutexGuard<'a, primitive::
 pub type IRQSafeSpinlock<T> = lock_api::Mutex<primitive::IRQSafeSpinlock, T>;
 pub type IRQSafeSpinlockGuard<'a, T> = lock_api::MutexGuard<'a, primitive::IRQSafeSpinlock, T>;
 
+pub type Mutex<T> = lock_api::Mutex<primitive::RawSleepingMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, primitive::RawSleepingMutex, T>;
+pub type MappedMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, primitive::RawSleepingMutex, T>;
+
 pub type SpinRwLock<T> = lock_api::RwLock<primitive::RawRwSpinlock, T>;
 pub type SpinRwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, primitive::RawRwSpinlock, T>;
 pub type SpinRwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, primitive::RawRwSpinlock, T>;
@@ -0,0 +1,76 @@
+//! Condition variable for the sleeping `Mutex`
+
+use super::{MutexGuard, WaitQueue};
+use crate::{exception, scheduler::SCHEDULER};
+
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // release the mutex and block until notified, the mutex is held again on return.
+    // wakeups can be spurious, use wait_while to wait for a condition
+    pub fn wait<T: ?Sized>(&self, guard: &mut MutexGuard<'_, T>) {
+        let daif = exception::local_irq_mask_save();
+        // queue up before unlocking so a notify right after the unlock is not lost
+        self.waiters.enqueue_current();
+        MutexGuard::unlocked(guard, || SCHEDULER.get().unwrap().schedule());
+        exception::local_irq_restore(daif);
+    }
+
+    pub fn wait_while<T: ?Sized>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        mut cond: impl FnMut(&mut T) -> bool,
+    ) {
+        while cond(&mut *guard) {
+            self.wait(guard);
+        }
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::{kthread, synchronization::Mutex};
+    use test_macros::kernel_test;
+
+    static QUEUE: Mutex<usize> = Mutex::new(0);
+    static NOT_EMPTY: Condvar = Condvar::new();
+
+    fn consumer(n: usize) -> i32 {
+        for _ in 0..n {
+            let mut q = QUEUE.lock();
+            NOT_EMPTY.wait_while(&mut q, |q| *q == 0);
+            *q -= 1;
+        }
+        0
+    }
+
+    #[kernel_test]
+    fn test_mutex_condvar() {
+        let c = kthread::spawn(consumer, 3).unwrap();
+        for _ in 0..3 {
+            *QUEUE.lock() += 1;
+            NOT_EMPTY.notify_one();
+            SCHEDULER.get().unwrap().schedule();
+        }
+        kthread::join(c).unwrap();
+        assert_eq!(*QUEUE.lock(), 0);
+    }
+}
@@ -9,6 +9,7 @@
 //!
 //! The means at the very beginning of kernel boot, we CANNOT use locks relying on these atomics
 
+use super::WaitQueue;
 use crate::{exception, println};
 use core::{
     cell::UnsafeCell,
@@ -147,6 +148,40 @@ unsafe impl RawMutex for IRQSafeSpinlock {
     }
 }
 
+// parks the calling task instead of spinning, so it must not be taken in interrupt context
+pub struct RawSleepingMutex {
+    locked: RawSpinlock,
+    waiters: WaitQueue,
+}
+
+impl RawSleepingMutex {
+    const fn new() -> Self {
+        Self {
+            locked: RawSpinlock::new(),
+            waiters: WaitQueue::new(),
+        }
+    }
+}
+
+unsafe impl RawMutex for RawSleepingMutex {
+    const INIT: Self = RawSleepingMutex::new();
+
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        self.waiters.wait_until(|| self.locked.try_lock());
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.locked.unlock();
+        self.waiters.wake_one();
+    }
+}
+
 #[cfg(test)]
 #[allow(unused_imports, unused_variables, dead_code)]
 mod tests {
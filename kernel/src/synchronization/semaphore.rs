@@ -0,0 +1,65 @@
+//! Counting semaphore that parks the calling task while the count is zero
+
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |c| c.checked_sub(1))
+            .is_ok()
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::kthread;
+    use test_macros::kernel_test;
+
+    static SEM: Semaphore = Semaphore::new(0);
+
+    fn consumer(_: usize) -> i32 {
+        SEM.acquire();
+        SEM.acquire();
+        0
+    }
+
+    #[kernel_test]
+    fn test_semaphore() {
+        assert!(!SEM.try_acquire());
+
+        let c = kthread::spawn(consumer, 0).unwrap();
+        SEM.release();
+        SEM.release();
+        kthread::join(c).unwrap();
+
+        assert_eq!(SEM.count(), 0);
+    }
+}
@@ -0,0 +1,123 @@
+//! Tasks blocked on an event
+//!
+//! A sleeping task is taken off the run queue and linked into the wait queue through the same
+//! `doubly_link` it uses on the run queue, so it is on at most one of them at a time.
+
+use super::Spinlock;
+use crate::{
+    exception,
+    generics::{DoublyLinkedList, Link},
+    scheduler::{Task, SCHEDULER},
+};
+
+pub struct WaitQueue {
+    waiters: Spinlock<DoublyLinkedList<Task>>,
+}
+
+unsafe impl Send for WaitQueue {}
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Spinlock::new(DoublyLinkedList::new()),
+        }
+    }
+
+    // the caller must have IRQs masked until it has been switched out
+    pub(super) fn enqueue_current(&self) {
+        self.enqueue_current_locked(&mut self.waiters.lock());
+    }
+
+    fn enqueue_current_locked(&self, waiters: &mut DoublyLinkedList<Task>) {
+        let t = SCHEDULER.get().unwrap().block_current();
+        waiters.push_back(Link::some(t as usize));
+    }
+
+    // block until woken up
+    pub fn sleep_on(&self) {
+        let daif = exception::local_irq_mask_save();
+        self.enqueue_current();
+        SCHEDULER.get().unwrap().schedule();
+        exception::local_irq_restore(daif);
+    }
+
+    // block until `cond` holds, it is re-checked every time we are woken up.
+    // the waker must make `cond` true before calling wake_*
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        let daif = exception::local_irq_mask_save();
+        loop {
+            // checked under the queue lock so a wake_* in between can't be missed
+            let mut waiters = self.waiters.lock();
+            if cond() {
+                break;
+            }
+            self.enqueue_current_locked(&mut waiters);
+            drop(waiters);
+            SCHEDULER.get().unwrap().schedule();
+        }
+        exception::local_irq_restore(daif);
+    }
+
+    // returns true if a task was woken up
+    pub fn wake_one(&self) -> bool {
+        let daif = exception::local_irq_mask_save();
+        let t = self.waiters.lock().pop_front();
+        if let Some(t) = t {
+            SCHEDULER.get().unwrap().wake(t.resolve_mut() as *mut Task);
+        }
+        exception::local_irq_restore(daif);
+        t.is_some()
+    }
+
+    // returns the number of tasks woken up
+    pub fn wake_all(&self) -> usize {
+        let daif = exception::local_irq_mask_save();
+        let mut woken = 0;
+        while let Some(t) = self.waiters.lock().pop_front() {
+            SCHEDULER.get().unwrap().wake(t.resolve_mut() as *mut Task);
+            woken += 1;
+        }
+        exception::local_irq_restore(daif);
+        woken
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().len() == 0
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::kthread;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use test_macros::kernel_test;
+
+    static WQ: WaitQueue = WaitQueue::new();
+    static EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn waiter(_: usize) -> i32 {
+        WQ.wait_until(|| EVENTS.load(Ordering::Relaxed) > 0);
+        0
+    }
+
+    #[kernel_test]
+    fn test_wait_queue() {
+        let a = kthread::spawn(waiter, 0).unwrap();
+        let b = kthread::spawn(waiter, 0).unwrap();
+
+        // let both waiters run and block
+        while WQ.waiters.lock().len() != 2 {
+            SCHEDULER.get().unwrap().schedule();
+        }
+
+        EVENTS.store(1, Ordering::Relaxed);
+        assert_eq!(WQ.wake_all(), 2);
+        assert!(WQ.is_empty());
+
+        kthread::join(a).unwrap();
+        kthread::join(b).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! System calls from EL0
+//!
+//! The number is passed in x8 and up to six arguments in x0 - x5. The result is returned in x0,
+//! negative values are `-errno`.
+
+#[cfg(target_arch = "aarch64")]
+#[path = "_arch/aarch64/syscall.rs"]
+mod arch_syscall;
+
+extern crate alloc;
+use crate::{
+    cpu::timer::TIMER,
+    errno::*,
+    memory::{
+        address::{AddressRange, VaRange, VirtualAddress},
+        address_space::AddressSpace,
+        config, copy_from_user, MemoryType, RONORMAL, RWNORMAL, RWXNORMAL, XNORMAL,
+    },
+    print,
+    scheduler::{Task, TaskId, SCHEDULER},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::time::Duration;
+
+pub const SYS_WRITE: usize = 0;
+pub const SYS_YIELD: usize = 1;
+pub const SYS_EXIT: usize = 2;
+pub const SYS_GETPID: usize = 3;
+pub const SYS_SLEEP: usize = 4;
+// needs the caller's registers, the exception code handles it before `dispatch`
+pub const SYS_FORK: usize = 5;
+pub const SYS_MMAP: usize = 6;
+pub const SYS_MUNMAP: usize = 7;
+pub const SYS_MPROTECT: usize = 8;
+
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+const STDOUT: usize = 1;
+
+type Syscall = fn(&[usize; 6]) -> Result<usize, ErrorCode>;
+
+static SYSCALL_TABLE: [Syscall; 9] = [
+    sys_write,
+    sys_yield,
+    sys_exit,
+    sys_getpid,
+    sys_sleep,
+    sys_fork,
+    sys_mmap,
+    sys_munmap,
+    sys_mprotect,
+];
+
+pub fn dispatch(nr: usize, args: &[usize; 6]) -> isize {
+    let Some(handler) = SYSCALL_TABLE.get(nr) else {
+        return -(ESUPPORTED.errno() as isize);
+    };
+    match handler(args) {
+        Ok(v) => v as isize,
+        Err(e) => -(e.errno() as isize),
+    }
+}
+
+// a copy of the caller's buffer. pages of its VMAs that were not touched yet are faulted in,
+// anything EL0 could not read is EFAULT
+fn copy_user_buffer(ptr: usize, len: usize) -> Result<Vec<u8>, ErrorCode> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| EPAGE)?;
+    buf.resize(len, 0);
+    copy_from_user(&mut buf, VirtualAddress::from(ptr))?;
+    Ok(buf)
+}
+
+// write(fd, buf, len)
+fn sys_write(args: &[usize; 6]) -> Result<usize, ErrorCode> {
+    if args[0] != STDOUT {
+        return Err(ESUPPORTED);
+    }
+    let buf = copy_user_buffer(args[1], args[2])?;
+    let s = core::str::from_utf8(&buf).map_err(|_| EPARAM)?;
+    print!("{}", s);
+    Ok(buf.len())
+}
+
+fn sys_yield(_: &[usize; 6]) -> Result<usize, ErrorCode> {
+    SCHEDULER.get().unwrap().schedule();
+    Ok(0)
+}
+
+// exit(code)
+fn sys_exit(args: &[usize; 6]) -> Result<usize, ErrorCode> {
+    SCHEDULER.get().unwrap().exit(args[0] as i32)
+}
+
+fn sys_getpid(_: &[usize; 6]) -> Result<usize, ErrorCode> {
+    Ok(SCHEDULER.get().unwrap().current_id().value())
+}
+
+// sleep(milliseconds)
+fn sys_sleep(args: &[usize; 6]) -> Result<usize, ErrorCode> {
+    TIMER
+        .get()
+        .unwrap()
+        .sleep(Duration::from_millis(args[0] as u64));
+    Ok(0)
+}
+
+// only reached without the caller's registers, see SYS_FORK
+fn sys_fork(_: &[usize; 6]) -> Result<usize, ErrorCode> {
+    Err(ESUPPORTED)
+}
+
+fn current_address_space() -> Result<Arc<AddressSpace>, ErrorCode> {
+    SCHEDULER
+        .get()
+        .unwrap()
+        .current_address_space()
+        .ok_or(ESUPPORTED)
+}
+
+// there is no type for PROT_NONE, and write access implies read access
+fn prot_to_type(prot: usize) -> Result<&'static MemoryType, ErrorCode> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EPARAM);
+    }
+    match prot {
+        0 => Err(ESUPPORTED),
+        PROT_READ => Ok(RONORMAL),
+        _ if prot & PROT_WRITE == 0 => Ok(XNORMAL),
+        _ if prot & PROT_EXEC == 0 => Ok(RWNORMAL),
+        _ => Ok(RWXNORMAL),
+    }
+}
+
+// `len` rounded up to pages
+fn user_range(addr: usize, len: usize) -> Result<VaRange, ErrorCode> {
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_add(config::PAGE_SIZE - 1))
+        .ok_or(EOVERFLOW)?;
+    Ok(VaRange::new(addr, end & !(config::PAGE_SIZE - 1)))
+}
+
+// mmap(addr, len, prot, flags, fd, offset), private anonymous memory only
+fn sys_mmap(args: &[usize; 6]) -> Result<usize, ErrorCode> {
+    let (addr, len, prot, flags) = (args[0], args[1], args[2], args[3]);
+    if flags & MAP_ANONYMOUS == 0 || flags & MAP_SHARED != 0 {
+        return Err(ESUPPORTED);
+    }
+    let mt = prot_to_type(prot)?;
+    let hint = (addr != 0).then_some(VirtualAddress::from(addr));
+    let va = current_address_space()?.mmap(hint, len, mt, flags & MAP_FIXED != 0)?;
+    Ok(va.start().value())
+}
+
+// munmap(addr, len)
+fn sys_munmap(args: &[usize; 6]) -> Result<usize, ErrorCode> {
+    current_address_space()?.munmap(user_range(args[0], args[1])?)?;
+    Ok(0)
+}
+
+// mprotect(addr, len, prot)
+fn sys_mprotect(args: &[usize; 6]) -> Result<usize, ErrorCode> {
+    let mt = prot_to_type(args[2])?;
+    current_address_space()?.mprotect(user_range(args[0], args[1])?, mt)?;
+    Ok(0)
+}
+
+// the kernel half of fork(): a new task on a copy-on-write copy of the caller's address space,
+// started at `entry(arg)` which is expected to resume the copied user context
+pub fn fork(entry: fn(usize) -> i32, arg: usize) -> Result<TaskId, ErrorCode> {
+    let mm = current_address_space()?;
+    let mut t = Task::new(entry, arg)?;
+    t.set_address_space(Arc::new(mm.fork()?));
+    Ok(SCHEDULER.get().unwrap().spawn(t))
+}
+
+// wrappers for code running at EL0
+pub mod user {
+    use super::{arch_syscall::syscall, *};
+
+    pub fn write(fd: usize, buf: &[u8]) -> isize {
+        syscall(SYS_WRITE, [fd, buf.as_ptr() as usize, buf.len(), 0, 0, 0])
+    }
+
+    pub fn yield_now() {
+        syscall(SYS_YIELD, [0; 6]);
+    }
+
+    pub fn exit(code: i32) -> ! {
+        syscall(SYS_EXIT, [code as usize, 0, 0, 0, 0, 0]);
+        unreachable!()
+    }
+
+    pub fn getpid() -> usize {
+        syscall(SYS_GETPID, [0; 6]) as usize
+    }
+
+    pub fn sleep(ms: usize) {
+        syscall(SYS_SLEEP, [ms, 0, 0, 0, 0, 0]);
+    }
+
+    // the id of the child in the parent, 0 in the child
+    pub fn fork() -> isize {
+        syscall(SYS_FORK, [0; 6])
+    }
+
+    pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize) -> isize {
+        syscall(SYS_MMAP, [addr, len, prot, flags, usize::MAX, 0])
+    }
+
+    pub fn munmap(addr: usize, len: usize) -> isize {
+        syscall(SYS_MUNMAP, [addr, len, 0, 0, 0, 0])
+    }
+
+    pub fn mprotect(addr: usize, len: usize, prot: usize) -> isize {
+        syscall(SYS_MPROTECT, [addr, len, prot, 0, 0, 0])
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports, unused_variables, dead_code)]
+mod tests {
+    use super::*;
+    use crate::{exception, kthread, memory::MMU};
+    use test_macros::kernel_test;
+
+    const USER_STACK_TOP: usize = 0x40_0001_0000;
+
+    fn user_main() -> ! {
+        let pid = user::getpid();
+        // a copy on the user stack, the kernel's rodata is not user memory
+        let msg = core::hint::black_box(*b"hello from EL0\n");
+        assert_eq!(user::write(STDOUT, &msg), 15);
+        assert!(user::write(0, &msg) < 0);
+        let efault = -(EFAULT.errno() as isize);
+        assert_eq!(user::write(STDOUT, b"kernel rodata\n"), efault);
+        // right below the stack, nothing is mapped there
+        let unmapped = USER_STACK_TOP - 2 * config::PAGE_SIZE;
+        assert_eq!(
+            arch_syscall::syscall(SYS_WRITE, [STDOUT, unmapped, 1, 0, 0, 0]),
+            efault
+        );
+        // runs off the top of the stack halfway through the copy
+        assert_eq!(
+            arch_syscall::syscall(SYS_WRITE, [STDOUT, USER_STACK_TOP - 8, 16, 0, 0, 0]),
+            efault
+        );
+        user::yield_now();
+        user::exit(pid as i32 + 100)
+    }
+
+    #[kernel_test]
+    fn test_syscalls() {
+        let id = spawn_user_mm(user_main);
+        assert_eq!(kthread::join(id).unwrap(), id.value() as i32 + 100);
+    }
+
+    // the parent exits with the id of the child
+    fn user_fork() -> ! {
+        let mut counter = 1;
+        let pid = user::fork();
+        counter += 1;
+        match pid {
+            0 => user::exit(counter + 40),
+            _ => user::exit(pid as i32),
+        }
+    }
+
+    // the stack has to live in the address space, e.g. for fork to copy it
+    fn enter_user_mm(entry: usize) -> i32 {
+        let mm = SCHEDULER.get().unwrap().current_address_space().unwrap();
+        let stack = VaRange::new(USER_STACK_TOP - config::PAGE_SIZE, USER_STACK_TOP);
+        mm.map_zeroed(stack, RWNORMAL).unwrap();
+        exception::enter_el0(entry, USER_STACK_TOP)
+    }
+
+    fn spawn_user_mm(entry: fn() -> !) -> TaskId {
+        let mut t = Task::new(enter_user_mm, entry as usize).unwrap();
+        t.set_address_space(Arc::new(AddressSpace::new().unwrap()));
+        SCHEDULER.get().unwrap().spawn(t)
+    }
+
+    #[kernel_test]
+    fn test_fork() {
+        let parent = spawn_user_mm(user_fork);
+        let child = kthread::join(parent).unwrap();
+        assert_ne!(child, parent.value() as i32);
+        assert_eq!(kthread::join(TaskId::from(child as usize)).unwrap(), 42);
+    }
+
+    fn user_mmap() -> ! {
+        let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+        let p = user::mmap(0, 2 * config::PAGE_SIZE, PROT_READ | PROT_WRITE, flags);
+        if p < 0 {
+            user::exit(p as i32);
+        }
+        let p = p as usize;
+        unsafe { core::ptr::write_volatile(p as *mut u64, 40) };
+        if user::mprotect(p, config::PAGE_SIZE, PROT_READ) != 0 {
+            user::exit(-1);
+        }
+        let value = unsafe { core::ptr::read_volatile(p as *const u64) } as i32;
+        if user::munmap(p, 2 * config::PAGE_SIZE) != 0 {
+            user::exit(-2);
+        }
+        // PROT_NONE has no memory type
+        let none = user::mmap(0, config::PAGE_SIZE, 0, flags);
+        user::exit(value + (none == -(ESUPPORTED.errno() as isize)) as i32 * 2)
+    }
+
+    #[kernel_test]
+    fn test_mmap() {
+        let id = spawn_user_mm(user_mmap);
+        assert_eq!(kthread::join(id).unwrap(), 42);
+    }
+}
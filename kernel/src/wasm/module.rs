@@ -664,6 +664,12 @@ impl WasmManager {
     fn get_module(&self, idx: Idx) -> MappedSpinRwLockReadGuard<'_, WasmModule> {
         SpinRwLockReadGuard::map(self.store.read(), |s: &GlobalStore| s.get_module(idx))
     }
+
+    // drop every module and function parsed so far, their indices are no longer valid
+    #[cfg(test)]
+    fn clear(&self) {
+        *self.store.write() = GlobalStore::new();
+    }
 }
 
 pub static WASM_MANAGER: Once<WasmManager> = Once::new();
@@ -750,5 +756,7 @@ mod tests {
         let (_, module_idx) = WASM_MANAGER.get().unwrap().parse(WASM_MODULE).unwrap();
         let module = WASM_MANAGER.get().unwrap().get_module(module_idx);
         println!("{}", module);
+        drop(module);
+        WASM_MANAGER.get().unwrap().clear();
     }
 }
@@ -10,5 +10,6 @@
 	echo $TEST_BINARY
 	echo $TEST_ELF
 
+    docker run -t --rm -v /Users/lsw/Code/pi-OS:/work/tutorial -w /work/tutorial rustembedded/osdev-utils:2021.12 ruby utils/kernel_symbols.rb $TEST_ELF
     rust-objcopy --strip-all -O binary $TEST_ELF $TEST_BINARY
     docker run -t --rm -v /Users/lsw/Code/pi-OS:/work/tutorial -w /work/tutorial -v /Users/lsw/Code/pi-OS/common:/work/common rustembedded/osdev-utils:2021.12 ruby common/tests/dispatch.rb qemu-system-aarch64 -M raspi3b -serial stdio -display none  -machine raspi3b -semihosting  -kernel $TEST_BINARY
@@ -4,10 +4,18 @@ use proc_macro_error::{abort, abort_call_site, proc_macro_error};
 use quote::quote;
 use syn::{parse::Parser, parse_macro_input, Expr::Field, Ident, ItemFn, ItemStruct};
 
+#[proc_macro_error]
 #[proc_macro_attribute]
-pub fn kernel_test(_attr: TokenStream, input: TokenStream) -> TokenStream {
+pub fn kernel_test(attr: TokenStream, input: TokenStream) -> TokenStream {
     let f = parse_macro_input!(input as ItemFn);
 
+    // tests that keep heap memory alive on purpose opt out of the leak check
+    let leak_check = match attr.to_string().as_str() {
+        "" => true,
+        "no_leak_check" => false,
+        other => abort_call_site!("unknown kernel_test option {}", other),
+    };
+
     let test_name = &format!("{}", f.sig.ident);
     let test_ident = Ident::new(
         &format!("{}_TEST_CONTAINER", f.sig.ident.to_string().to_uppercase()),
@@ -20,6 +28,7 @@ pub fn kernel_test(_attr: TokenStream, input: TokenStream) -> TokenStream {
         const #test_ident: test_types::UnitTest = test_types::UnitTest {
             name: #test_name,
             test_func: || #test_code_block,
+            leak_check: #leak_check,
         };
     )
     .into()
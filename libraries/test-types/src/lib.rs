@@ -3,4 +3,5 @@
 pub struct UnitTest {
     pub name: &'static str,
     pub test_func: fn(),
+    pub leak_check: bool,
 }
@@ -0,0 +1,65 @@
+#!/usr/bin/env ruby
+# frozen_string_literal: true
+
+# SPDX-License-Identifier: MIT OR Apache-2.0
+
+# Writes the function symbols of a linked kernel ELF into its own .kernel_symbols section, in the
+# layout kernel/src/symbols.rs reads. Run it before the ELF is turned into an image.
+#
+#   ruby utils/kernel_symbols.rb <kernel elf>
+#
+# NM and READELF override the binutils, e.g. NM=rust-nm READELF=rust-readelf outside of docker.
+
+NM = ENV.fetch('NM', 'aarch64-none-elf-nm')
+READELF = ENV.fetch('READELF', 'aarch64-none-elf-readelf')
+
+MAGIC = 'KSYM'
+HEADER_SIZE = 8
+ENTRY_SIZE = 24
+
+def section(elf)
+    `#{READELF} --section-headers --wide #{elf}`.each_line do |line|
+        next unless (m = line.match(/\]\s+\.kernel_symbols\s+\S+\s+(\h+)\s+(\h+)\s+(\h+)/))
+
+        return { offset: m[2].hex, size: m[3].hex }
+    end
+    abort "#{elf}: no .kernel_symbols section"
+end
+
+# [address, size, name] of every function, sorted by address
+def symbols(elf)
+    `#{NM} --demangle --defined-only --print-size --numeric-sort #{elf}`.each_line.filter_map do |line|
+        # symbols without a size, e.g. assembly labels, have no second column
+        next unless (m = line.chomp.match(/^(\h{16})(?: (\h{16}))? ([tT]) (.+)$/))
+        # mapping symbols like $x mark code, not functions
+        next if m[4].start_with?('$')
+
+        # the hash rustc appends to every path
+        [m[1].hex, m[2].to_s.hex, m[4].sub(/::h\h{16}$/, '')]
+    end
+end
+
+def table(symbols)
+    names = +''
+    entries = symbols.map do |address, size, name|
+        offset = HEADER_SIZE + (symbols.length * ENTRY_SIZE) + names.bytesize
+        names << name
+        [address, size, offset, name.bytesize].pack('Q<Q<L<L<')
+    end
+    [MAGIC, symbols.length].pack('a4L<') + entries.join + names.b
+end
+
+elf = ARGV.fetch(0) { abort "usage: #{$PROGRAM_NAME} <kernel elf>" }
+section = section(elf)
+symbols = symbols(elf)
+table = table(symbols)
+if table.bytesize > section[:size]
+    abort "#{elf}: #{table.bytesize} bytes of symbols do not fit into #{section[:size]}, " \
+          'raise TABLE_SIZE in kernel/src/symbols.rs'
+end
+
+File.open(elf, 'r+b') do |f|
+    f.seek(section[:offset])
+    f.write(table.ljust(section[:size], "\0"))
+end
+puts "#{elf}: #{symbols.length} symbols, #{table.bytesize} of #{section[:size]} bytes"